@@ -1,11 +1,14 @@
 import json
 
-path = "printer/officejet_pro_6835/unpacker/" 
-with open("segment_table", "r") as s:
-    segments = json.load(s.read())
-    for segment in segments:     
-        with open(path + segment["name"], 'rb') as f:         
-            data = f.read()         
+path = "printer/officejet_pro_6835/unpacker/"
+with open(path + "manifest.json", "r") as s:
+    manifest = json.load(s)
+    # Memory regions never overlap each other, the unpacker reports any overlap with the firmware
+    for region in manifest["regions"]:
+        if region["origin"] not in ("memory", "firmware"):
+            continue
+        with open(path + region["file"], 'rb') as f:
+            data = f.read()[region["file_offset"]:region["file_offset"] + region["size"]]
             bv.parent_view.write(len(bv.parent_view), data)
-            bv.add_user_segment(segment["load_addr"], len(data), 
-                    len(bv.parent_view) - len(data), len(data), segment["perms"]) 
+            bv.add_user_segment(region["vaddr"], len(data),
+                    len(bv.parent_view) - len(data), len(data), region["perms"])
@@ -0,0 +1,605 @@
+use crate::{
+    bytes_to_int_be,
+    error::{Error, Result},
+    firmware::Firmware,
+    lzss::lzss_uncompress,
+};
+
+/// Size of a single dst, src/val, length tripple in the bootloader lists
+const TRIPPLE_SIZE: usize = 12;
+
+/// Largest memset that is still considered valid while scanning for first stage tables
+const MAX_MEMSET: usize = 0x1000000;
+
+/// A single memory operation performed by the bootloader before entering the firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootOp {
+    /// Fill `len` bytes at `dst` with `val`
+    Memset { dst: usize, val: u8, len: usize },
+
+    /// Copy `len` bytes from `src` to `dst`
+    Memcpy { dst: usize, src: usize, len: usize },
+
+    /// Uncompress `len` lzss compressed bytes from `src` to `dst`
+    Uncompress { dst: usize, src: usize, len: usize },
+}
+
+impl BootOp {
+    /// Name of the routine performing this operation
+    pub fn name(&self) -> &'static str {
+        match self {
+            BootOp::Memset { .. } => "Memset",
+            BootOp::Memcpy { .. } => "Memcpy",
+            BootOp::Uncompress { .. } => "Uncompress",
+        }
+    }
+
+    /// Destination address of the operation
+    pub fn dst(&self) -> usize {
+        match *self {
+            BootOp::Memset { dst, .. } | BootOp::Memcpy { dst, .. } |
+                BootOp::Uncompress { dst, .. } => dst,
+        }
+    }
+
+    /// Source address of the operation, memsets do not read from the firmware
+    pub fn src(&self) -> Option<usize> {
+        match *self {
+            BootOp::Memset { .. } => None,
+            BootOp::Memcpy { src, .. } | BootOp::Uncompress { src, .. } => Some(src),
+        }
+    }
+
+    /// Length argument of the operation, for uncompress this is the compressed size
+    pub fn len(&self) -> usize {
+        match *self {
+            BootOp::Memset { len, .. } | BootOp::Memcpy { len, .. } |
+                BootOp::Uncompress { len, .. } => len,
+        }
+    }
+
+    /// Returns true if the operation does not touch any memory
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes the operation writes to `dst`
+    pub fn data(&self, firmware: &Firmware) -> Result<Vec<u8>> {
+        match *self {
+            BootOp::Memset { val, len, .. } => Ok(vec![val; len]),
+            BootOp::Memcpy { src, len, .. } => Ok(firmware.read(src, len)?.to_vec()),
+            BootOp::Uncompress { src, len, .. } => Ok(lzss_uncompress(firmware.read(src, len)?)),
+        }
+    }
+}
+
+/// Header of the application image, describing how the bootloader sets up memory
+#[derive(Default, Debug, Copy, Clone)]
+pub struct AppHeader {
+    magic: usize,
+    size: usize,
+    _magic1: usize,
+    _magic2: usize,
+    _bootsplash_bmp: usize,
+    entry_point: usize,
+    protected_count: usize,
+    protected_addr: usize,
+    section_linked_list: usize,
+    memset_list_start: usize,
+    memset_list_end: usize,
+    copy_list_start: usize,
+    copy_list_end: usize,
+    copy_list_barrier: usize,
+    uncompress_list_start: usize,
+    uncompress_list_end: usize,
+    uncompress_list_barrier: usize,
+}
+
+impl AppHeader {
+    /// Parse the app header out of the firmware, locating it by its magic value
+    pub fn parse(firmware: &Firmware) -> Result<Self> {
+        let app_hdr_index = find_app_header(firmware.data())?;
+
+        Ok(Self {
+            magic: APP_HEADER_MAGIC,
+            size: firmware.word(app_hdr_index+4)?,
+            _magic1: 0,
+            _magic2: 0,
+            _bootsplash_bmp: 0,
+            entry_point: firmware.word(app_hdr_index+52)?,
+            protected_count: firmware.word(app_hdr_index-4)?,
+            protected_addr: firmware.word(app_hdr_index+60)?,
+            section_linked_list: firmware.word(app_hdr_index+64)?,
+            memset_list_start: firmware.word(app_hdr_index+72)?,
+            memset_list_end: firmware.word(app_hdr_index+76)?,
+            copy_list_start: firmware.word(app_hdr_index+80)?,
+            copy_list_end: firmware.word(app_hdr_index+84)?,
+            copy_list_barrier: firmware.word(app_hdr_index+88)?,
+            uncompress_list_start: firmware.word(app_hdr_index+92)?,
+            uncompress_list_end: firmware.word(app_hdr_index+96)?,
+            uncompress_list_barrier: firmware.word(app_hdr_index+100)?,
+        })
+    }
+
+    /// Magic value identifying the app header, always `0x3ca55a3c`
+    pub fn magic(&self) -> usize {
+        self.magic
+    }
+
+    /// Size of the app header
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Address execution continues at once the bootloader is done
+    pub fn entry_point(&self) -> usize {
+        self.entry_point
+    }
+
+    /// Number of protected ranges
+    pub fn protected_count(&self) -> usize {
+        self.protected_count
+    }
+
+    /// Address of the list of protected ranges
+    pub fn protected_addr(&self) -> usize {
+        self.protected_addr
+    }
+
+    /// Address of the first element of the segment table
+    pub fn section_linked_list(&self) -> usize {
+        self.section_linked_list
+    }
+
+    /// Start and end address of the memset list
+    pub fn memset_list(&self) -> (usize, usize) {
+        (self.memset_list_start, self.memset_list_end)
+    }
+
+    /// Start and end address of the memcpy list
+    pub fn copy_list(&self) -> (usize, usize) {
+        (self.copy_list_start, self.copy_list_end)
+    }
+
+    /// Start and end address of the uncompress list
+    pub fn uncompress_list(&self) -> (usize, usize) {
+        (self.uncompress_list_start, self.uncompress_list_end)
+    }
+
+    /// Address within the memcpy list from which on entries are handled by the first stage
+    pub fn copy_list_barrier(&self) -> usize {
+        self.copy_list_barrier
+    }
+
+    /// Address within the uncompress list from which on entries are handled by the first stage
+    pub fn uncompress_list_barrier(&self) -> usize {
+        self.uncompress_list_barrier
+    }
+}
+
+/// Bootloader state recovered from the app header, describing the memory setup done before the
+/// firmware is entered
+#[derive(Default, Debug)]
+pub struct BootLoader {
+    /// App header used to parse out other important structures
+    header: AppHeader,
+
+    /// Each entry lists start and end addresses of a protected section. Protected sections should
+    /// not be overwritten
+    protected_ranges: Vec<(usize, usize)>,
+
+    /// dst, src, compressed_size that are passed to the uncompress section to later decompress
+    uncompress_tripples: Vec<(usize, usize, usize)>,
+
+    /// dst, src, length that are passed to the memcpy function to setup memory mappings
+    memcpy_tripples: Vec<(usize, usize, usize)>,
+
+    /// dst, val, length that are passed to the memset function to setup memory mappings
+    memset_tripples: Vec<(usize, usize, usize)>,
+
+    /// Operations performed by the first stage, before the app header lists are processed
+    first_stage: Vec<BootOp>,
+
+    /// First stage operations guessed by scanning its code, not parsed from any table
+    scanned: Vec<BootOp>,
+}
+
+/// Magic value at the start of the app header
+const APP_HEADER_MAGIC: usize = 0x3ca55a3c;
+
+/// Offset of the app_hdr struct within the firmware data, located based on its magic value
+pub fn find_app_header(data: &[u8]) -> Result<usize> {
+    let mut index = 0;
+    let mut app_hdr_index: Option<usize> = None;
+
+    for e in data.chunks_exact(4) {
+        if bytes_to_int_be(e, 4) == APP_HEADER_MAGIC {
+            if app_hdr_index.is_some() {
+                // Failed to automatically locate app header
+                return Err(Error::Duplicate("app header magic"));
+            }
+            app_hdr_index = Some(index);
+        }
+        index += 4;
+    }
+    let app_hdr_index = app_hdr_index.ok_or(Error::NotFound("app header"))?;
+    if app_hdr_index < 4 {
+        return Err(Error::Truncated { offset: app_hdr_index });
+    }
+    Ok(app_hdr_index)
+}
+
+impl BootLoader {
+    /// App header used to parse out other important structures
+    pub fn header(&self) -> &AppHeader {
+        &self.header
+    }
+
+    /// Start and end addresses of the protected sections
+    pub fn protected_ranges(&self) -> &[(usize, usize)] {
+        &self.protected_ranges
+    }
+
+    /// dst, src, compressed_size tripples passed to the uncompress routine
+    pub fn uncompress_tripples(&self) -> &[(usize, usize, usize)] {
+        &self.uncompress_tripples
+    }
+
+    /// Mutable access to the uncompress tripples, used to update compressed sizes when repacking
+    pub fn uncompress_tripples_mut(&mut self) -> &mut [(usize, usize, usize)] {
+        &mut self.uncompress_tripples
+    }
+
+    /// dst, src, length tripples passed to the memcpy routine
+    pub fn memcpy_tripples(&self) -> &[(usize, usize, usize)] {
+        &self.memcpy_tripples
+    }
+
+    /// dst, val, length tripples passed to the memset routine
+    pub fn memset_tripples(&self) -> &[(usize, usize, usize)] {
+        &self.memset_tripples
+    }
+
+    /// Parse out app header structure from firmware
+    pub fn parse_header(&mut self, firmware: &Firmware) -> Result<()> {
+        self.header = AppHeader::parse(firmware)?;
+        Ok(())
+    }
+
+    /// Parse out protected segments from firmware. These are address ranges that should not be
+    /// overwritten since they are virtal for the boot process
+    pub fn initialize_protected(&mut self, firmware: &Firmware) -> Result<()> {
+        for i in 0..self.header.protected_count {
+            let range = firmware.read(self.header.protected_addr + i*8, 8)?;
+            let start = bytes_to_int_be(&range[0..4], 4);
+            let end = bytes_to_int_be(&range[4..8], 4);
+
+            self.protected_ranges.push((start, end));
+        }
+        Ok(())
+    }
+
+    /// Split a list of big endian 32-bit words into the dst, src/val, length tripples consumed by
+    /// the bootloader routines
+    pub fn parse_tripples(data: &[u8]) -> Vec<(usize, usize, usize)> {
+        data.chunks_exact(12)
+            .map(|e| {
+                (
+                    bytes_to_int_be(&e[0..4], 4),
+                    bytes_to_int_be(&e[4..8], 4),
+                    bytes_to_int_be(&e[8..12], 4),
+                )
+            }).collect()
+    }
+
+    /// Parse out the uncompress, memset and memcpy tripples from the lists referenced by the app
+    /// header
+    pub fn initialize_tripples(&mut self, firmware: &Firmware) -> Result<()> {
+        // Closure to retrieve a list that is given by its start and end address
+        let list = |start: usize, end: usize| {
+            firmware.read(start, end.checked_sub(start).ok_or(Error::Truncated { offset: end })?)
+        };
+
+        // Parse out uncompress tripples
+        self.uncompress_tripples = Self::parse_tripples(
+            list(self.header.uncompress_list_start, self.header.uncompress_list_end)?);
+
+        // Parse out memset tripples
+        self.memset_tripples = Self::parse_tripples(
+            list(self.header.memset_list_start, self.header.memset_list_end)?);
+
+        // Parse out memcpy tripples
+        self.memcpy_tripples = Self::parse_tripples(
+            list(self.header.copy_list_start, self.header.copy_list_end)?);
+
+        Ok(())
+    }
+
+    /// First stage operations, available after `initialize_first_stage`
+    pub fn first_stage(&self) -> &[BootOp] {
+        &self.first_stage
+    }
+
+    /// Parse out the operations of the first stage. Entries following the barrier of the memcpy
+    /// and uncompress lists are handled by the first stage
+    pub fn initialize_first_stage(&mut self) {
+        let copy_barrier = barrier_index(self.header.copy_list_start, self.header.copy_list_end,
+                                         self.header.copy_list_barrier);
+        let uncompress_barrier = barrier_index(self.header.uncompress_list_start,
+                                               self.header.uncompress_list_end,
+                                               self.header.uncompress_list_barrier);
+
+        self.first_stage = self.memcpy_tripples.iter().skip(copy_barrier)
+            .map(|&(dst, src, len)| BootOp::Memcpy { dst, src, len })
+            .chain(self.uncompress_tripples.iter().skip(uncompress_barrier)
+                   .map(|&(dst, src, len)| BootOp::Uncompress { dst, src, len }))
+            .filter(|op| !op.is_empty())
+            .collect();
+    }
+
+    /// First stage operations guessed by `scan_first_stage`
+    pub fn scanned(&self) -> &[BootOp] {
+        &self.scanned
+    }
+
+    /// Returns true if the operation was guessed by `scan_first_stage` instead of being parsed
+    pub fn is_scanned(&self, op: &BootOp) -> bool {
+        self.scanned.contains(op)
+    }
+
+    /// Guess the uncompress and memset operations of the first stage. The memcpys of the first
+    /// stage load its code, which carries its own tables. Their location is not known, so the
+    /// copied code is scanned for plausible tripples. This is a heuristic that can match literal
+    /// pools, the results are kept apart from the parsed operations in `scanned`
+    pub fn scan_first_stage(&mut self, firmware: &Firmware) -> Result<()> {
+        let known: Vec<BootOp> = self.list_operations(usize::MAX, usize::MAX);
+        let mut scanned = Vec::new();
+        for op in &self.first_stage {
+            if let BootOp::Memcpy { src, len, .. } = *op {
+                for found in scan_tables(firmware.read(src, len)?, firmware) {
+                    if !known.contains(&found) && !self.first_stage.contains(&found)
+                            && !scanned.contains(&found) {
+                        scanned.push(found);
+                    }
+                }
+            }
+        }
+        self.scanned = scanned;
+        Ok(())
+    }
+
+    /// Operations from the app header lists, limited to the entries before the given barrier
+    /// indices of the memcpy and uncompress lists
+    fn list_operations(&self, copy_barrier: usize, uncompress_barrier: usize) -> Vec<BootOp> {
+        let memcpys = self.memcpy_tripples.iter().take(copy_barrier)
+            .map(|&(dst, src, len)| BootOp::Memcpy { dst, src, len });
+        let uncompresses = self.uncompress_tripples.iter().take(uncompress_barrier)
+            .map(|&(dst, src, len)| BootOp::Uncompress { dst, src, len });
+        let memsets = self.memset_tripples.iter()
+            .map(|&(dst, val, len)| BootOp::Memset { dst, val: val as u8, len });
+        memcpys.chain(uncompresses).chain(memsets).collect()
+    }
+
+    /// All operations parsed from the tables of the bootloader, in the order they are executed.
+    /// The first stage runs first, followed by the memcpy, uncompress and memset lists of the app
+    /// header. Empty operations are skipped. Operations guessed by `scan_first_stage` are not
+    /// included, see `operations_with_scanned`
+    pub fn operations(&self) -> Vec<BootOp> {
+        self.ordered_operations(&[])
+    }
+
+    /// All operations like `operations`, with the operations guessed by `scan_first_stage` run as
+    /// part of the first stage. `is_scanned` tells them apart
+    pub fn operations_with_scanned(&self) -> Vec<BootOp> {
+        self.ordered_operations(&self.scanned)
+    }
+
+    /// Operations in the order they are executed, running `scanned` after the first stage
+    fn ordered_operations(&self, scanned: &[BootOp]) -> Vec<BootOp> {
+        let copy_barrier = barrier_index(self.header.copy_list_start, self.header.copy_list_end,
+                                         self.header.copy_list_barrier);
+        let uncompress_barrier = barrier_index(self.header.uncompress_list_start,
+                                               self.header.uncompress_list_end,
+                                               self.header.uncompress_list_barrier);
+        self.first_stage.iter().chain(scanned).copied()
+            .chain(self.list_operations(copy_barrier, uncompress_barrier))
+            .filter(|op| !op.is_empty())
+            .collect()
+    }
+
+    /// Write all tripples back into their lists in the firmware, inverse of `initialize_tripples`
+    pub fn write_tripples(&self, firmware: &mut Firmware) -> Result<()> {
+        let lists = [
+            (self.header.uncompress_list_start, &self.uncompress_tripples),
+            (self.header.memset_list_start, &self.memset_tripples),
+            (self.header.copy_list_start, &self.memcpy_tripples),
+        ];
+        for (list_start, tripples) in lists {
+            let mut offset = list_start.checked_sub(firmware.header().load_addr())
+                .ok_or(Error::Truncated { offset: list_start })?;
+            for tripple in tripples {
+                for value in [tripple.0, tripple.1, tripple.2] {
+                    firmware.data_mut().get_mut(offset..offset + 4)
+                        .ok_or(Error::Truncated { offset })?
+                        .copy_from_slice(&(value as u32).to_be_bytes());
+                    offset += 4;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return true if given range overlaps with a protected section
+    pub fn is_protected(&self, start: usize, end: usize) -> bool {
+        for protected_section in self.protected_ranges.iter() {
+            if std::cmp::max(protected_section.0, start)
+                <= std::cmp::min(protected_section.1, end) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Verify that the given range does not overlap with a protected section
+    pub fn check_protected(&self, start: usize, end: usize) -> Result<()> {
+        if self.is_protected(start, end) {
+            return Err(Error::ProtectedOverlap { start, end });
+        }
+        Ok(())
+    }
+}
+
+/// Number of list entries before the barrier. Lists without a barrier inside of them are handled
+/// entirely by the second stage
+fn barrier_index(start: usize, end: usize, barrier: usize) -> usize {
+    if barrier >= start && barrier <= end && (barrier - start).is_multiple_of(TRIPPLE_SIZE) {
+        (barrier - start) / TRIPPLE_SIZE
+    } else {
+        end.saturating_sub(start) / TRIPPLE_SIZE
+    }
+}
+
+/// Scan code for uncompress and memset tripples. Uncompress tripples read from an aligned source
+/// within the firmware and write outside of it, memset tripples fill an aligned range outside of
+/// the firmware with a byte value
+fn scan_tables(code: &[u8], firmware: &Firmware) -> Vec<BootOp> {
+    let in_firmware = |addr: usize| firmware.read(addr, 1).is_ok();
+    let mut ops = Vec::new();
+    let mut offset = 0;
+
+    while offset + TRIPPLE_SIZE <= code.len() {
+        let dst = bytes_to_int_be(&code[offset..], 4);
+        let arg = bytes_to_int_be(&code[offset + 4..], 4);
+        let len = bytes_to_int_be(&code[offset + 8..], 4);
+
+        let op = if !dst.is_multiple_of(4) || dst == 0 || len == 0 || in_firmware(dst) {
+            None
+        } else if arg.is_multiple_of(4) && firmware.read(arg, len).is_ok() {
+            Some(BootOp::Uncompress { dst, src: arg, len })
+        } else if arg <= 0xff && len.is_multiple_of(4) && len <= MAX_MEMSET {
+            Some(BootOp::Memset { dst, val: arg as u8, len })
+        } else {
+            None
+        };
+
+        match op {
+            Some(op) => {
+                ops.push(op);
+                offset += TRIPPLE_SIZE;
+            }
+            None => offset += 4,
+        }
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Firmware loaded at the source of the first stage, with `code` at its start
+    fn firmware_with(code: &[u8]) -> Firmware {
+        let page_size = 0x800;
+        let load_size = 0x20000;
+        let mut flash = vec![0u8; page_size + load_size];
+        flash[0x0..0x4].copy_from_slice(&0xBAD2BFEDu32.to_be_bytes());
+        flash[0x10..0x14].copy_from_slice(&(page_size as u32).to_be_bytes());
+        flash[0x30..0x34].copy_from_slice(&0x26710000u32.to_be_bytes());
+        flash[0x34..0x38].copy_from_slice(&(load_size as u32).to_be_bytes());
+        flash[page_size..page_size + code.len()].copy_from_slice(code);
+
+        let mut firmware = Firmware::new();
+        firmware.parse_header(&flash).unwrap();
+        firmware.parse_data(&flash).unwrap();
+        firmware
+    }
+
+    /// First stage code holding an uncompress and three memset tripples
+    fn first_stage_code() -> Vec<u8> {
+        let mut code: Vec<u8> = (0..0x7b0u32).map(|i| (i * 0x9d) as u8 | 1).collect();
+        let tables = [(0x200890c0u32, 0x267107b0u32, 0x11be7u32), (0xa007cd20, 0, 0x40),
+                      (0xa0081160, 0, 0x7f58), (0x600788a0, 0, 0x4464)];
+        for (i, (dst, arg, len)) in tables.into_iter().enumerate() {
+            let offset = 0x700 + i * TRIPPLE_SIZE;
+            code[offset..offset + 4].copy_from_slice(&dst.to_be_bytes());
+            code[offset + 4..offset + 8].copy_from_slice(&arg.to_be_bytes());
+            code[offset + 8..offset + 12].copy_from_slice(&len.to_be_bytes());
+        }
+        code
+    }
+
+    /// Memcpy loading the first stage code
+    const FIRST_STAGE: BootOp = BootOp::Memcpy { dst: 0x60, src: 0x26710000, len: 0x7b0 };
+
+    /// Operations held by the tables of `first_stage_code`
+    const FIRST_STAGE_OPS: [BootOp; 4] = [
+        BootOp::Uncompress { dst: 0x200890c0, src: 0x267107b0, len: 0x11be7 },
+        BootOp::Memset { dst: 0xa007cd20, val: 0, len: 0x40 },
+        BootOp::Memset { dst: 0xa0081160, val: 0, len: 0x7f58 },
+        BootOp::Memset { dst: 0x600788a0, val: 0, len: 0x4464 },
+    ];
+
+    /// Bootloader with parsed tables, the first stage loads `first_stage_code`
+    fn parsed_bootloader() -> BootLoader {
+        BootLoader {
+            header: AppHeader {
+                uncompress_list_end: 2 * TRIPPLE_SIZE,
+                uncompress_list_barrier: 2 * TRIPPLE_SIZE,
+                ..Default::default()
+            },
+            first_stage: vec![FIRST_STAGE],
+            uncompress_tripples: vec![(0xa007cd60, 0x26724b18, 0x3cfa),
+                                      (0x2009fd8c, 0x26722398, 0x277f)],
+            memset_tripples: vec![(0x60000000, 0, 0xc7c)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn scan_finds_tables() {
+        let code = first_stage_code();
+        let firmware = firmware_with(&code);
+        assert_eq!(scan_tables(&code, &firmware), FIRST_STAGE_OPS);
+
+        let mut bootloader = parsed_bootloader();
+        bootloader.scan_first_stage(&firmware).unwrap();
+        assert_eq!(bootloader.scanned(), &FIRST_STAGE_OPS);
+        assert!(FIRST_STAGE_OPS.iter().all(|op| bootloader.is_scanned(op)));
+    }
+
+    #[test]
+    fn scanned_operations_on_request() {
+        let firmware = firmware_with(&first_stage_code());
+        let mut bootloader = parsed_bootloader();
+        bootloader.scan_first_stage(&firmware).unwrap();
+
+        let parsed = [
+            FIRST_STAGE,
+            BootOp::Uncompress { dst: 0xa007cd60, src: 0x26724b18, len: 0x3cfa },
+            BootOp::Uncompress { dst: 0x2009fd8c, src: 0x26722398, len: 0x277f },
+            BootOp::Memset { dst: 0x60000000, val: 0, len: 0xc7c },
+        ];
+        assert_eq!(bootloader.operations(), parsed);
+
+        // Scanned operations run right after the first stage
+        let mut expected = vec![FIRST_STAGE];
+        expected.extend(FIRST_STAGE_OPS);
+        expected.extend(&parsed[1..]);
+        assert_eq!(bootloader.operations_with_scanned(), expected);
+    }
+
+    #[test]
+    fn scan_picks_up_literal_pools() {
+        // A literal pool that happens to look like a memset is only ever a guess
+        let mut code = first_stage_code();
+        code[0x100..0x10c].copy_from_slice(&[0x20, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 0]);
+        let firmware = firmware_with(&code);
+
+        let mut bootloader = parsed_bootloader();
+        bootloader.scan_first_stage(&firmware).unwrap();
+        let pool = BootOp::Memset { dst: 0x20000000, val: 1, len: 0x100 };
+        assert!(bootloader.is_scanned(&pool));
+        assert!(!bootloader.operations().contains(&pool));
+        assert!(bootloader.operations_with_scanned().contains(&pool));
+    }
+}
@@ -0,0 +1,368 @@
+use crate::error::{Error, Result};
+
+/// Length of the longest run length or mode code
+const MAX_CODE_LEN: u8 = 13;
+
+/// Number of zero bits preceding the one bit of an end of line code
+const EOL_ZEROS: usize = 11;
+
+/// Coding schemes of CCITT fax compressed rows, as selected by raster compression modes 6, 7 and 8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    /// Group 3 one-dimensional (Modified Huffman), rows are sequences of alternating runs
+    Group3OneDim,
+
+    /// Group 3 two-dimensional (Modified READ), a tag bit selects one- or two-dimensional coding
+    Group3TwoDim,
+
+    /// Group 4 (Modified Modified READ), every row is coded against the previous row
+    Group4,
+}
+
+/// Coding modes of two-dimensional rows
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    /// Skip to below the second changing element of the reference row
+    Pass,
+
+    /// Two runs coded the same way as in one-dimensional rows
+    Horizontal,
+
+    /// Next change lies the given distance from the changing element of the reference row
+    Vertical(isize),
+}
+
+/// Codes of the two-dimensional coding modes
+const MODES: &[(u8, u16, Mode)] = &[
+    (1, 0b1, Mode::Vertical(0)), (3, 0b011, Mode::Vertical(1)), (3, 0b010, Mode::Vertical(-1)),
+    (3, 0b001, Mode::Horizontal), (4, 0b0001, Mode::Pass), (6, 0b000011, Mode::Vertical(2)),
+    (6, 0b000010, Mode::Vertical(-2)), (7, 0b0000011, Mode::Vertical(3)),
+    (7, 0b0000010, Mode::Vertical(-3)),
+];
+
+/// Codes of white runs of 0 to 63 pixels, as `(length, code, run)`
+const WHITE_TERMINATING: &[(u8, u16, u16)] = &[
+    (8, 0b00110101, 0), (6, 0b000111, 1), (4, 0b0111, 2), (4, 0b1000, 3), (4, 0b1011, 4),
+    (4, 0b1100, 5), (4, 0b1110, 6), (4, 0b1111, 7), (5, 0b10011, 8), (5, 0b10100, 9),
+    (5, 0b00111, 10), (5, 0b01000, 11), (6, 0b001000, 12), (6, 0b000011, 13), (6, 0b110100, 14),
+    (6, 0b110101, 15), (6, 0b101010, 16), (6, 0b101011, 17), (7, 0b0100111, 18), (7, 0b0001100, 19),
+    (7, 0b0001000, 20), (7, 0b0010111, 21), (7, 0b0000011, 22), (7, 0b0000100, 23),
+    (7, 0b0101000, 24), (7, 0b0101011, 25), (7, 0b0010011, 26), (7, 0b0100100, 27),
+    (7, 0b0011000, 28), (8, 0b00000010, 29), (8, 0b00000011, 30), (8, 0b00011010, 31),
+    (8, 0b00011011, 32), (8, 0b00010010, 33), (8, 0b00010011, 34), (8, 0b00010100, 35),
+    (8, 0b00010101, 36), (8, 0b00010110, 37), (8, 0b00010111, 38), (8, 0b00101000, 39),
+    (8, 0b00101001, 40), (8, 0b00101010, 41), (8, 0b00101011, 42), (8, 0b00101100, 43),
+    (8, 0b00101101, 44), (8, 0b00000100, 45), (8, 0b00000101, 46), (8, 0b00001010, 47),
+    (8, 0b00001011, 48), (8, 0b01010010, 49), (8, 0b01010011, 50), (8, 0b01010100, 51),
+    (8, 0b01010101, 52), (8, 0b00100100, 53), (8, 0b00100101, 54), (8, 0b01011000, 55),
+    (8, 0b01011001, 56), (8, 0b01011010, 57), (8, 0b01011011, 58), (8, 0b01001010, 59),
+    (8, 0b01001011, 60), (8, 0b00110010, 61), (8, 0b00110011, 62), (8, 0b00110100, 63),
+];
+
+/// Codes of white runs of multiples of 64 up to 1728 pixels
+const WHITE_MAKEUP: &[(u8, u16, u16)] = &[
+    (5, 0b11011, 64), (5, 0b10010, 128), (6, 0b010111, 192), (7, 0b0110111, 256),
+    (8, 0b00110110, 320), (8, 0b00110111, 384), (8, 0b01100100, 448), (8, 0b01100101, 512),
+    (8, 0b01101000, 576), (8, 0b01100111, 640), (9, 0b011001100, 704), (9, 0b011001101, 768),
+    (9, 0b011010010, 832), (9, 0b011010011, 896), (9, 0b011010100, 960), (9, 0b011010101, 1024),
+    (9, 0b011010110, 1088), (9, 0b011010111, 1152), (9, 0b011011000, 1216), (9, 0b011011001, 1280),
+    (9, 0b011011010, 1344), (9, 0b011011011, 1408), (9, 0b010011000, 1472), (9, 0b010011001, 1536),
+    (9, 0b010011010, 1600), (6, 0b011000, 1664), (9, 0b010011011, 1728),
+];
+
+/// Codes of black runs of 0 to 63 pixels
+const BLACK_TERMINATING: &[(u8, u16, u16)] = &[
+    (10, 0b0000110111, 0), (3, 0b010, 1), (2, 0b11, 2), (2, 0b10, 3), (3, 0b011, 4), (4, 0b0011, 5),
+    (4, 0b0010, 6), (5, 0b00011, 7), (6, 0b000101, 8), (6, 0b000100, 9), (7, 0b0000100, 10),
+    (7, 0b0000101, 11), (7, 0b0000111, 12), (8, 0b00000100, 13), (8, 0b00000111, 14),
+    (9, 0b000011000, 15), (10, 0b0000010111, 16), (10, 0b0000011000, 17), (10, 0b0000001000, 18),
+    (11, 0b00001100111, 19), (11, 0b00001101000, 20), (11, 0b00001101100, 21),
+    (11, 0b00000110111, 22), (11, 0b00000101000, 23), (11, 0b00000010111, 24),
+    (11, 0b00000011000, 25), (12, 0b000011001010, 26), (12, 0b000011001011, 27),
+    (12, 0b000011001100, 28), (12, 0b000011001101, 29), (12, 0b000001101000, 30),
+    (12, 0b000001101001, 31), (12, 0b000001101010, 32), (12, 0b000001101011, 33),
+    (12, 0b000011010010, 34), (12, 0b000011010011, 35), (12, 0b000011010100, 36),
+    (12, 0b000011010101, 37), (12, 0b000011010110, 38), (12, 0b000011010111, 39),
+    (12, 0b000001101100, 40), (12, 0b000001101101, 41), (12, 0b000011011010, 42),
+    (12, 0b000011011011, 43), (12, 0b000001010100, 44), (12, 0b000001010101, 45),
+    (12, 0b000001010110, 46), (12, 0b000001010111, 47), (12, 0b000001100100, 48),
+    (12, 0b000001100101, 49), (12, 0b000001010010, 50), (12, 0b000001010011, 51),
+    (12, 0b000000100100, 52), (12, 0b000000110111, 53), (12, 0b000000111000, 54),
+    (12, 0b000000100111, 55), (12, 0b000000101000, 56), (12, 0b000001011000, 57),
+    (12, 0b000001011001, 58), (12, 0b000000101011, 59), (12, 0b000000101100, 60),
+    (12, 0b000001011010, 61), (12, 0b000001100110, 62), (12, 0b000001100111, 63),
+];
+
+/// Codes of black runs of multiples of 64 up to 1728 pixels
+const BLACK_MAKEUP: &[(u8, u16, u16)] = &[
+    (10, 0b0000001111, 64), (12, 0b000011001000, 128), (12, 0b000011001001, 192),
+    (12, 0b000001011011, 256), (12, 0b000000110011, 320), (12, 0b000000110100, 384),
+    (12, 0b000000110101, 448), (13, 0b0000001101100, 512), (13, 0b0000001101101, 576),
+    (13, 0b0000001001010, 640), (13, 0b0000001001011, 704), (13, 0b0000001001100, 768),
+    (13, 0b0000001001101, 832), (13, 0b0000001110010, 896), (13, 0b0000001110011, 960),
+    (13, 0b0000001110100, 1024), (13, 0b0000001110101, 1088), (13, 0b0000001110110, 1152),
+    (13, 0b0000001110111, 1216), (13, 0b0000001010010, 1280), (13, 0b0000001010011, 1344),
+    (13, 0b0000001010100, 1408), (13, 0b0000001010101, 1472), (13, 0b0000001011010, 1536),
+    (13, 0b0000001011011, 1600), (13, 0b0000001100100, 1664), (13, 0b0000001100101, 1728),
+];
+
+/// Codes of runs of multiples of 64 from 1792 up to 2560 pixels, shared by both colors
+const EXTENDED_MAKEUP: &[(u8, u16, u16)] = &[
+    (11, 0b00000001000, 1792), (11, 0b00000001100, 1856), (11, 0b00000001101, 1920),
+    (12, 0b000000010010, 1984), (12, 0b000000010011, 2048), (12, 0b000000010100, 2112),
+    (12, 0b000000010101, 2176), (12, 0b000000010110, 2240), (12, 0b000000010111, 2304),
+    (12, 0b000000011100, 2368), (12, 0b000000011101, 2432), (12, 0b000000011110, 2496),
+    (12, 0b000000011111, 2560),
+];
+
+/// Reads the compressed data bit by bit, most significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    /// Read a single bit
+    fn bit(&mut self) -> Result<u16> {
+        let byte = *self.data.get(self.pos / 8).ok_or(Error::Truncated { offset: self.pos / 8 })?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(bit as u16)
+    }
+
+    /// Read the next code and look it up in `tables`
+    fn code<T: Copy>(&mut self, tables: &[&[(u8, u16, T)]]) -> Result<T> {
+        let start = self.pos;
+        let mut code = 0;
+        for len in 1..=MAX_CODE_LEN {
+            code = (code << 1) | self.bit()?;
+            let found = tables.iter()
+                .flat_map(|table| table.iter())
+                .find(|&&(l, c, _)| l == len && c == code);
+            if let Some(&(_, _, value)) = found {
+                return Ok(value);
+            }
+        }
+        Err(Error::BadCompressedData { offset: start / 8 })
+    }
+
+    /// Read a complete run, made up of any number of makeup codes and a terminating code
+    fn run(&mut self, white: bool) -> Result<usize> {
+        let tables: [&[(u8, u16, u16)]; 3] = if white {
+            [WHITE_TERMINATING, WHITE_MAKEUP, EXTENDED_MAKEUP]
+        } else {
+            [BLACK_TERMINATING, BLACK_MAKEUP, EXTENDED_MAKEUP]
+        };
+        let mut total = 0;
+        loop {
+            let run = self.code(&tables)? as usize;
+            total += run;
+            if run < 64 {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Skip an end of line code, including any fill bits before it, if one follows
+    fn skip_eol(&mut self) {
+        let start = self.pos;
+        let mut zeros = 0;
+        loop {
+            match self.bit() {
+                Ok(0) => zeros += 1,
+                Ok(_) if zeros >= EOL_ZEROS => return,
+                _ => break,
+            }
+        }
+        self.pos = start;
+    }
+}
+
+/// Whether pixel `index` of a packed row is black. Pixels past the end of the row are white
+fn is_black(row: &[u8], index: usize) -> bool {
+    row.get(index / 8).is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+}
+
+/// Set pixels `start..end` of a packed row to black
+fn fill_black(row: &mut [u8], start: usize, end: usize) {
+    for index in start..end {
+        row[index / 8] |= 0x80 >> (index % 8);
+    }
+}
+
+/// Decode a one-dimensional row of alternating white and black runs, starting with white
+fn decode_one_dim(bits: &mut BitReader, row: &mut [u8], width: usize) -> Result<()> {
+    let mut pos = 0;
+    let mut white = true;
+    while pos < width {
+        let end = std::cmp::min(pos + bits.run(white)?, width);
+        if !white {
+            fill_black(row, pos, end);
+        }
+        pos = end;
+        white = !white;
+    }
+    Ok(())
+}
+
+/// Decode a two-dimensional row, coded as changes relative to the `reference` row
+fn decode_two_dim(bits: &mut BitReader, row: &mut [u8], reference: &[u8], width: usize)
+        -> Result<()> {
+    // Positions at which the color of the reference row changes, starting from white. Changes at
+    // even indices are to black, changes at odd indices are back to white
+    let changes: Vec<usize> = (0..width)
+        .filter(|&i| is_black(reference, i) != (i > 0 && is_black(reference, i - 1)))
+        .collect();
+
+    let mut a0: isize = -1;
+    let mut white = true;
+    while a0 < width as isize {
+        let offset = bits.pos / 8;
+        let mode = bits.code(&[MODES])?;
+
+        // First change of the reference row right of a0 to the opposite color of a0, and the next
+        let index = changes.iter()
+            .enumerate()
+            .position(|(i, &c)| c as isize > a0 && (i % 2 == 0) == white)
+            .unwrap_or(changes.len());
+        let b1 = changes.get(index).copied().unwrap_or(width);
+        let b2 = changes.get(index + 1).copied().unwrap_or(width);
+        let start = std::cmp::max(a0, 0) as usize;
+
+        match mode {
+            Mode::Pass => {
+                if !white {
+                    fill_black(row, start, b2);
+                }
+                a0 = b2 as isize;
+            }
+            Mode::Horizontal => {
+                let first = std::cmp::min(start + bits.run(white)?, width);
+                let second = std::cmp::min(first + bits.run(!white)?, width);
+                if white {
+                    fill_black(row, first, second);
+                } else {
+                    fill_black(row, start, first);
+                }
+                a0 = second as isize;
+            }
+            Mode::Vertical(delta) => {
+                let a1 = b1 as isize + delta;
+                if a1 < start as isize || a1 > width as isize {
+                    return Err(Error::BadCompressedData { offset });
+                }
+                if !white {
+                    fill_black(row, start, a1 as usize);
+                }
+                a0 = a1;
+                white = !white;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decode a single CCITT fax compressed row of `width` pixels, packed 8 to a byte with black pixels
+/// set. Two-dimensional rows are decoded against the `reference` row. An end of line code in front
+/// of the row is skipped
+pub fn decode_row(data: &[u8], reference: &[u8], width: usize, coding: Coding) -> Result<Vec<u8>> {
+    let mut bits = BitReader { data, pos: 0 };
+    let mut row = vec![0u8; width.div_ceil(8)];
+    if coding != Coding::Group4 {
+        bits.skip_eol();
+    }
+
+    let two_dim = match coding {
+        Coding::Group3OneDim => false,
+        Coding::Group3TwoDim => bits.bit()? == 0,
+        Coding::Group4 => true,
+    };
+    if two_dim {
+        decode_two_dim(&mut bits, &mut row, reference, width)?;
+    } else {
+        decode_one_dim(&mut bits, &mut row, width)?;
+    }
+    Ok(row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// End of line code in front of group 3 rows
+    const EOL: &str = "000000000001";
+
+    /// Pack a string of bits, most significant bit first. Spaces are ignored
+    fn bits(codes: &str) -> Vec<u8> {
+        let bits: Vec<u8> = codes.bytes().filter(|&b| b != b' ').map(|b| b - b'0').collect();
+        bits.chunks(8)
+            .map(|chunk| chunk.iter().enumerate().fold(0, |byte, (i, bit)| byte | bit << (7 - i)))
+            .collect()
+    }
+
+    /// Packed row of `width` pixels with the pixels in `ranges` black
+    fn row(width: usize, ranges: &[(usize, usize)]) -> Vec<u8> {
+        let mut row = vec![0; width.div_ceil(8)];
+        for &(start, end) in ranges {
+            fill_black(&mut row, start, end);
+        }
+        row
+    }
+
+    #[test]
+    fn one_dim_row() {
+        // White 3, black 2 and white 11, behind an end of line code
+        let data = bits(&format!("{} 1000 11 01000", EOL));
+        assert_eq!(decode_row(&data, &[], 16, Coding::Group3OneDim).unwrap(), [0x18, 0x00]);
+    }
+
+    #[test]
+    fn one_dim_makeup_codes() {
+        // White 1792 + 8 with an extended makeup code, black 128 + 2 and white 64 + 6
+        let data = bits("00000001000 10011 000011001000 11 11011 1110");
+        assert_eq!(decode_row(&data, &[], 2000, Coding::Group3OneDim).unwrap(),
+                   row(2000, &[(1800, 1930)]));
+    }
+
+    #[test]
+    fn group3_two_dim_rows() {
+        let reference = row(16, &[(3, 5)]);
+
+        // Tag bit 1 selects a one-dimensional row
+        let data = bits(&format!("{} 1 1000 11 01000", EOL));
+        assert_eq!(decode_row(&data, &reference, 16, Coding::Group3TwoDim).unwrap(), reference);
+
+        // V0 and VR1 widen the black run by one, horizontal mode codes white 4 and black 2 and V0
+        // finishes the row
+        let data = bits(&format!("{} 0 1 011 001 1011 11 1", EOL));
+        assert_eq!(decode_row(&data, &reference, 16, Coding::Group3TwoDim).unwrap(),
+                   row(16, &[(3, 6), (10, 12)]));
+    }
+
+    #[test]
+    fn group4_rows() {
+        // Pass skips the first black run, V0 and VL1 keep a single pixel of the second
+        let reference = row(16, &[(2, 4), (8, 10)]);
+        let data = bits("0001 1 010 1");
+        assert_eq!(decode_row(&data, &reference, 16, Coding::Group4).unwrap(),
+                   row(16, &[(8, 9)]));
+
+        // Horizontal mode with makeup codes for both runs, white 64 + 6 and black 128 + 2
+        let data = bits("001 11011 1110 000011001000 11");
+        assert_eq!(decode_row(&data, &row(200, &[]), 200, Coding::Group4).unwrap(),
+                   row(200, &[(70, 200)]));
+    }
+
+    #[test]
+    fn invalid_rows() {
+        // Thirteen zero bits are no valid code, and rows may not end before their last pixel
+        assert!(matches!(decode_row(&[0, 0], &[], 16, Coding::Group3OneDim),
+                         Err(Error::BadCompressedData { offset: 0 })));
+        assert!(matches!(decode_row(&bits("1000"), &[], 16, Coding::Group3OneDim),
+                         Err(Error::Truncated { offset: 1 })));
+
+        // Vertical modes may not move left of the current position
+        assert!(matches!(decode_row(&bits("0000010"), &row(16, &[(1, 2)]), 16, Coding::Group4),
+                         Err(Error::BadCompressedData { offset: 0 })));
+    }
+}
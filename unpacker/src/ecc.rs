@@ -0,0 +1,494 @@
+use crate::error::{Error, Result};
+
+/// Degree of the Galois field used by the BCH codes, GF(2^13) covers 512 and 1024 byte steps
+const BCH_M: usize = 13;
+
+/// Primitive polynomial of GF(2^13)
+const BCH_PRIM_POLY: usize = 0x201b;
+
+/// Number of non-zero elements of GF(2^13)
+const BCH_N: usize = (1 << BCH_M) - 1;
+
+/// Error correcting code protecting one step of a NAND page
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EccScheme {
+    /// Hamming code as used by the Linux software ECC, 3 bytes per 256 or 512 byte step. Corrects
+    /// single bit errors. `swapped` stores the two line parity bytes in reverse order, which is
+    /// the Linux default, while the SmartMedia order keeps them in order
+    Hamming { step: usize, swapped: bool },
+
+    /// Binary BCH code over GF(2^13) correcting up to `t` bit errors per step
+    Bch(Bch),
+}
+
+impl EccScheme {
+    /// Number of data bytes protected by a single ECC
+    pub fn step(&self) -> usize {
+        match self {
+            EccScheme::Hamming { step, .. } => *step,
+            EccScheme::Bch(bch) => bch.step,
+        }
+    }
+
+    /// Number of ECC bytes per step
+    pub fn ecc_bytes(&self) -> usize {
+        match self {
+            EccScheme::Hamming { .. } => 3,
+            EccScheme::Bch(bch) => bch.ecc_bytes(),
+        }
+    }
+
+    /// Calculate the ECC of a single step of data
+    pub fn calculate(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            EccScheme::Hamming { swapped, .. } => hamming_calculate(data, *swapped).to_vec(),
+            EccScheme::Bch(bch) => bch.calculate(data),
+        }
+    }
+
+    /// Verify a step of data against its stored ECC and correct both in place. Returns the number
+    /// of bits that were flipped, or `None` if the errors can not be corrected
+    pub fn correct(&self, data: &mut [u8], ecc: &mut [u8]) -> Option<usize> {
+        match self {
+            EccScheme::Hamming { swapped, .. } => hamming_correct(data, ecc, *swapped),
+            EccScheme::Bch(bch) => bch.correct(data, ecc),
+        }
+    }
+}
+
+/// Calculate the 3 byte Hamming ECC of 256 or 512 bytes of data. Every line parity pair covers
+/// one bit of the byte index, the column parities cover the bits within the bytes
+fn hamming_calculate(data: &[u8], swapped: bool) -> [u8; 3] {
+    let mut line = 0u32;
+    let mut column = 0u8;
+    for (i, &byte) in data.iter().enumerate() {
+        column ^= byte;
+        if byte.count_ones() % 2 == 1 {
+            // Odd line parities cover the bytes with the index bit set, even ones the others
+            for bit in 0..9 {
+                line ^= 1 << (2 * bit + ((i >> bit) & 1));
+            }
+        }
+    }
+
+    // Column parities, in pairs of complementing bit groups
+    let parity = |mask: u8| (column & mask).count_ones() as u8 & 1;
+    let cp = parity(0x55) | parity(0xaa) << 1 | parity(0x33) << 2 | parity(0xcc) << 3 |
+        parity(0x0f) << 4 | parity(0xf0) << 5;
+
+    // The index bits of 256 byte steps only cover 16 line parities, the unused ones stay set
+    let line = if data.len() > 256 { line } else { line & 0xffff };
+    let lp_low = !(line as u8);
+    let lp_high = !((line >> 8) as u8);
+    let code2 = !((cp << 2) | ((line >> 16) as u8 & 3));
+    if swapped {
+        [lp_high, lp_low, code2]
+    } else {
+        [lp_low, lp_high, code2]
+    }
+}
+
+/// Correct a single bit error in 256 or 512 bytes of data using the stored Hamming ECC
+fn hamming_correct(data: &mut [u8], ecc: &mut [u8], swapped: bool) -> Option<usize> {
+    let calc = hamming_calculate(data, swapped);
+    let (lo, hi) = if swapped { (1, 0) } else { (0, 1) };
+    let diff = (ecc[lo] ^ calc[lo]) as u32 | ((ecc[hi] ^ calc[hi]) as u32) << 8 |
+        ((ecc[2] ^ calc[2]) as u32) << 16;
+    if diff == 0 {
+        return Some(0);
+    }
+
+    // A single flipped data bit flips exactly one parity of every pair
+    let index_bits = if data.len() > 256 { 9 } else { 8 };
+    let lines = diff & ((1 << (2 * index_bits)) - 1);
+    let columns = (diff >> 18) & 0x3f;
+    let pairs_ok = |bits: u32, pairs: usize| (0..pairs).all(|p| (bits >> (2 * p)) & 3 != 0 &&
+                                                          (bits >> (2 * p)) & 3 != 3);
+    if pairs_ok(lines, index_bits) && pairs_ok(columns, 3) {
+        let index = (0..index_bits).fold(0, |acc, bit| acc | (((lines >> (2 * bit + 1)) & 1) << bit));
+        let bit = (0..3).fold(0, |acc, b| acc | (((columns >> (2 * b + 1)) & 1) << b));
+        data[index as usize] ^= 1 << bit;
+        return Some(1);
+    }
+
+    // A single flipped bit within the ECC itself
+    if diff.count_ones() == 1 {
+        ecc[..3].copy_from_slice(&calc);
+        return Some(1);
+    }
+    None
+}
+
+/// Binary BCH code over GF(2^13)
+#[derive(Clone, PartialEq, Eq)]
+pub struct Bch {
+    /// Number of data bytes per step
+    step: usize,
+
+    /// Number of correctable bit errors per step
+    t: usize,
+
+    /// Value xored into the ECC, the inverted ECC of an erased step if the ECC is masked so
+    /// erased pages carry an erased (0xFF) ECC
+    mask: u128,
+
+    /// Generator polynomial without its leading term, bit `i` holds the coefficient of x^i
+    generator: u128,
+
+    /// Degree of the generator polynomial, the number of ECC bits
+    degree: usize,
+
+    /// Remainder of every byte value shifted to the top of the remainder register
+    table: Vec<u128>,
+
+    /// Powers of the primitive element
+    exp: Vec<u16>,
+
+    /// Logarithms of the field elements
+    log: Vec<u16>,
+}
+
+impl std::fmt::Debug for Bch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Bch")
+            .field("step", &self.step)
+            .field("t", &self.t)
+            .field("masked", &(self.mask != 0))
+            .finish()
+    }
+}
+
+impl Bch {
+    /// Create a BCH code correcting `t` bit errors in every `step` bytes. Data bits are taken most
+    /// significant bit first and the ECC is packed with the highest degree first. `t` is limited
+    /// to 9, so the ECC bits fit into the remainder register. `masked` xors the ECC with the
+    /// inverted ECC of an erased step
+    pub fn new(step: usize, t: usize, masked: bool) -> Result<Self> {
+        if t == 0 || t > 9 {
+            return Err(Error::InvalidConfig("BCH strength must be between 1 and 9 bits"));
+        }
+        if step == 0 || step * 8 + BCH_M * t > BCH_N {
+            return Err(Error::InvalidConfig("BCH step does not fit into the code length"));
+        }
+
+        let mut exp = vec![0u16; 2 * BCH_N];
+        let mut log = vec![0u16; BCH_N + 1];
+        let mut x = 1usize;
+        for i in 0..BCH_N {
+            exp[i] = x as u16;
+            exp[i + BCH_N] = x as u16;
+            log[x] = i as u16;
+            x <<= 1;
+            if x & (1 << BCH_M) != 0 {
+                x ^= BCH_PRIM_POLY;
+            }
+        }
+
+        // The generator is the product of the minimal polynomials of the odd powers up to 2t-1,
+        // each of which has the conjugates of its power as roots
+        let mut generator = 1u128;
+        let mut degree = 0;
+        let mut used = vec![false; BCH_N];
+        for i in (1..2 * t).step_by(2) {
+            if used[i] {
+                continue;
+            }
+            let mut minimal = vec![1u16];
+            let mut root = i;
+            while !used[root] {
+                used[root] = true;
+                // minimal *= (x + a^root)
+                let mut next = vec![0u16; minimal.len() + 1];
+                for (j, &coeff) in minimal.iter().enumerate() {
+                    next[j + 1] ^= coeff;
+                    if coeff != 0 {
+                        next[j] ^= exp[(log[coeff as usize] as usize + root) % BCH_N];
+                    }
+                }
+                minimal = next;
+                root = (root * 2) % BCH_N;
+            }
+
+            // The minimal polynomial has binary coefficients, multiply it into the generator
+            let mut product = 0u128;
+            for (j, &coeff) in minimal.iter().enumerate() {
+                if coeff != 0 {
+                    product ^= generator << j;
+                }
+            }
+            generator = product;
+            degree += minimal.len() - 1;
+        }
+
+        let mut bch = Self {
+            step,
+            t,
+            mask: 0,
+            generator: generator & !(1u128 << degree),
+            degree,
+            table: Vec::new(),
+            exp,
+            log,
+        };
+        bch.table = (0..256u128)
+            .map(|byte| {
+                let mut rem = byte << (bch.degree - 8);
+                for _ in 0..8 {
+                    rem = bch.shift(rem);
+                }
+                rem
+            })
+            .collect();
+        if masked {
+            bch.mask = !bch.remainder(&vec![0xff; step]) & ((1u128 << bch.degree) - 1);
+        }
+        Ok(bch)
+    }
+
+    /// Number of correctable bit errors per step
+    pub fn t(&self) -> usize {
+        self.t
+    }
+
+    /// Number of ECC bytes per step
+    pub fn ecc_bytes(&self) -> usize {
+        self.degree.div_ceil(8)
+    }
+
+    /// Multiply the remainder register by x modulo the generator
+    fn shift(&self, rem: u128) -> u128 {
+        let feedback = (rem >> (self.degree - 1)) & 1 != 0;
+        let rem = (rem << 1) & ((1u128 << self.degree) - 1);
+        if feedback { rem ^ self.generator } else { rem }
+    }
+
+    /// Remainder of the data multiplied by x^degree modulo the generator, data bits are taken
+    /// most significant bit first
+    fn remainder(&self, data: &[u8]) -> u128 {
+        data.iter().fold(0u128, |rem, &byte| {
+            let top = ((rem >> (self.degree - 8)) as u8) ^ byte;
+            ((rem << 8) & ((1u128 << self.degree) - 1)) ^ self.table[top as usize]
+        })
+    }
+
+    /// Pack a remainder into ECC bytes, left aligned with the highest degree first
+    fn pack(&self, rem: u128) -> Vec<u8> {
+        let bytes = self.ecc_bytes();
+        let aligned = rem << (bytes * 8 - self.degree);
+        (0..bytes).rev().map(|i| (aligned >> (i * 8)) as u8).collect()
+    }
+
+    /// Unpack ECC bytes into a remainder
+    fn unpack(&self, ecc: &[u8]) -> u128 {
+        let bytes = self.ecc_bytes();
+        let aligned = ecc[..bytes].iter().fold(0u128, |acc, &b| (acc << 8) | b as u128);
+        aligned >> (bytes * 8 - self.degree)
+    }
+
+    /// Calculate the ECC of a single step of data
+    pub fn calculate(&self, data: &[u8]) -> Vec<u8> {
+        self.pack(self.remainder(data) ^ self.mask)
+    }
+
+    /// Multiply two field elements
+    fn mul(&self, a: u16, b: u16) -> u16 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+    }
+
+    /// Divide two field elements
+    fn div(&self, a: u16, b: u16) -> u16 {
+        if a == 0 {
+            return 0;
+        }
+        self.exp[(self.log[a as usize] as usize + BCH_N - self.log[b as usize] as usize) % BCH_N]
+    }
+
+    /// Verify a step of data against its stored ECC and correct both in place. Returns the number
+    /// of bits that were flipped, or `None` if the errors can not be corrected
+    pub fn correct(&self, data: &mut [u8], ecc: &mut [u8]) -> Option<usize> {
+        // The syndromes of the received codeword only depend on its remainder modulo the
+        // generator, which is the difference of the calculated and stored ECC
+        let rem = self.remainder(data) ^ self.mask ^ self.unpack(ecc);
+        if rem == 0 {
+            return Some(0);
+        }
+
+        let syndromes: Vec<u16> = (1..=2 * self.t)
+            .map(|j| {
+                (0..self.degree)
+                    .filter(|&bit| (rem >> bit) & 1 != 0)
+                    .fold(0, |acc, bit| acc ^ self.exp[(j * bit) % BCH_N])
+            })
+            .collect();
+
+        // Berlekamp-Massey to find the error locator polynomial
+        let mut locator = vec![1u16];
+        let mut prev = vec![1u16];
+        let mut len = 0;
+        let mut shift = 1;
+        let mut prev_disc = 1u16;
+        for n in 0..syndromes.len() {
+            let disc = (1..=len).fold(syndromes[n], |acc, i| {
+                acc ^ self.mul(*locator.get(i).unwrap_or(&0), syndromes[n - i])
+            });
+            if disc == 0 {
+                shift += 1;
+                continue;
+            }
+
+            let scale = self.div(disc, prev_disc);
+            let mut next = locator.clone();
+            next.resize(std::cmp::max(next.len(), prev.len() + shift), 0);
+            for (i, &coeff) in prev.iter().enumerate() {
+                next[i + shift] ^= self.mul(scale, coeff);
+            }
+            if 2 * len <= n {
+                prev = std::mem::replace(&mut locator, next);
+                len = n + 1 - len;
+                prev_disc = disc;
+                shift = 1;
+            } else {
+                locator = next;
+                shift += 1;
+            }
+        }
+        if len > self.t {
+            return None;
+        }
+
+        // Chien search, bit position p is in error if the locator has a root at a^-p. Positions
+        // below the degree are ECC bits, the data bits follow from the last one to the first
+        let data_bits = data.len() * 8;
+        let mut errors = Vec::new();
+        for pos in 0..self.degree + data_bits {
+            let inv = (BCH_N - pos % BCH_N) % BCH_N;
+            let value = locator.iter().enumerate().fold(0, |acc, (i, &coeff)| {
+                acc ^ self.mul(coeff, self.exp[(inv * i) % BCH_N])
+            });
+            if value == 0 {
+                errors.push(pos);
+            }
+        }
+        if errors.len() != len {
+            return None;
+        }
+
+        let mut ecc_rem = self.unpack(ecc);
+        for &pos in &errors {
+            if pos < self.degree {
+                ecc_rem ^= 1 << pos;
+            } else {
+                let bit = data_bits - 1 - (pos - self.degree);
+                data[bit / 8] ^= 0x80 >> (bit % 8);
+            }
+        }
+        let bytes = self.ecc_bytes();
+        ecc[..bytes].copy_from_slice(&self.pack(ecc_rem));
+        Some(errors.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic xorshift generator so the random cases are reproducible
+    struct XorShift(u32);
+
+    impl XorShift {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            (0..len).map(|_| self.next() as u8).collect()
+        }
+    }
+
+    /// Flip `count` distinct random bits of `data` and check that the scheme corrects them
+    fn assert_corrects(scheme: &EccScheme, count: usize, rng: &mut XorShift) {
+        let data = rng.bytes(scheme.step());
+        let ecc = scheme.calculate(&data);
+        assert_eq!(ecc.len(), scheme.ecc_bytes());
+
+        let mut corrupted = data.clone();
+        let mut flipped = Vec::new();
+        while flipped.len() < count {
+            let bit = rng.next() as usize % (data.len() * 8);
+            if !flipped.contains(&bit) {
+                flipped.push(bit);
+                corrupted[bit / 8] ^= 1 << (bit % 8);
+            }
+        }
+
+        let mut stored = ecc.clone();
+        assert_eq!(scheme.correct(&mut corrupted, &mut stored), Some(count), "{:?}", scheme);
+        assert_eq!(corrupted, data);
+        assert_eq!(stored, ecc);
+    }
+
+    #[test]
+    fn hamming_known_vector() {
+        for swapped in [true, false] {
+            let scheme = EccScheme::Hamming { step: 256, swapped };
+            assert_eq!(scheme.calculate(&[0; 256]), [0xff, 0xff, 0xff]);
+        }
+    }
+
+    #[test]
+    fn hamming_corrects_single_bit() {
+        let mut rng = XorShift(0x1234_5678);
+        for step in [256, 512] {
+            for swapped in [true, false] {
+                let scheme = EccScheme::Hamming { step, swapped };
+                for count in [0, 1, 1, 1] {
+                    assert_corrects(&scheme, count, &mut rng);
+                }
+
+                // A flipped bit within the ECC itself
+                let data = rng.bytes(step);
+                let ecc = scheme.calculate(&data);
+                let mut stored = ecc.clone();
+                stored[1] ^= 0x10;
+                let mut copy = data.clone();
+                assert_eq!(scheme.correct(&mut copy, &mut stored), Some(1));
+                assert_eq!((copy, stored), (data, ecc));
+            }
+        }
+    }
+
+    #[test]
+    fn bch_corrects_up_to_t_bits() {
+        let mut rng = XorShift(0x9e37_79b9);
+        for t in [4, 8] {
+            for masked in [true, false] {
+                let scheme = EccScheme::Bch(Bch::new(512, t, masked).unwrap());
+                assert_eq!(scheme.ecc_bytes(), (BCH_M * t).div_ceil(8));
+                for count in 0..=t {
+                    assert_corrects(&scheme, count, &mut rng);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bch_masked_erased_step() {
+        // Masking makes the ECC of an erased step erased as well
+        let bch = Bch::new(512, 8, true).unwrap();
+        assert!(bch.calculate(&[0xff; 512]).iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn bch_rejects_invalid_config() {
+        assert!(Bch::new(512, 0, false).is_err());
+        assert!(Bch::new(512, 10, false).is_err());
+        assert!(Bch::new(2048, 8, false).is_err());
+        assert!(Bch::new(0, 4, false).is_err());
+    }
+}
@@ -0,0 +1,429 @@
+/// Segment is executable
+pub const PF_X: u32 = 1;
+
+/// Segment is writable
+pub const PF_W: u32 = 2;
+
+/// Segment is readable
+pub const PF_R: u32 = 4;
+
+/// Size of the ELF32 file header
+const EHDR_SIZE: usize = 52;
+
+/// Size of a single ELF32 program header
+const PHDR_SIZE: usize = 32;
+
+/// ELF machine type for ARM
+const EM_ARM: u16 = 40;
+
+/// Loadable program segment
+const PT_LOAD: u32 = 1;
+
+/// Executable file
+const ET_EXEC: u16 = 2;
+
+/// Version 5 of the ARM EABI, the firmware is big endian code without the BE8 flag
+const EF_ARM_EABI_VER5: u32 = 0x05000000;
+
+/// Size of a single ELF32 section header
+const SHDR_SIZE: usize = 40;
+
+/// Size of a single ELF32 symbol
+const SYM_SIZE: usize = 16;
+
+/// Section holding data from the file
+const SHT_PROGBITS: u32 = 1;
+
+/// Symbol table section
+const SHT_SYMTAB: u32 = 2;
+
+/// String table section
+const SHT_STRTAB: u32 = 3;
+
+/// Section occupying no space in the file
+const SHT_NOBITS: u32 = 8;
+
+/// Section is writable
+const SHF_WRITE: u32 = 1;
+
+/// Section occupies memory during execution
+const SHF_ALLOC: u32 = 2;
+
+/// Section contains code
+const SHF_EXECINSTR: u32 = 4;
+
+/// Symbol section index for absolute values
+const SHN_ABS: u16 = 0xfff1;
+
+/// Kind of a symbol
+#[derive(Debug, Clone, Copy)]
+pub enum SymbolKind {
+    /// Data object, such as a table
+    Object,
+
+    /// Function or other code
+    Func,
+}
+
+/// Named section of an ELF file
+#[derive(Debug)]
+pub struct ElfSection {
+    /// Name of the section
+    name: String,
+
+    /// Address of the section
+    addr: usize,
+
+    /// Size of the section
+    size: usize,
+
+    /// Permissions of the section, combination of `PF_R`, `PF_W` and `PF_X`
+    flags: u32,
+}
+
+/// Global symbol of an ELF file
+#[derive(Debug)]
+pub struct ElfSymbol {
+    /// Name of the symbol
+    name: String,
+
+    /// Address of the symbol
+    value: usize,
+
+    /// Size of the object or function
+    size: usize,
+
+    /// Kind of the symbol
+    kind: SymbolKind,
+}
+
+/// Loadable segment of an ELF file
+#[derive(Debug)]
+pub struct ElfSegment {
+    /// Virtual address the segment is loaded to
+    vaddr: usize,
+
+    /// Contents of the segment stored in the file
+    data: Vec<u8>,
+
+    /// Size of the segment in memory, bytes past the end of `data` are zero-filled
+    mem_size: usize,
+
+    /// Permissions of the segment, combination of `PF_R`, `PF_W` and `PF_X`
+    flags: u32,
+}
+
+/// Big endian ELF32 image of the firmware memory map
+#[derive(Debug, Default)]
+pub struct Elf {
+    /// Address execution starts at
+    entry: usize,
+
+    /// Loadable segments, one program header is emitted for each of them
+    segments: Vec<ElfSegment>,
+
+    /// Named sections, a section header is emitted for each of them
+    sections: Vec<ElfSection>,
+
+    /// Symbols emitted into the symbol table
+    symbols: Vec<ElfSymbol>,
+}
+
+impl Elf {
+    /// Create new ELF file with the given entry point
+    pub fn new(entry: usize) -> Self {
+        Self {
+            entry,
+            ..Default::default()
+        }
+    }
+
+    /// Add a segment that is loaded with `data` at `vaddr`
+    pub fn add_segment(&mut self, vaddr: usize, data: &[u8], flags: u32) {
+        self.segments.push(ElfSegment {
+            vaddr,
+            data: data.to_vec(),
+            mem_size: data.len(),
+            flags,
+        });
+    }
+
+    /// Add a zero-filled segment of `size` bytes at `vaddr` that takes up no space in the file
+    pub fn add_zero_segment(&mut self, vaddr: usize, size: usize, flags: u32) {
+        self.segments.push(ElfSegment {
+            vaddr,
+            data: Vec::new(),
+            mem_size: size,
+            flags,
+        });
+    }
+
+    /// Add a named section covering `size` bytes at `addr`. Sections within a segment that is
+    /// stored in the file refer to its data, all others take up no space in the file
+    pub fn add_section(&mut self, name: &str, addr: usize, size: usize, flags: u32) {
+        self.sections.push(ElfSection {
+            name: name.to_string(),
+            addr,
+            size,
+            flags,
+        });
+    }
+
+    /// Add a global symbol
+    pub fn add_symbol(&mut self, name: &str, value: usize, size: usize, kind: SymbolKind) {
+        self.symbols.push(ElfSymbol {
+            name: name.to_string(),
+            value,
+            size,
+            kind,
+        });
+    }
+
+    /// Serialize the ELF file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let phoff = EHDR_SIZE;
+        let mut offset = phoff + self.segments.len() * PHDR_SIZE;
+
+        // Program headers, the file offset of every segment has to be congruent to its address
+        // modulo the alignment
+        let mut offsets = Vec::new();
+        for segment in &self.segments {
+            offset += (segment.vaddr.wrapping_sub(offset)) % 4;
+            offsets.push(offset);
+            offset += segment.data.len();
+        }
+        let data_end = offset;
+
+        // Sections are only emitted together with the symbol and string tables
+        let has_sections = !self.sections.is_empty() || !self.symbols.is_empty();
+        let mut strtab = vec![0u8];
+        let mut shstrtab = vec![0u8];
+        let mut symtab = vec![0u8; SYM_SIZE];
+        let mut shdrs = vec![0u8; SHDR_SIZE];
+
+        // Closure to append a name to a string table, returning its offset
+        let add_str = |table: &mut Vec<u8>, name: &str| {
+            let index = table.len();
+            table.extend(name.as_bytes());
+            table.push(0);
+            index
+        };
+
+        // Closure to append big endian values
+        let push16 = |out: &mut Vec<u8>, value: u16| out.extend(&value.to_be_bytes());
+        let push32 = |out: &mut Vec<u8>, value: usize| out.extend(&(value as u32).to_be_bytes());
+
+        // Closure to append a section header
+        let push_shdr = |out: &mut Vec<u8>, fields: [usize; 10]| {
+            for field in fields {
+                out.extend(&(field as u32).to_be_bytes());
+            }
+        };
+
+        let mut shoff = 0;
+        let mut shnum = 0;
+        let mut shstrndx = 0;
+        let mut tables = Vec::new();
+        if has_sections {
+            for section in &self.sections {
+                let mut flags = SHF_ALLOC;
+                if section.flags & PF_W != 0 {
+                    flags |= SHF_WRITE;
+                }
+                if section.flags & PF_X != 0 {
+                    flags |= SHF_EXECINSTR;
+                }
+
+                // Locate the file data backing the section
+                let backing = self.segments.iter().zip(&offsets).find(|(segment, _)| {
+                    section.addr >= segment.vaddr &&
+                        section.addr + section.size <= segment.vaddr + segment.data.len()
+                });
+                let (sh_type, sh_offset) = match backing {
+                    Some((segment, &offset)) => {
+                        (SHT_PROGBITS, offset + section.addr - segment.vaddr)
+                    }
+                    None => (SHT_NOBITS, data_end),
+                };
+                let name = add_str(&mut shstrtab, &section.name);
+                push_shdr(&mut shdrs, [name, sh_type as usize, flags as usize, section.addr,
+                          sh_offset, section.size, 0, 0, 1, 0]);
+            }
+
+            let symtab_index = self.sections.len() + 1;
+            for symbol in &self.symbols {
+                let name = add_str(&mut strtab, &symbol.name);
+                let shndx = self.sections.iter()
+                    .position(|section| {
+                        symbol.value >= section.addr && symbol.value < section.addr + section.size
+                    })
+                    .map_or(SHN_ABS, |index| index as u16 + 1);
+                let kind = match symbol.kind {
+                    SymbolKind::Object => 1,
+                    SymbolKind::Func => 2,
+                };
+                push32(&mut symtab, name);
+                push32(&mut symtab, symbol.value);
+                push32(&mut symtab, symbol.size);
+                // Global binding
+                symtab.push((1 << 4) | kind);
+                symtab.push(0);
+                push16(&mut symtab, shndx);
+            }
+
+            // Symbol table, its string table and the section name string table follow the data
+            let symtab_name = add_str(&mut shstrtab, ".symtab");
+            let strtab_name = add_str(&mut shstrtab, ".strtab");
+            let shstrtab_name = add_str(&mut shstrtab, ".shstrtab");
+            let mut table_offset = data_end + (4 - data_end % 4) % 4;
+            push_shdr(&mut shdrs, [symtab_name, SHT_SYMTAB as usize, 0, 0, table_offset,
+                      symtab.len(), symtab_index + 1, 1, 4, SYM_SIZE]);
+            tables.push((table_offset, &symtab));
+            table_offset += symtab.len();
+            push_shdr(&mut shdrs, [strtab_name, SHT_STRTAB as usize, 0, 0, table_offset,
+                      strtab.len(), 0, 0, 1, 0]);
+            tables.push((table_offset, &strtab));
+            table_offset += strtab.len();
+            push_shdr(&mut shdrs, [shstrtab_name, SHT_STRTAB as usize, 0, 0, table_offset,
+                      shstrtab.len(), 0, 0, 1, 0]);
+            tables.push((table_offset, &shstrtab));
+            table_offset += shstrtab.len();
+
+            shoff = table_offset + (4 - table_offset % 4) % 4;
+            shnum = symtab_index + 3;
+            shstrndx = symtab_index + 2;
+        }
+
+        // e_ident: magic, 32-bit, big endian, current version
+        out.extend(b"\x7fELF");
+        out.extend(&[1, 2, 1, 0]);
+        out.resize(16, 0);
+        push16(&mut out, ET_EXEC);
+        push16(&mut out, EM_ARM);
+        push32(&mut out, 1);
+        push32(&mut out, self.entry);
+        push32(&mut out, phoff);
+        push32(&mut out, shoff);
+        push32(&mut out, EF_ARM_EABI_VER5 as usize);
+        push16(&mut out, EHDR_SIZE as u16);
+        push16(&mut out, PHDR_SIZE as u16);
+        push16(&mut out, self.segments.len() as u16);
+        push16(&mut out, SHDR_SIZE as u16);
+        push16(&mut out, shnum as u16);
+        push16(&mut out, shstrndx as u16);
+
+        for (segment, &offset) in self.segments.iter().zip(&offsets) {
+            push32(&mut out, PT_LOAD as usize);
+            push32(&mut out, offset);
+            push32(&mut out, segment.vaddr);
+            push32(&mut out, segment.vaddr);
+            push32(&mut out, segment.data.len());
+            push32(&mut out, segment.mem_size);
+            push32(&mut out, segment.flags as usize);
+            push32(&mut out, 4);
+        }
+
+        for (segment, offset) in self.segments.iter().zip(offsets) {
+            out.resize(offset, 0);
+            out.extend(&segment.data);
+        }
+        for (offset, table) in tables {
+            out.resize(offset, 0);
+            out.extend(table);
+        }
+        if has_sections {
+            out.resize(shoff, 0);
+            out.extend(&shdrs);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be16(bytes: &[u8], offset: usize) -> usize {
+        u16::from_be_bytes([bytes[offset], bytes[offset + 1]]) as usize
+    }
+
+    fn be32(bytes: &[u8], offset: usize) -> usize {
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize
+    }
+
+    /// NUL terminated string at `offset` of the string table starting at `table`
+    fn string(bytes: &[u8], table: usize, offset: usize) -> &str {
+        let start = table + offset;
+        let len = bytes[start..].iter().position(|&c| c == 0).unwrap();
+        std::str::from_utf8(&bytes[start..start + len]).unwrap()
+    }
+
+    #[test]
+    fn write_two_segments() {
+        let text: Vec<u8> = (0..0x16).collect();
+        let mut elf = Elf::new(0x1004);
+        elf.add_segment(0x1002, &text, PF_R | PF_X);
+        elf.add_zero_segment(0x2000, 0x100, PF_R | PF_W);
+        elf.add_section(".text", 0x1004, 0x10, PF_R | PF_X);
+        elf.add_section(".bss", 0x2000, 0x100, PF_R | PF_W);
+        elf.add_symbol("boot_memset_list", 0x1008, 0xc, SymbolKind::Object);
+        elf.add_symbol("entry", 0x1004, 0, SymbolKind::Func);
+        elf.add_symbol("rom", 0x8000, 0, SymbolKind::Func);
+        let bytes = elf.to_bytes();
+
+        // File header
+        assert_eq!(bytes[..8], *b"\x7fELF\x01\x02\x01\x00");
+        assert_eq!((be16(&bytes, 16), be16(&bytes, 18), be32(&bytes, 20)), (2, 40, 1));
+        assert_eq!((be32(&bytes, 24), be32(&bytes, 28)), (0x1004, EHDR_SIZE));
+        assert_eq!(be32(&bytes, 36), 0x05000000);
+        assert_eq!((be16(&bytes, 40), be16(&bytes, 42), be16(&bytes, 44)), (52, 32, 2));
+        assert_eq!((be16(&bytes, 46), be16(&bytes, 48), be16(&bytes, 50)), (40, 6, 5));
+
+        // Program headers, the data is placed at an offset congruent to its address
+        let phdr = |i: usize| -> Vec<usize> {
+            (0..8).map(|field| be32(&bytes, EHDR_SIZE + i * PHDR_SIZE + field * 4)).collect()
+        };
+        let text_offset = 2 * PHDR_SIZE + EHDR_SIZE + 2;
+        assert_eq!(phdr(0), [1, text_offset, 0x1002, 0x1002, 0x16, 0x16, 5, 4]);
+        assert_eq!(bytes[text_offset..text_offset + 0x16], text);
+        assert_eq!(phdr(1), [1, text_offset + 0x16, 0x2000, 0x2000, 0, 0x100, 6, 4]);
+
+        // Section headers, the last one holds the section names
+        let shoff = be32(&bytes, 32);
+        assert_eq!(shoff % 4, 0);
+        let shdr = |i: usize| -> Vec<usize> {
+            (0..10).map(|field| be32(&bytes, shoff + i * SHDR_SIZE + field * 4)).collect()
+        };
+        assert_eq!(shdr(0), [0; 10]);
+        let shstrtab = shdr(5)[4];
+        let names: Vec<&str> = (1..6).map(|i| string(&bytes, shstrtab, shdr(i)[0])).collect();
+        assert_eq!(names, [".text", ".bss", ".symtab", ".strtab", ".shstrtab"]);
+        assert_eq!(shdr(1)[1..6], [1, 6, 0x1004, text_offset + 2, 0x10]);
+        assert_eq!(shdr(2)[1..4], [8, 3, 0x2000]);
+        assert_eq!(shdr(2)[5], 0x100);
+        assert_eq!((shdr(3)[1], shdr(3)[5], shdr(3)[6], shdr(3)[9]), (2, 4 * SYM_SIZE, 4, 16));
+        assert_eq!((shdr(4)[1], shdr(5)[1]), (3, 3));
+
+        // Symbols refer to the section they lie in, or are absolute
+        let (symtab, strtab) = (shdr(3)[4], shdr(4)[4]);
+        let symbol = |i: usize| {
+            let offset = symtab + i * SYM_SIZE;
+            (string(&bytes, strtab, be32(&bytes, offset)), be32(&bytes, offset + 4),
+             be32(&bytes, offset + 8), bytes[offset + 12], be16(&bytes, offset + 14))
+        };
+        assert_eq!(symbol(0), ("", 0, 0, 0, 0));
+        assert_eq!(symbol(1), ("boot_memset_list", 0x1008, 0xc, 0x11, 1));
+        assert_eq!(symbol(2), ("entry", 0x1004, 0, 0x12, 1));
+        assert_eq!(symbol(3), ("rom", 0x8000, 0, 0x12, SHN_ABS as usize));
+    }
+
+    #[test]
+    fn write_without_sections() {
+        let mut elf = Elf::new(0);
+        elf.add_segment(0, &[1, 2, 3, 4], PF_R);
+        let bytes = elf.to_bytes();
+        assert_eq!(bytes.len(), EHDR_SIZE + PHDR_SIZE + 4);
+        assert_eq!((be32(&bytes, 32), be16(&bytes, 48), be16(&bytes, 50)), (0, 0, 0));
+    }
+}
@@ -0,0 +1,105 @@
+use std::fmt;
+
+/// Errors that can occur while unpacking or repacking a firmware update
+#[derive(Debug)]
+pub enum Error {
+    /// Checksum of the record at `offset` does not match its contents
+    BadChecksum { offset: usize },
+
+    /// Record at `offset` has a type that is not known
+    UnknownRecordType { offset: usize, record_type: u8 },
+
+    /// Command at `offset` is not known or could not be parsed
+    UnknownCommand { offset: usize },
+
+    /// Data ended before the structure starting at `offset` was complete
+    Truncated { offset: usize },
+
+    /// Character `found` at `offset` is not a hex digit
+    BadHexDigit { offset: usize, found: u8 },
+
+    /// Magic value of a structure does not match the expected value
+    BadMagic { expected: usize, found: usize },
+
+    /// A required structure could not be located
+    NotFound(&'static str),
+
+    /// A structure that should be unique was found more than once
+    Duplicate(&'static str),
+
+    /// Range `start..end` overlaps with a section that is protected by the bootloader
+    ProtectedOverlap { start: usize, end: usize },
+
+    /// Range of `size` bytes at memory address `addr` is not backed by the firmware
+    OutOfRange { addr: usize, size: usize },
+
+    /// Data meant for `addr` needs `size` bytes, but only `max` bytes are available
+    TooLarge { addr: usize, size: usize, max: usize },
+
+    /// Text at `offset` does not follow the syntax of the language
+    BadSyntax { offset: usize },
+
+    /// Raster data uses a compression mode that is not known
+    UnknownCompression { mode: u8 },
+
+    /// Compressed data at `offset` is not valid for its compression mode
+    BadCompressedData { offset: usize },
+
+    /// Input uses a feature that is known but not supported
+    Unsupported(&'static str),
+
+    /// Parameters passed in are not valid
+    InvalidConfig(&'static str),
+
+    /// Underlying I/O error while reading or writing files
+    Io(std::io::Error),
+}
+
+/// Result type used throughout the unpacker
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::BadChecksum { offset } => write!(f, "Bad checksum at {:#X}", offset),
+            Error::UnknownRecordType { offset, record_type } => {
+                write!(f, "Unknown record type {:#X} at {:#X}", record_type, offset)
+            }
+            Error::UnknownCommand { offset } => write!(f, "Unknown command at {:#X}", offset),
+            Error::Truncated { offset } => write!(f, "Truncated data at {:#X}", offset),
+            Error::BadHexDigit { offset, found } => {
+                write!(f, "Invalid hex digit {:?} at {:#X}", *found as char, offset)
+            }
+            Error::BadMagic { expected, found } => {
+                write!(f, "Bad magic {:#X}, expected {:#X}", found, expected)
+            }
+            Error::NotFound(what) => write!(f, "Could not locate {}", what),
+            Error::Duplicate(what) => write!(f, "Found {} more than once", what),
+            Error::ProtectedOverlap { start, end } => {
+                write!(f, "Range {:#X}-{:#X} overlaps a protected section", start, end)
+            }
+            Error::OutOfRange { addr, size } => {
+                write!(f, "Range of {:#X} bytes at {:#X} is outside of the firmware", size, addr)
+            }
+            Error::TooLarge { addr, size, max } => {
+                write!(f, "Data for {:#X} does not fit, {:#X} > {:#X}", addr, size, max)
+            }
+            Error::BadSyntax { offset } => write!(f, "Invalid syntax at {:#X}", offset),
+            Error::UnknownCompression { mode } => write!(f, "Unknown compression mode {}", mode),
+            Error::BadCompressedData { offset } => {
+                write!(f, "Invalid compressed data at {:#X}", offset)
+            }
+            Error::Unsupported(what) => write!(f, "Unsupported {}", what),
+            Error::InvalidConfig(what) => write!(f, "Invalid configuration: {}", what),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+
+use crate::{
+    bytes_to_int_be,
+    bootloader::AppHeader,
+    error::{Error, Result},
+};
+
+/// Size of a single element of the segment table linked list
+pub const SEGMENT_ENTRY_SIZE: usize = 24;
+
+/// Longest segment name that is still considered valid while searching for the segment table
+const MAX_SEGMENT_NAME: usize = 64;
+
+/// Magic value at the start of the firmware header
+const FIRMWARE_MAGIC: usize = 0xBAD2BFED;
+
+/// Firmware Header
+#[derive(Debug, Default)]
+pub struct Header {
+    magic: usize,
+    header_size: usize,
+    page_size: usize,
+    bmp_size: usize,
+    load_addr: usize,
+    load_size: usize,
+    exec_addr: usize,
+}
+
+impl Header {
+    /// Magic value at the start of the header, always `0xBAD2BFED`
+    pub fn magic(&self) -> usize {
+        self.magic
+    }
+
+    /// Size of the header in bytes
+    pub fn header_size(&self) -> usize {
+        self.header_size
+    }
+
+    /// Size of a single flash page in bytes
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Size of the bootsplash bmp that follows the header page
+    pub fn bmp_size(&self) -> usize {
+        self.bmp_size
+    }
+
+    /// Address the firmware is loaded to
+    pub fn load_addr(&self) -> usize {
+        self.load_addr
+    }
+
+    /// Size of the firmware in bytes
+    pub fn load_size(&self) -> usize {
+        self.load_size
+    }
+
+    /// Address execution starts at once the firmware is loaded
+    pub fn exec_addr(&self) -> usize {
+        self.exec_addr
+    }
+}
+
+/// Structure that describes the segments
+#[derive(Debug)]
+pub struct Segment {
+    /// Pointer to next element of linked list
+    next: usize,
+
+    /// Segment Name
+    name: String,
+
+    /// Starting address of section
+    start: usize,
+
+    /// Size of section
+    size: usize,
+
+    /// Options, the low three bits are the rwx bits of the segment
+    flags: usize,
+
+    /// Used for intermediate loads using memcpys
+    dst: usize,
+}
+
+impl Segment {
+    /// Address of the next element of the linked list, 0 for the last segment
+    pub fn next(&self) -> usize {
+        self.next
+    }
+
+    /// Segment name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Starting address of the segment
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Size of the segment
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Raw flags of the segment
+    pub fn flags(&self) -> usize {
+        self.flags
+    }
+
+    /// Permissions of the segment as a combination of `PF_R`, `PF_W` and `PF_X`. The low three
+    /// bits of the flags follow the ELF convention, segments without any of them are taken as RWX
+    pub fn permissions(&self) -> u32 {
+        match (self.flags & 7) as u32 {
+            0 => 7,
+            perms => perms,
+        }
+    }
+
+    /// Destination address used for intermediate loads
+    pub fn dst(&self) -> usize {
+        self.dst
+    }
+}
+
+/// How the segment table was located within the firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableSource {
+    /// Referenced by the section linked list of the app header
+    AppHeader,
+
+    /// Found by scanning the firmware for the longest valid linked list of segments
+    Scan,
+}
+
+/// Firmware image after initial uncompression routines are completed
+#[derive(Default)]
+pub struct Firmware {
+    header: Header,
+    segments: Vec<Segment>,
+    data: Vec<u8>,
+}
+
+impl Firmware {
+    /// Create new empty firmware
+    pub fn new() -> Self {
+        Self {
+            header: Header::default(),
+            segments: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    /// Firmware header parsed out of the first flash page
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Segments listed in the segment table
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Raw firmware data, starting at the load address
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Mutable access to the raw firmware data, used to patch the firmware before repacking
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Read a big endian 32-bit value at `offset` into the firmware data
+    pub fn word(&self, offset: usize) -> Result<usize> {
+        let bytes = self.data.get(offset..offset + 4).ok_or(Error::Truncated { offset })?;
+        Ok(bytes_to_int_be(bytes, 4))
+    }
+
+    /// Retrieve `size` bytes of firmware data stored at the memory address `addr`
+    pub fn read(&self, addr: usize, size: usize) -> Result<&[u8]> {
+        addr.checked_sub(self.header.load_addr)
+            .and_then(|offset| self.data.get(offset..offset.checked_add(size)?))
+            .ok_or(Error::OutOfRange { addr, size })
+    }
+
+    /// Parse out the firmware header from the srecords
+    pub fn parse_header(&mut self, srecords: &[u8]) -> Result<()> {
+        if srecords.len() < 0x40 {
+            return Err(Error::Truncated { offset: srecords.len() });
+        }
+        self.header.magic = bytes_to_int_be(&srecords[0x0..0x4], 4);
+        self.header.header_size = bytes_to_int_be(&srecords[0x8..0xc], 4);
+        self.header.page_size = bytes_to_int_be(&srecords[0x10..0x14], 4);
+        self.header.bmp_size = bytes_to_int_be(&srecords[0x1c..0x20], 4);
+        self.header.load_addr = bytes_to_int_be(&srecords[0x30..0x34], 4);
+        self.header.load_size = bytes_to_int_be(&srecords[0x34..0x38], 4);
+        self.header.exec_addr = bytes_to_int_be(&srecords[0x3c..0x40], 4);
+        if self.header.magic != FIRMWARE_MAGIC {
+            return Err(Error::BadMagic { expected: FIRMWARE_MAGIC, found: self.header.magic });
+        }
+        if self.header.page_size == 0 {
+            return Err(Error::InvalidConfig("firmware header has a page size of zero"));
+        }
+        Ok(())
+    }
+
+    /// Offset of the firmware within the raw flash image, following the header page and the
+    /// bootsplash bmp
+    pub fn data_offset(&self) -> usize {
+        // Calculate the number of pages occupied by the bootsplash bmp, round up to nearest page
+        let num_bmp_pages: usize = (self.header.bmp_size / self.header.page_size) +
+            if !self.header.bmp_size.is_multiple_of(self.header.page_size) { 1 } else { 0 };
+
+        (num_bmp_pages + 1) * self.header.page_size
+    }
+
+    /// Parse out the firmware from the raw flash image
+    pub fn parse_data(&mut self, data: &[u8]) -> Result<()> {
+        // Calculate the number of pages occupied by the firmware, round up to nearest page
+        let num_firmware_pages: usize = (self.header.load_size / self.header.page_size) +
+            if !self.header.load_size.is_multiple_of(self.header.page_size) { 1 } else { 0 };
+
+        // Get start address and end address of firmware to then extract it from data
+        let start_addr = self.data_offset();
+        let end_addr = start_addr + (num_firmware_pages * self.header.page_size);
+        self.data.extend(data.get(start_addr..end_addr)
+                         .ok_or(Error::Truncated { offset: data.len() })?);
+        Ok(())
+    }
+
+    /// Change the size of the firmware to `load_size`. The data is resized to whole pages, pages
+    /// that are added are erased (0xFF)
+    pub fn set_load_size(&mut self, load_size: usize) {
+        self.header.load_size = load_size;
+        self.data.resize(load_size.next_multiple_of(self.header.page_size), 0xFF);
+    }
+
+    /// Write the header fields that can change, the load size, back into the header page at the
+    /// start of the raw flash image `flash`
+    pub fn write_header(&self, flash: &mut [u8]) -> Result<()> {
+        let len = flash.len();
+        flash.get_mut(0x34..0x38)
+            .ok_or(Error::Truncated { offset: len })?
+            .copy_from_slice(&(self.header.load_size as u32).to_be_bytes());
+        Ok(())
+    }
+
+    /// Translate a pointer into the firmware to an offset into the firmware data
+    fn pointer(&self, addr: usize) -> Option<usize> {
+        addr.checked_sub(self.header.load_addr).filter(|&offset| offset < self.data.len())
+    }
+
+    /// Return the segment name at `offset` if it is a short, printable, NUL-terminated string
+    fn segment_name(&self, offset: usize) -> Option<&[u8]> {
+        let bytes = self.data.get(offset..)?;
+        let len = bytes.iter().take(MAX_SEGMENT_NAME + 1).position(|&c| c == 0)?;
+        let name = &bytes[..len];
+        if name.is_empty() || !name.iter().all(|c| c.is_ascii_graphic()) {
+            return None;
+        }
+        Some(name)
+    }
+
+    /// Whether the element of the segment table linked list at `offset` looks like a valid segment,
+    /// returning the offset of the next element. `Some(None)` marks the last element
+    fn segment_entry(&self, offset: usize) -> Option<Option<usize>> {
+        if !offset.is_multiple_of(4) || offset + SEGMENT_ENTRY_SIZE > self.data.len() {
+            return None;
+        }
+        let (next, name) = (self.word(offset).ok()?, self.word(offset + 4).ok()?);
+        self.pointer(name).and_then(|name| self.segment_name(name))?;
+        match next {
+            0 => Some(None),
+            next => self.pointer(next).map(Some),
+        }
+    }
+
+    /// Number of elements in the segment table linked list starting at `offset`. Returns 0 if any
+    /// of the elements does not look like a valid segment or the list does not terminate. The
+    /// lengths of all lists walked are kept in `known`, so every element is only visited once
+    /// while scanning
+    fn segment_chain_len(&self, offset: usize, known: &mut HashMap<usize, usize>) -> usize {
+        // Elements walked that are not known yet, marked as in progress to detect cycles
+        const IN_PROGRESS: usize = usize::MAX;
+        let mut path = vec![];
+        let mut cursor = Some(offset);
+
+        // Length of the list following the walked elements, `None` if it is not valid
+        let tail = loop {
+            let Some(offset) = cursor else {
+                break Some(0);
+            };
+            match known.get(&offset) {
+                Some(&IN_PROGRESS) | Some(0) => break None,
+                Some(&len) => break Some(len),
+                None => {}
+            }
+            match self.segment_entry(offset) {
+                Some(next) => {
+                    known.insert(offset, IN_PROGRESS);
+                    path.push(offset);
+                    cursor = next;
+                }
+                None => {
+                    known.insert(offset, 0);
+                    break None;
+                }
+            }
+        };
+
+        for (i, &offset) in path.iter().enumerate() {
+            known.insert(offset, tail.map_or(0, |tail| tail + path.len() - i));
+        }
+        known.get(&offset).copied().unwrap_or(0)
+    }
+
+    /// Locate the segment table, returning its offset into the firmware data and how it was
+    /// found. The table is retrieved through the app_hdr struct, if that does not yield a valid
+    /// table the firmware is scanned for the longest valid linked list of segments instead
+    pub fn find_segment_table(&self) -> Result<(usize, TableSource)> {
+        let from_app_header = AppHeader::parse(self)
+            .ok()
+            .and_then(|app_header| self.pointer(app_header.section_linked_list()))
+            .filter(|&offset| self.segment_chain_len(offset, &mut HashMap::new()) > 0);
+        if let Some(offset) = from_app_header {
+            return Ok((offset, TableSource::AppHeader));
+        }
+
+        let mut known = HashMap::new();
+        let mut best: Option<(usize, usize)> = None;
+        for offset in (0..self.data.len().saturating_sub(SEGMENT_ENTRY_SIZE)).step_by(4) {
+            // Cheap checks first, the pointers have to point into the firmware
+            let next = self.word(offset).unwrap_or(usize::MAX);
+            let name = self.word(offset + 4).unwrap_or(usize::MAX);
+            if self.pointer(name).is_none() || (next != 0 && self.pointer(next).is_none()) {
+                continue;
+            }
+
+            let len = self.segment_chain_len(offset, &mut known);
+            if len > 1 && best.is_none_or(|(_, best_len)| len > best_len) {
+                best = Some((offset, len));
+            }
+        }
+        best.map(|(offset, _)| (offset, TableSource::Scan)).ok_or(Error::NotFound("segment table"))
+    }
+
+    /// Parse out segment table from firmware, returning how the table was located
+    pub fn parse_segments(&mut self) -> Result<TableSource> {
+        // Offset of the current element of the segment table, `None` once the list ends
+        let (table, source) = self.find_segment_table()?;
+        let mut cursor = Some(table);
+
+        while let Some(offset) = cursor {
+            let mut name = Vec::new();
+
+            let name_addr = self.word(offset + 4)?.checked_sub(self.header.load_addr)
+                .ok_or(Error::Truncated { offset: offset + 4 })?;
+            let start = self.word(offset + 8)?;
+            let size = self.word(offset + 12)?;
+            let flags = self.word(offset + 16)?;
+            let dst = self.word(offset + 20)?;
+
+            // Parse out name
+            let mut i = 0;
+            while *self.data.get(name_addr + i).ok_or(Error::Truncated { offset: name_addr })?
+                    != 0x0 {
+                name.push(self.data[name_addr+i]);
+                i+=1;
+            }
+            let str_name = String::from_utf8_lossy(&name);
+
+            let next = self.word(offset)?;
+            cursor = match next {
+                0 => None,
+                addr => Some(self.pointer(addr)
+                    .ok_or(Error::OutOfRange { addr, size: SEGMENT_ENTRY_SIZE })?),
+            };
+            self.segments.push(Segment {
+                    next,
+                    name: str_name.to_string(),
+                    start,
+                    size,
+                    flags,
+                    dst,
+                });
+        }
+        Ok(source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOAD_ADDR: usize = 0x26710000;
+
+    /// Offset of the segment table within the test firmware
+    const TABLE: usize = 0x2000;
+
+    /// Firmware with a segment table of three entries and an app header at 0x1000 referencing
+    /// the table at `table`, 0 for none
+    fn firmware_with_table(table: usize) -> Firmware {
+        let mut firmware = Firmware {
+            header: Header { load_addr: LOAD_ADDR, load_size: 0x4000, ..Default::default() },
+            segments: Vec::new(),
+            data: vec![0; 0x4000],
+        };
+        let data = &mut firmware.data;
+        put(data, 0x1000, 0x3ca55a3c);
+        put(data, 0x1000 + 64, table);
+
+        let names = 0x3000;
+        for (i, name) in ["text", "data", "bss"].into_iter().enumerate() {
+            let entry = TABLE + i * SEGMENT_ENTRY_SIZE;
+            let next = if i == 2 { 0 } else { LOAD_ADDR + entry + SEGMENT_ENTRY_SIZE };
+            put(data, entry, next);
+            put(data, entry + 4, LOAD_ADDR + names + i * 0x10);
+            put(data, entry + 8, 0x1000 * (i + 1));
+            put(data, entry + 12, 0x100);
+            put(data, entry + 16, i + 5);
+            put(data, entry + 20, 0x20000000 + i);
+            data[names + i * 0x10..][..name.len()].copy_from_slice(name.as_bytes());
+        }
+        firmware
+    }
+
+    /// Write the big endian 32-bit `value` at `offset`
+    fn put(data: &mut [u8], offset: usize, value: usize) {
+        data[offset..offset + 4].copy_from_slice(&(value as u32).to_be_bytes());
+    }
+
+    /// Write a segment table entry at `offset` pointing to `next` and the name of the first segment
+    fn put_entry(firmware: &mut Firmware, offset: usize, next: usize) {
+        put(&mut firmware.data, offset, next);
+        put(&mut firmware.data, offset + 4, LOAD_ADDR + 0x3000);
+    }
+
+    fn names(firmware: &Firmware) -> Vec<&str> {
+        firmware.segments().iter().map(|segment| segment.name()).collect()
+    }
+
+    #[test]
+    fn table_from_app_header() {
+        let mut firmware = firmware_with_table(LOAD_ADDR + TABLE);
+        assert_eq!(firmware.parse_segments().unwrap(), TableSource::AppHeader);
+        assert_eq!(names(&firmware), ["text", "data", "bss"]);
+        let bss = &firmware.segments()[2];
+        assert_eq!((bss.next(), bss.start(), bss.size(), bss.flags(), bss.dst()),
+                   (0, 0x3000, 0x100, 7, 0x20000002));
+    }
+
+    #[test]
+    fn segment_permissions() {
+        let mut firmware = firmware_with_table(LOAD_ADDR + TABLE);
+        put(&mut firmware.data, TABLE + 2 * SEGMENT_ENTRY_SIZE + 16, 0x10);
+        firmware.parse_segments().unwrap();
+        let permissions: Vec<u32> = firmware.segments().iter()
+            .map(|segment| segment.permissions())
+            .collect();
+        assert_eq!(permissions, [5, 6, 7]);
+    }
+
+    #[test]
+    fn table_from_scan() {
+        // A shorter list and a cycle elsewhere in the firmware are not taken
+        let mut firmware = firmware_with_table(0);
+        put_entry(&mut firmware, 0x800, LOAD_ADDR + 0x818);
+        put_entry(&mut firmware, 0x818, 0);
+        put_entry(&mut firmware, 0x900, LOAD_ADDR + 0x918);
+        put_entry(&mut firmware, 0x918, LOAD_ADDR + 0x930);
+        put_entry(&mut firmware, 0x930, LOAD_ADDR + 0x948);
+        put_entry(&mut firmware, 0x948, LOAD_ADDR + 0x900);
+        assert_eq!(firmware.find_segment_table().unwrap(), (TABLE, TableSource::Scan));
+        assert_eq!(firmware.parse_segments().unwrap(), TableSource::Scan);
+        assert_eq!(names(&firmware), ["text", "data", "bss"]);
+
+        // An app header pointing at garbage falls back to the scan as well
+        let firmware = firmware_with_table(LOAD_ADDR + 0x100);
+        assert_eq!(firmware.find_segment_table().unwrap(), (TABLE, TableSource::Scan));
+    }
+
+    #[test]
+    fn scan_long_chain() {
+        // A cycle through most of the firmware, every entry is visited once
+        let mut firmware = firmware_with_table(0);
+        firmware.data.resize(0x40000, 0);
+        let entries: Vec<usize> = (0x4000..0x40000).step_by(SEGMENT_ENTRY_SIZE)
+            .take_while(|offset| offset + SEGMENT_ENTRY_SIZE <= 0x40000)
+            .collect();
+        for pair in entries.windows(2) {
+            put_entry(&mut firmware, pair[0], LOAD_ADDR + pair[1]);
+        }
+        put_entry(&mut firmware, *entries.last().unwrap(), LOAD_ADDR + entries[0]);
+        assert_eq!(firmware.find_segment_table().unwrap(), (TABLE, TableSource::Scan));
+
+        // Without the cycle it is the longest list
+        put_entry(&mut firmware, *entries.last().unwrap(), 0);
+        assert_eq!(firmware.find_segment_table().unwrap(), (entries[0], TableSource::Scan));
+    }
+
+    #[test]
+    fn no_table() {
+        let mut firmware = firmware_with_table(0);
+        firmware.data[TABLE..TABLE + 3 * SEGMENT_ENTRY_SIZE].fill(0);
+        assert!(matches!(firmware.find_segment_table(), Err(Error::NotFound("segment table"))));
+    }
+}
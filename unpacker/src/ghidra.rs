@@ -0,0 +1,158 @@
+use crate::{
+    elf::{SymbolKind, PF_R, PF_W, PF_X},
+    manifest::{Manifest, Origin},
+};
+
+/// Body of the loader script, run once the region and symbol tables are defined
+const SCRIPT_BODY: &str = r#"
+import os
+
+from ghidra.program.model.symbol import SourceType
+
+base = getSourceFile().getParentFile().getAbsolutePath()
+memory = currentProgram.getMemory()
+
+for (name, vaddr, path, offset, size, perms) in REGIONS:
+    addr = toAddr(vaddr)
+    end = addr.add(size - 1)
+    if memory.getBlock(addr) is not None or memory.getBlock(end) is not None:
+        print("Skipping %s, memory at 0x%x is already mapped" % (name, vaddr))
+        continue
+
+    with open(os.path.join(base, path), "rb") as f:
+        f.seek(offset)
+        data = f.read(size)
+
+    block = memory.createInitializedBlock(name, addr, size, 0, monitor, False)
+    memory.setBytes(addr, data)
+    block.setRead(perms & 4 != 0)
+    block.setWrite(perms & 2 != 0)
+    block.setExecute(perms & 1 != 0)
+
+for (name, vaddr, code) in LABELS:
+    addr = toAddr(vaddr)
+    createLabel(addr, name, True, SourceType.IMPORTED)
+    if code:
+        addEntryPoint(addr)
+        disassemble(addr)
+
+addEntryPoint(toAddr(ENTRY))
+disassemble(toAddr(ENTRY))
+"#;
+
+/// Generate a Ghidra python script that maps every region of the manifest with its permissions,
+/// sets the entry point and labels all symbols and named segments. Function symbols, such as the
+/// boot routines, are disassembled as well. The script expects the files listed in the manifest
+/// next to it
+pub fn loader_script(manifest: &Manifest) -> String {
+    let mut out = String::new();
+    out += "# Ghidra loader for an unpacked firmware, generated by the unpacker.\n";
+    out += "#\n";
+    out += "# Import the firmware as a raw binary and run this script on it:\n";
+    out += "#   analyzeHeadless <project_dir> <project> -import firmware \\\n";
+    out += "#       -loader BinaryLoader -processor ARM:BE:32:v7 \\\n";
+    out += &format!("#       -loader-baseAddr {:#x} \\\n", manifest.load_addr());
+    out += "#       -scriptPath . -preScript ghidra_loader.py\n";
+    out += "#\n";
+    out += "# Regions that are already mapped, such as the imported firmware, are skipped\n";
+    out += "\n";
+    out += &format!("ENTRY = {:#x}\n\n", manifest.entry());
+
+    // Memory regions never overlap each other, but the firmware image may overlap them. Any such
+    // overlap is reported by the unpacker and the script keeps the region that is mapped first
+    out += "# name, address, file, offset into the file, size, permissions\n";
+    out += "REGIONS = [\n";
+    for region in manifest.regions() {
+        let file = match &region.file {
+            Some(file) if matches!(region.origin, Origin::Memory | Origin::Firmware) => file,
+            _ => continue,
+        };
+        let name = match region.origin {
+            Origin::Firmware => "firmware".to_string(),
+            _ => format!("mem_{:x}", region.vaddr),
+        };
+        out += &format!("    ({}, {:#x}, {}, {:#x}, {:#x}, {}),  # {}\n", py_string(&name),
+                        region.vaddr, py_string(file), region.file_offset, region.size,
+                        region.perms, permissions(region.perms));
+    }
+    out += "]\n\n";
+
+    out += "# name, address, whether the address holds code\n";
+    out += "LABELS = [\n";
+    for symbol in manifest.symbols() {
+        let code = matches!(symbol.kind, SymbolKind::Func);
+        out += &format!("    ({}, {:#x}, {}),\n", py_string(&symbol.name), symbol.addr,
+                        if code { "True" } else { "False" });
+    }
+    for region in manifest.regions() {
+        if let (Origin::SegmentTable, Some(name)) = (region.origin, &region.name) {
+            out += &format!("    ({}, {:#x}, False),\n", py_string(name), region.vaddr);
+        }
+    }
+    out += "]\n";
+    out += SCRIPT_BODY;
+    out
+}
+
+/// Permissions in the form `rwx`
+fn permissions(perms: u32) -> String {
+    [(PF_R, 'r'), (PF_W, 'w'), (PF_X, 'x')]
+        .iter()
+        .map(|&(flag, c)| if perms & flag != 0 { c } else { '-' })
+        .collect()
+}
+
+/// Encode a string as a python string literal
+fn py_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for byte in value.bytes() {
+        match byte {
+            b'"' => out += "\\\"",
+            b'\\' => out += "\\\\",
+            0x20..=0x7e => out.push(byte as char),
+            _ => out += &format!("\\x{:02x}", byte),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Region;
+
+    #[test]
+    fn script_for_small_image() {
+        let mut manifest = Manifest::new(0x26710000, 0x26710040);
+        manifest.add(Region::new("firmware", 0x26710000, &[0; 0x100], PF_R | PF_X,
+                                 Origin::Firmware));
+        manifest.add(Region::new("memory/20000000.bin", 0x20000000, &[0; 0x20], PF_R | PF_W,
+                                 Origin::Memory));
+        manifest.add(Region::new("segments/20000000.dump", 0x20000000, &[0; 0x20], PF_R | PF_W,
+                                 Origin::Memcpy));
+        let mut text = Region::new("memory/20000000.bin", 0x20000010, &[0; 0x10], PF_R | PF_X,
+                                   Origin::SegmentTable);
+        text.name = Some("te\"xt".to_string());
+        text.file_offset = 0x10;
+        manifest.add(text);
+        manifest.add_symbol("boot_memcpy_list", 0x267784a4, 0x108, SymbolKind::Object);
+        manifest.add_symbol("boot_memcpy", 0x26710080, 0, SymbolKind::Func);
+
+        let script = loader_script(&manifest);
+        assert!(script.contains("#       -loader-baseAddr 0x26710000 \\\n"));
+        assert!(script.contains("ENTRY = 0x26710040\n"));
+        let regions = script.split("REGIONS = [\n").nth(1).unwrap().split("]\n").next().unwrap();
+        assert_eq!(regions, concat!(
+            "    (\"firmware\", 0x26710000, \"firmware\", 0x0, 0x100, 5),  # r-x\n",
+            "    (\"mem_20000000\", 0x20000000, \"memory/20000000.bin\", 0x0, 0x20, 6),  # rw-\n",
+        ));
+        let labels = script.split("LABELS = [\n").nth(1).unwrap().split("]\n").next().unwrap();
+        assert_eq!(labels, concat!(
+            "    (\"boot_memcpy_list\", 0x267784a4, False),\n",
+            "    (\"boot_memcpy\", 0x26710080, True),\n",
+            "    (\"te\\\"xt\", 0x20000010, False),\n",
+        ));
+        assert!(script.contains("createLabel(addr, name, True, SourceType.IMPORTED)"));
+    }
+}
@@ -1,7 +1,20 @@
+pub mod bootloader;
+pub mod ccitt;
+pub mod ecc;
+pub mod elf;
+pub mod error;
+pub mod firmware;
+pub mod ghidra;
 pub mod lzss;
+pub mod manifest;
+pub mod memory;
+pub mod nand;
 pub mod pjl;
+pub mod sha256;
 pub mod srecord;
 
+pub use error::{Error, Result};
+
 /// Converts a sequence of bytes to a number by putting together the ascii value of each individual
 /// byte to form a number with a given base
 pub fn hex_to_ascii(bytes: &[u8], base: usize) -> (usize, usize) {
@@ -16,6 +29,9 @@ pub fn hex_to_ascii(bytes: &[u8], base: usize) -> (usize, usize) {
         } else if *byte >= 0x41 && *byte <= 0x46 {
             // Hex A-F range
             results.push(*byte as usize - 0x37);
+        } else if *byte >= 0x61 && *byte <= 0x66 {
+            // Hex a-f range
+            results.push(*byte as usize - 0x57);
         } else {
             break;
         }
@@ -31,13 +47,8 @@ pub fn hex_to_ascii(bytes: &[u8], base: usize) -> (usize, usize) {
 /// Convert `size` `bytes` from a sequence of bytes to a big endian number
 pub fn bytes_to_int_be(bytes: &[u8], size: usize) -> usize {
     let mut result = 0usize;
-    let mut count = 0;
-    for &byte in bytes {
-        if count == size {
-            break;
-        }
+    for &byte in bytes.iter().take(size) {
         result = (result << 8) | (byte as usize);
-        count += 1;
     }
     result
 }
@@ -45,13 +56,8 @@ pub fn bytes_to_int_be(bytes: &[u8], size: usize) -> usize {
 /// Convert `size` `bytes` from a sequence of bytes to a little endian number
 pub fn bytes_to_int_le(bytes: &[u8], size: usize) -> usize {
     let mut result = 0usize;
-    let mut count = 0;
-    for &byte in bytes {
-        if count == size {
-            break;
-        }
+    for (count, &byte) in bytes.iter().take(size).enumerate() {
         result |= (byte as usize) << (count * 8);
-        count += 1;
     }
     result
 }
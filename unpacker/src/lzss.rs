@@ -30,7 +30,7 @@ pub fn lzss_uncompress(src: &[u8]) -> Vec<u8> {
             control >>= 1;
 
             let offset_upper: u32 = ((src[src_idx+1] >> 4) & 0xfu8).into();
-            let offset_lower: u32 = (src[src_idx] & 0xffu8).into();
+            let offset_lower: u32 = src[src_idx].into();
             let offset: i32 = ((offset_upper << 8) | offset_lower) as i32;
             let length: u32 = ((src[src_idx + 1] & 0xfu8) + 3).into();
 
@@ -56,3 +56,155 @@ pub fn lzss_uncompress(src: &[u8]) -> Vec<u8> {
 }
 
 
+
+/// Size of the sliding window used by the bootloader's decompression routine
+const WINDOW_SIZE: usize = 0x1000;
+
+/// Initial write position inside of the sliding window (`4096 - 18`)
+const WINDOW_START: usize = 0xFEE;
+
+/// Shortest match worth encoding as a back-reference, shorter matches are emitted as literals
+const MIN_MATCH: usize = 3;
+
+/// Longest match that fits into the 4-bit length nibble (`0xF + 3`)
+const MAX_MATCH: usize = 18;
+
+/// Number of previous occurrences of a prefix that are checked before settling on a match
+const MAX_CHAIN: usize = 256;
+
+/// Compress given data into the format understood by `lzss_uncompress` and the bootloader. Each
+/// group of up to 8 tokens is preceded by a control byte whose bits (lsb first) mark literals (1)
+/// or 2-byte back-references (0) holding a 12-bit window position and a 4-bit `length - 3`
+pub fn lzss_compress(src: &[u8]) -> Vec<u8> {
+    let mut dst: Vec<u8> = Vec::new();
+
+    // Hash chains over 3-byte prefixes, `head` holds the most recent position of each hash while
+    // `prev` links every position to the previous one with the same hash
+    let hash = |idx: usize| {
+        ((src[idx] as usize) << 8 ^ (src[idx + 1] as usize) << 4 ^ src[idx + 2] as usize) & 0xfff
+    };
+    let mut head: Vec<usize> = vec![usize::MAX; 0x1000];
+    let mut prev: Vec<usize> = vec![usize::MAX; src.len()];
+
+    let mut control_idx = 0;
+    let mut control_bit = 8;
+    let mut src_idx: usize = 0;
+
+    while src_idx < src.len() {
+        // Start a new group of tokens once the current control byte is used up
+        if control_bit == 8 {
+            control_idx = dst.len();
+            dst.push(0);
+            control_bit = 0;
+        }
+
+        // Find the longest match within the window. Distances are kept below the window size so
+        // the referenced bytes are never overwritten in the device's ring buffer
+        let max_len = std::cmp::min(MAX_MATCH, src.len() - src_idx);
+        let mut best_len = 0;
+        let mut best_pos = 0;
+        if max_len >= MIN_MATCH {
+            let mut candidate = head[hash(src_idx)];
+            let mut chain = 0;
+            while candidate != usize::MAX && src_idx - candidate < WINDOW_SIZE && chain < MAX_CHAIN {
+                let len = (0..max_len)
+                    .take_while(|&i| src[candidate + i] == src[src_idx + i])
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best_pos = candidate;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+
+        let advance = if best_len >= MIN_MATCH {
+            let window_pos = (best_pos + WINDOW_START) & (WINDOW_SIZE - 1);
+            dst.push((window_pos & 0xff) as u8);
+            dst.push((((window_pos >> 4) & 0xf0) | (best_len - MIN_MATCH)) as u8);
+            best_len
+        } else {
+            dst[control_idx] |= 1 << control_bit;
+            dst.push(src[src_idx]);
+            1
+        };
+        control_bit += 1;
+
+        // Insert every consumed position into the hash chains
+        let end = src_idx + advance;
+        while src_idx < end {
+            if src_idx + MIN_MATCH <= src.len() {
+                let h = hash(src_idx);
+                prev[src_idx] = head[h];
+                head[h] = src_idx;
+            }
+            src_idx += 1;
+        }
+    }
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic xorshift generator so the random cases are reproducible
+    fn pseudo_random(len: usize, mut state: u32) -> Vec<u8> {
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect()
+    }
+
+    fn assert_round_trip(data: &[u8]) {
+        assert_eq!(lzss_uncompress(&lzss_compress(data)), data, "len {}", data.len());
+    }
+
+    #[test]
+    fn round_trip_short() {
+        assert_round_trip(&[]);
+        assert_round_trip(&[0x41]);
+        assert_round_trip(&[0x41, 0x42]);
+        assert_round_trip(&[0x41, 0x42, 0x41]);
+    }
+
+    #[test]
+    fn round_trip_window_boundaries() {
+        for len in [WINDOW_SIZE - 1, WINDOW_SIZE, WINDOW_SIZE + 1] {
+            let text: Vec<u8> = b"the quick brown fox jumps over the lazy dog "
+                .iter().cycle().take(len).copied().collect();
+            assert_round_trip(&text);
+            assert_round_trip(&pseudo_random(len, len as u32));
+        }
+    }
+
+    #[test]
+    fn round_trip_large() {
+        let mut data = pseudo_random(100_000, 0x1234_5678);
+        // Mix in compressible stretches so matches cross the window wrap-around
+        for chunk in data.chunks_mut(7000).step_by(2) {
+            let len = chunk.len() / 2;
+            chunk[..len].fill(0);
+        }
+        assert_round_trip(&data);
+    }
+
+    #[test]
+    fn round_trip_runs() {
+        assert_round_trip(&[0u8; 100_000]);
+        assert_round_trip(&[0xFFu8; 19]);
+        let runs: Vec<u8> = (0..5000u32).flat_map(|i| vec![i as u8; (i % 40) as usize]).collect();
+        assert_round_trip(&runs);
+    }
+
+    #[test]
+    fn compresses_repetitive_data() {
+        assert!(lzss_compress(&[0u8; 4096]).len() < 4096 / 4);
+    }
+}
@@ -1,440 +1,720 @@
+use std::{fs::File, io::{BufReader, BufWriter}};
+
 use unpacker::{
-    bytes_to_int_be,
-    lzss::lzss_uncompress,
-    pjl::{parse_pjl, extract_bitmap},
-    srecord::{parse_srecords, print_binary_record}
+    bootloader::{find_app_header, BootLoader, BootOp},
+    elf::{Elf, SymbolKind, PF_R, PF_W, PF_X},
+    error::{Error, Result},
+    firmware::{Firmware, TableSource, SEGMENT_ENTRY_SIZE},
+    ghidra::loader_script,
+    lzss::{lzss_compress, lzss_uncompress},
+    manifest::{Manifest, Origin, Region},
+    memory::MemoryImage,
+    pjl::{parse_pjl, extract_bitmap, replace_bitmap, PJLJob},
+    nand::{strip_oob, Geometry, Nand, PageStatus, OOB_SIZE, PAGE_SIZE, PAGES_PER_BLOCK},
+    srecord::{
+        parse_srecords, raw_binary_record, repack_binary_record, FlatImageWriter, SRecord,
+        SRecordReader,
+    },
 };
 
-/// Start of table that is used to retrieve section details for decompression
-/// Finding this could be automated by instead searching memory of the firmware for the magic value
-/// `0x3ca55a3c`, marking the start of the app_hdr struct, which can be used to retrieve this 
-/// address
-const TABLE_START: usize = 0x68690;
-
-/// Firmware Header
-#[derive(Debug, Default)]
-struct Header {
-    magic: usize,
-    _header_size: usize,
-    page_size: usize,
-    bmp_size: usize,
-    load_addr: usize,
-    load_size: usize,
-    exec_addr: usize,
-}
+/// Recompress all segment dumps in `dir` that no longer match the firmware and write them back
+/// to their source location, updating the tripples with the new compressed sizes
+fn repack_segments(firmware: &mut Firmware, bootloader: &mut BootLoader, dir: &str) -> Result<()> {
+    for tripple in bootloader.uncompress_tripples_mut().iter_mut() {
+        let (dst, src, size) = *tripple;
+        if size == 0 {
+            continue;
+        }
+        let data = match std::fs::read(format!("{}/{:X}.dump", dir, dst)) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
 
-/// Structure that describes the segments
-#[derive(Debug)]
-struct Segment {
-    /// Pointer to next element of linked list
-    _next: usize,
+        if lzss_uncompress(firmware.read(src, size)?) == data {
+            continue;
+        }
 
-    /// Segment Name
-    name: String,
+        // The compressed data has to fit into the space of the original, otherwise it would
+        // overwrite the data that follows it
+        let compressed = lzss_compress(&data);
+        if compressed.len() > size {
+            return Err(Error::TooLarge { addr: dst, size: compressed.len(), max: size });
+        }
+        let offset = src - firmware.header().load_addr();
+        firmware.data_mut()[offset..offset + size].fill(0);
+        firmware.data_mut()[offset..offset + compressed.len()].copy_from_slice(&compressed);
+        tripple.2 = compressed.len();
+        println!("[+] Uncompress: Repacked {:#X?}: {:#X?} -> {:#X?}", dst, size, compressed.len());
+    }
 
-    /// Starting address of section
-    start: usize,
+    for tripple in bootloader.memcpy_tripples() {
+        let (dst, src, size) = *tripple;
+        if size == 0 {
+            continue;
+        }
+        let data = match std::fs::read(format!("{}/{:X}.dump", dir, dst)) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
 
-    /// Size of section
-    size: usize,
+        if firmware.read(src, size)? == &data[..] {
+            continue;
+        }
+        if data.len() != size {
+            return Err(Error::TooLarge { addr: dst, size: data.len(), max: size });
+        }
+        let offset = src - firmware.header().load_addr();
+        firmware.data_mut()[offset..offset + size].copy_from_slice(&data);
+        println!("[+] Memcpy: Repacked {:#X?}", dst);
+    }
+    Ok(())
+}
 
-    /// Some options, possibly rwx bits, but doesn't quite line up
-    _flags: usize,
+/// Largest gap between two dumps that is still reported, dumps further apart hold unrelated memory
+const MAX_REPORTED_GAP: usize = 0x1000;
 
-    /// Used for intermediate loads using memcpys
-    _dst: usize,
-}
+/// Issue found while merging dumps
+#[derive(Debug, PartialEq, Eq)]
+enum MergeIssue {
+    /// Dump `start..end` overlaps the dumps `other_start..other_end` merged so far
+    Overlap { start: usize, end: usize, other_start: usize, other_end: usize },
 
-/// Firmware image after initial uncompression routines are completed
-struct Firmware {
-    header: Header,
-    segments: Vec<Segment>,
-    data: Vec<u8>,
+    /// Nearby dumps leave `start..end` uncovered
+    Gap { start: usize, end: usize },
 }
 
-impl Firmware {
-    /// Create new empty firmware
-    pub fn new() -> Self {
-        Self {
-            header: Header::default(),
-            segments: Vec::new(),
-            data: Vec::new(),
-        }
-    }
-
-    /// Parse out the firmware header from the srecords
-    pub fn parse_header(&mut self, srecords: &[u8]) {
-        self.header.magic = bytes_to_int_be(&srecords[0x0..0x4], 4);
-        self.header._header_size = bytes_to_int_be(&srecords[0x8..0xc], 4);
-        self.header.page_size = bytes_to_int_be(&srecords[0x10..0x14], 4);
-        self.header.bmp_size = bytes_to_int_be(&srecords[0x1c..0x20], 4);
-        self.header.load_addr = bytes_to_int_be(&srecords[0x30..0x34], 4);
-        self.header.load_size = bytes_to_int_be(&srecords[0x34..0x38], 4);
-        self.header.exec_addr = bytes_to_int_be(&srecords[0x3c..0x40], 4);
-        assert_eq!(self.header.magic, 0xBAD2BFED);
-    }
-
-    /// Parse out the firmware from the raw flash image
-    pub fn parse_data(&mut self, data: &[u8]) {
-        // Calculate the number of pages occupied by the bootsplash bmp, round up to nearest page
-        let num_bmp_pages: usize = (self.header.bmp_size / self.header.page_size) + 
-            if (self.header.bmp_size % self.header.page_size) > 0 { 1 } else { 0 };
-
-        // Calculate the number of pages occupied by the firmware, round up to nearest page
-        let num_firmware_pages: usize = (self.header.load_size / self.header.page_size) + 
-            if (self.header.page_size % self.header.page_size) > 0 { 1 } else { 0 };
-
-        // Get start address and end address of firmware to then extract it from data
-        let start_addr = (num_bmp_pages + 1) * self.header.page_size;
-        let end_addr = start_addr + (num_firmware_pages * self.header.page_size);
-        self.data.extend(&data[start_addr..end_addr]);
-
-        assert_eq!(self.data.len(), num_firmware_pages * self.header.page_size);
-    }
-
-    /// Parse out segment table from firmware
-    pub fn parse_segments(&mut self) {
-        // Start of the segment-table in memory
-        let mut next = TABLE_START;
-
-        while next != 0x0 {
-            let mut name = Vec::new();
-            let str_name;
-
-            let name_addr = bytes_to_int_be(&self.data[next+4..], 4) - self.header.load_addr;
-            let start = bytes_to_int_be(&self.data[next+8..], 4);
-            let size = bytes_to_int_be(&self.data[next+12..], 4);
-            let flags = bytes_to_int_be(&self.data[next+16..], 4);
-            let dst = bytes_to_int_be(&self.data[next+20..], 4);
-
-            // Parse out name
-            let mut i = 0;
-            while self.data[name_addr+i] != 0x0 {
-                name.push(self.data[name_addr+i]);
-                i+=1;
+/// Group the dumps, given as start and end addresses, whose address ranges directly follow each
+/// other. Returns the start addresses of the dumps of every group of more than one dump, in
+/// address order, along with the issues found. Overlapping dumps are not merged, and only gaps of
+/// up to `MAX_REPORTED_GAP` bytes are reported
+fn plan_merge(dumps: &[(usize, usize)]) -> (Vec<Vec<usize>>, Vec<MergeIssue>) {
+    let mut dumps = dumps.to_vec();
+    dumps.sort_unstable();
+
+    // Group currently being extended, along with its end address
+    let mut groups = Vec::new();
+    let mut issues = Vec::new();
+    let mut current: Option<(Vec<usize>, usize)> = None;
+    for (start, end) in dumps {
+        match &mut current {
+            Some((group, group_end)) if *group_end == start => {
+                group.push(start);
+                *group_end = end;
+                continue;
             }
-            str_name = std::str::from_utf8(&name).unwrap();
-
-            next = bytes_to_int_be(&self.data[next..], 4).checked_sub(self.header.load_addr)
-                .unwrap_or(0);
-            self.segments.push(Segment {
-                    _next: next + self.header.load_addr,
-                    name: str_name.to_string(),
+            Some((group, group_end)) if start < *group_end => {
+                issues.push(MergeIssue::Overlap {
                     start,
-                    size,
-                    _flags: flags,
-                    _dst: dst,
+                    end,
+                    other_start: group[0],
+                    other_end: *group_end,
                 });
+                if end <= *group_end {
+                    continue;
+                }
+            }
+            Some((_, group_end)) if start - *group_end <= MAX_REPORTED_GAP => {
+                issues.push(MergeIssue::Gap { start: *group_end, end: start });
+            }
+            _ => {}
         }
+        groups.extend(current.replace((vec![start], end)).map(|(group, _)| group));
     }
+    groups.extend(current.map(|(group, _)| group));
+    groups.retain(|group| group.len() > 1);
+    (groups, issues)
+}
 
-    pub fn dump_hardcoded(&self) {
-        // Hardcoded memcpy
-        { 
-            let dst: usize = 0x60;
-            let src: usize = 0x26710000;
-            let len: usize = 0x7b0;
+/// Merge the dumps in `dir` whose address ranges directly follow each other into the dump of the
+/// first one. Overlapping dumps and gaps between nearby dumps are reported and left as they are.
+/// Returns the start address of every merged dump along with the start of the dump it was merged
+/// into
+fn merge_dumps(dir: &str) -> Result<Vec<(usize, usize)>> {
+    // Dumps are named after their start address
+    let mut dumps = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let start = path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".dump"))
+            .and_then(|addr| usize::from_str_radix(addr, 16).ok());
+        if let Some(start) = start {
+            dumps.push((start, start + std::fs::metadata(&path)?.len() as usize));
+        }
+    }
 
-            let data = &self.data[src.checked_sub(self.header.load_addr).unwrap()..
-                (src - self.header.load_addr).checked_add(len).unwrap()];
+    let (groups, issues) = plan_merge(&dumps);
+    for issue in issues {
+        match issue {
+            MergeIssue::Overlap { start, end, other_start, other_end } => {
+                println!("[!] Overlap: {:#X?} - {:#X?} overlaps {:#X?} - {:#X?}", start, end,
+                         other_start, other_end);
+            }
+            MergeIssue::Gap { start, end } => {
+                println!("[!] Gap: {:#X?} bytes between {:#X?} and {:#X?}", end - start, start,
+                         end);
+            }
+        }
+    }
 
-            let path: String = format!("segments/{:X}.dump", dst).to_string();
-            std::fs::write(path, data).unwrap()
+    let mut merged = Vec::new();
+    for group in groups {
+        let into = group[0];
+        let mut data = std::fs::read(format!("{}/{:X}.dump", dir, into))?;
+        for &start in &group[1..] {
+            let part = std::fs::read(format!("{}/{:X}.dump", dir, start))?;
+            println!("[+] Merged {:#X?} - {:#X?} into {:#X?}", start, start + part.len(), into);
+            data.extend(part);
+            merged.push((start, into));
         }
 
-        // Hardcoded uncompress
-        {
-            let tripples: [(usize, usize, usize);3] = [
-                (0xa007cd60, 0x26724b18, 0x3cfa),
-                (0x2009fd8c, 0x26722398, 0x277f),
-                (0x200890c0, 0x267107b0, 0x11be7),
-            ];
-            for tripple in tripples {
-                let dst  = tripple.0;
-                let src  = tripple.1;
-                let size = tripple.2;
+        // The parts are only removed once the merged dump is written
+        std::fs::write(format!("{}/{:X}.dump", dir, into), data)?;
+        for &start in &group[1..] {
+            std::fs::remove_file(format!("{}/{:X}.dump", dir, start))?;
+        }
+    }
+    Ok(merged)
+}
 
-                let data = lzss_uncompress(&self.data[src
-                                           .checked_sub(self.header.load_addr).unwrap()..
-                                           (src - self.header.load_addr)
-                                           .checked_add(size).unwrap()]);
+/// Rebuild a flashable pjl job from the original job at `input`, with the firmware replaced by the
+/// image at `firmware_path` and/or the segment dumps found in `segments_dir`
+fn repack(input: &str, output: &str, firmware_path: Option<&str>, segments_dir: Option<&str>)
+        -> Result<()> {
+    let blob = std::fs::read(input)?;
+    let raw = parse_pjl(&blob)?;
+    let bm = extract_bitmap(&raw)?;
+    let srecord = parse_srecords(&bm)?;
+    let nand = load_nand(&srecord)?;
+    let mut data = strip_oob(&nand);
+    let mut firmware = Firmware::new();
 
-                let path: String = format!("segments/{:X}.dump", dst).to_string();
-                std::fs::write(path, data).unwrap()
-            }
+    firmware.parse_header(&data)?;
+    firmware.parse_data(&data)?;
+
+    if let Some(path) = firmware_path {
+        let image = std::fs::read(path)?;
+
+        // The firmware may grow into the erased flash following it, but not over other data
+        let end = firmware.data_offset() + firmware.data().len();
+        let erased = data.get(end..).unwrap_or_default().iter().take_while(|&&b| b == 0xFF).count();
+        if image.len() > firmware.data().len() + erased {
+            return Err(Error::TooLarge {
+                addr: firmware.header().load_addr(),
+                size: image.len(),
+                max: firmware.data().len() + erased,
+            });
         }
+        if image.len() > firmware.header().load_size() {
+            println!("[+] Firmware grows from {:#X?} to {:#X?} bytes",
+                     firmware.header().load_size(), image.len());
+            firmware.set_load_size(image.len());
+        }
+        firmware.data_mut()[..image.len()].copy_from_slice(&image);
+    }
+
+    let mut bootloader = BootLoader::default();
+    bootloader.parse_header(&firmware)?;
+    bootloader.initialize_tripples(&firmware)?;
+
+    if let Some(dir) = segments_dir {
+        repack_segments(&mut firmware, &mut bootloader, dir)?;
+    }
+    bootloader.write_tripples(&mut firmware)?;
+
+    // Put the firmware back into the flash image and re-encode it the same way it was decoded
+    let offset = firmware.data_offset();
+    if data.len() < offset + firmware.data().len() {
+        data.resize(offset + firmware.data().len(), 0xFF);
+    }
+    data[offset..offset + firmware.data().len()].copy_from_slice(firmware.data());
+    firmware.write_header(&mut data)?;
+    let original = Nand::new(nand, Geometry::default());
+    let layout = original.detect_ecc();
+    if layout.is_none() {
+        println!("[!] Could not detect the ECC scheme, modified pages get an erased OOB");
+    }
+    let nand = original.encode(&data, layout.as_ref()).into_raw();
+    let bm = repack_binary_record(&bm, &srecord, &nand)?;
+    std::fs::write(output, replace_bitmap(&blob, &bm)?)?;
+    Ok(())
+}
+
+/// Usage information printed for unknown commands
+const USAGE: &str = "\
+Usage: unpacker [<command>] [-i <input>] [-o <output>]
+
+Commands:
+    pjl      Dump the pjl commands of a job            -i <job>     [-o <text>]
+    raster   Extract the decompressed raster bitmap    -i <job>     -o <bitmap>
+    srec     Parse the s-records carried by a bitmap   -i <bitmap>  -o <nand>
+    nand     Check the ECC and strip the OOB data      -i <nand>    -o <flash>
+             [--page-size <n>] [--oob-size <n>] [--pages-per-block <n>]
+    fw       Parse the firmware header and segments    -i <flash>   -o <firmware>
+    boot     Parse the bootloader tables               -i <flash>   [-o <text>]
+    extract  Run every stage and dump all segments     -i <job>     -o <dir>
+             [--merge] [--scan]
+             [--memset <addr>] [--memcpy <addr>] [--uncompress <addr>]
+    merge    Merge adjacent segment dumps              -i <dir>
+    repack   Rebuild a job from a modified firmware    -i <job>     -o <job>
+             [--firmware <firmware>] [--segments <dir>]
+
+Without a command `extract` is run with the default paths
+";
+
+/// Options passed on the command line after the command
+struct Options<'a>(&'a [String]);
+
+impl Options<'_> {
+    /// Value following the first occurrence of any of the given flags
+    fn get(&self, flags: &[&str]) -> Option<&str> {
+        self.0.iter()
+            .position(|arg| flags.contains(&arg.as_str()))
+            .and_then(|i| self.0.get(i + 1))
+            .map(String::as_str)
+    }
 
-        // Hardcoded memsets
-        {
-            let tripples: [(usize, u8, usize);4] = [
-                (0xa007cd20, 0, 0x40),
-                (0xa0081160, 0, 0x7f58),
-                (0x600788a0, 0, 0x4464),
-                (0x60000000, 0, 0xc7c),
-            ];
-
-            // Dump data for memset tripples
-            for tripple in &tripples {
-                let dst  = tripple.0;
-                let val  = tripple.1;
-                let size = tripple.2;
-
-                let data = vec![val; size];
-                let path: String = format!("segments/{:X}.dump", dst).to_string();
-                std::fs::write(path, data).unwrap()
+    /// Whether the given flag was passed
+    fn has(&self, flag: &str) -> bool {
+        self.0.iter().any(|arg| arg == flag)
+    }
+
+    /// Number following the given flag, decimal or hex with a `0x` prefix
+    fn number(&self, flag: &str, default: usize) -> Result<usize> {
+        let value = match self.get(&[flag]) {
+            Some(value) => value,
+            None => return Ok(default),
+        };
+        match value.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => value.parse(),
+        }.map_err(|_| Error::InvalidConfig("expected a number"))
+    }
+
+    /// Entry addresses of the boot routines given with `--memset`, `--memcpy` and `--uncompress`
+    fn routines(&self) -> Result<Vec<(&'static str, usize)>> {
+        let mut routines = Vec::new();
+        for name in ["memset", "memcpy", "uncompress"] {
+            let flag = format!("--{}", name);
+            if self.has(&flag) {
+                routines.push((name, self.number(&flag, 0)?));
             }
         }
+        Ok(routines)
     }
-}
 
-#[derive(Default, Debug, Copy, Clone)]
-struct AppHeader {
-    magic: usize,
-    size: usize,
-    _magic1: usize,
-    _magic2: usize,
-    _bootsplash_bmp: usize,
-    entry_point: usize,
-    protected_count: usize,
-    protected_addr: usize,
-    section_linked_list: usize,
-    memset_list_start: usize,
-    memset_list_end: usize,
-    copy_list_start: usize,
-    copy_list_end: usize,
-    _copy_list_barrier: usize,
-    uncompress_list_start: usize,
-    uncompress_list_end: usize,
-    _uncompress_list_barrier: usize,
-}
+    /// Input path of the command
+    fn input<'b>(&'b self, default: &'b str) -> &'b str {
+        self.get(&["-i", "--input"]).unwrap_or(default)
+    }
 
-#[derive(Default, Debug)]
-struct BootLoader {
-    /// App header used to parse out other important structures
-    header: AppHeader,
+    /// Output path of the command
+    fn output<'b>(&'b self, default: &'b str) -> &'b str {
+        self.get(&["-o", "--output"]).unwrap_or(default)
+    }
+
+    /// Write textual output to the output path if one was given, otherwise print it
+    fn write_text(&self, text: &str) -> Result<()> {
+        match self.get(&["-o", "--output"]) {
+            Some(path) => std::fs::write(path, text)?,
+            None => print!("{}", text),
+        }
+        Ok(())
+    }
+}
 
-    /// Each entry lists start and end addresses of a protected section. Protected sections should 
-    /// not be overwritten
-    protected_ranges: Vec<(usize, usize)>,
+/// Parse a job and return its decompressed raster bitmap
+fn load_bitmap(input: &str) -> Result<Vec<u8>> {
+    let blob = std::fs::read(input)?;
+    let raw = parse_pjl(&blob)?;
+    extract_bitmap(&raw)
+}
 
-    /// dst, src, compressed_size that are passed to the uncompress section to later decompress
-    uncompress_tripples: Vec<(usize, usize, usize)>,
+/// Assemble the raw nand image carried by the binary records, reporting any gaps, overlaps and
+/// records that are out of order
+fn load_nand(srecord: &[SRecord]) -> Result<Vec<u8>> {
+    let image = raw_binary_record(srecord)?;
+    for (start, end) in image.gaps() {
+        println!("[!] Gap in binary records: {:#X?} - {:#X?}", start, end);
+    }
+    for (start, end) in image.overlaps() {
+        println!("[!] Overlapping binary records: {:#X?} - {:#X?}", start, end);
+    }
+    for offset in image.out_of_order() {
+        println!("[!] Binary record at {:#X?} is out of order", offset);
+    }
+    Ok(image.into_data())
+}
 
-    /// dst, src, length that are passed to the memcpy function to setup memory mappings
-    memcpy_tripples: Vec<(usize, usize, usize)>,
+/// Parse the firmware header, data and segments out of a flash image without OOB data
+fn load_firmware(data: &[u8]) -> Result<Firmware> {
+    let mut firmware = Firmware::new();
+    firmware.parse_header(data)?;
+    firmware.parse_data(data)?;
+    if firmware.parse_segments()? == TableSource::Scan {
+        println!("[!] Segment table not referenced by app header, scanning for it");
+    }
+    Ok(firmware)
+}
 
-    /// dst, val, length that are passed to the memset function to setup memory mappings
-    memset_tripples: Vec<(usize, usize, usize)>,
+/// Parse the app header, protected ranges and tripples used by the bootloader
+fn load_bootloader(firmware: &Firmware) -> Result<BootLoader> {
+    let mut bootloader = BootLoader::default();
+    bootloader.parse_header(firmware)?;
+    bootloader.initialize_protected(firmware)?;
+    bootloader.initialize_tripples(firmware)?;
+    bootloader.initialize_first_stage();
+
+    // The first stage tables are not referenced by anything, guess them from the first stage code
+    bootloader.scan_first_stage(firmware)?;
+    for op in bootloader.scanned() {
+        println!("[!] {} at {:#X} guessed by scanning the first stage", op.name(), op.dst());
+    }
+    Ok(bootloader)
 }
 
-const APP_HEADER_MAGIC: usize = 0x3ca55a3c;
-impl BootLoader {
-    /// Parse out app header structure from firmware
-    pub fn parse_header(&mut self, firmware: &Firmware) -> Option<()> {
-        // Find header offset
-        let mut index = 0;
-        let mut app_hdr_index: Option<usize> = None;
-
-        // Find app_hdr struct based on magic value
-        firmware.data.chunks_exact(4).for_each(|e| {
-            if bytes_to_int_be(e, 4) == APP_HEADER_MAGIC {
-                assert!(app_hdr_index.is_none(), "Found magic bytes more than once. Failed to \
-                        automatically locate app header");
-                    app_hdr_index = Some(index);
+/// `pjl`: Dump all pjl commands of a job
+fn cmd_pjl(options: &Options) -> Result<()> {
+    let blob = std::fs::read(options.input("./init_blob.bin"))?;
+    let pjls = parse_pjl(&blob)?;
+
+    // A header that does not parse still leaves the raw commands to look at
+    match PJLJob::from_commands(&pjls) {
+        Ok(job) => {
+            if let Some(name) = job.name() {
+                println!("Job name: {}", name);
+            }
+            if let Some(language) = job.language() {
+                println!("Language: {}", language);
+            }
+            for (name, value) in job.settings() {
+                println!("Setting {} = {}", name, value);
             }
-            index += 4;
-        });
-        assert!(app_hdr_index.is_some());
-
-        self.header.magic = APP_HEADER_MAGIC;
-        self.header.size = bytes_to_int_be(&firmware.data[app_hdr_index?+4..app_hdr_index?+8], 4);
-        self.header.entry_point = 
-            bytes_to_int_be(&firmware.data[app_hdr_index?+52..app_hdr_index?+56], 4);
-        self.header.protected_count = 
-            bytes_to_int_be(&firmware.data[app_hdr_index?-4..app_hdr_index?], 4);
-        self.header.protected_addr = 
-            bytes_to_int_be(&firmware.data[app_hdr_index?+60..app_hdr_index?+64], 4);
-        self.header.section_linked_list = 
-            bytes_to_int_be(&firmware.data[app_hdr_index?+64..app_hdr_index?+68], 4);
-        self.header.memset_list_start = 
-            bytes_to_int_be(&firmware.data[app_hdr_index?+72..app_hdr_index?+76], 4);
-        self.header.memset_list_end = 
-            bytes_to_int_be(&firmware.data[app_hdr_index?+76..app_hdr_index?+80], 4);
-        self.header.copy_list_start = 
-            bytes_to_int_be(&firmware.data[app_hdr_index?+80..app_hdr_index?+84], 4);
-        self.header.copy_list_end = 
-            bytes_to_int_be(&firmware.data[app_hdr_index?+84..app_hdr_index?+88], 4);
-        self.header.uncompress_list_start = 
-            bytes_to_int_be(&firmware.data[app_hdr_index?+92..app_hdr_index?+96], 4);
-        self.header.uncompress_list_end = 
-            bytes_to_int_be(&firmware.data[app_hdr_index?+96..app_hdr_index?+100], 4);
-
-        Some(())
-    }
-
-    /// Parse out protected segments from firmware. These are address ranges that should not be 
-    /// overwritten since they are virtal for the boot process
-    pub fn initialize_protected(&mut self, firmware: &Firmware) -> Option<()> {
-        for i in 0..self.header.protected_count {
-            let start = bytes_to_int_be(&firmware.data[
-                self.header.protected_addr - firmware.header.load_addr + i*8..
-                self.header.protected_addr - firmware.header.load_addr + i*8 + 4], 4);
-
-            let end = bytes_to_int_be(&firmware.data[
-                self.header.protected_addr - firmware.header.load_addr + i*8+4..
-                self.header.protected_addr - firmware.header.load_addr + i*8 + 8], 4);
-
-            self.protected_ranges.push((start, end));
-        }
-        Some(())
-    }
-
-    /// Parse out protected segments from firmware. These are address ranges that should not be 
-    /// overwritten since they are virtal for the boot process
-    pub fn parse_tripples(&mut self, data: &[u8]) 
-        -> Option<Vec<(usize, usize, usize)>> {
-
-            Some(data.chunks_exact(12)
-            .map(|e| {
-                (
-                    bytes_to_int_be(&e[0..4], 4),
-                    bytes_to_int_be(&e[4..8], 4),
-                    bytes_to_int_be(&e[8..12], 4),
-                )
-            }).collect())
-    }
-
-    /// Parse out protected segments from firmware. These are address ranges that should not be 
-    /// overwritten since they are virtal for the boot process
-    pub fn initialize_tripples(&mut self, firmware: &Firmware) {
-        // Parse out uncompress tripples
-        self.uncompress_tripples = self.parse_tripples(
-            &firmware.data[self.header.uncompress_list_start - firmware.header.load_addr..
-            self.header.uncompress_list_end - firmware.header.load_addr]).unwrap();
-
-        // Parse out memset tripples
-        self.memset_tripples = self.parse_tripples(
-            &firmware.data[self.header.memset_list_start - firmware.header.load_addr..
-            self.header.memset_list_end - firmware.header.load_addr]).unwrap();
-
-        // Parse out memcpy tripples
-        self.memcpy_tripples = self.parse_tripples(
-            &firmware.data[self.header.copy_list_start - firmware.header.load_addr..
-            self.header.copy_list_end - firmware.header.load_addr]).unwrap();
-    }
-
-    /// Return true if given range overlaps with a protected section
-    pub fn is_protected(&self, start: usize, end: usize) -> bool {
-        for protected_section in self.protected_ranges.iter() {
-            if std::cmp::max(protected_section.0, start) 
-                <= std::cmp::min(protected_section.1, end) {
-                return true;
+            for update in job.updates() {
+                println!("Update directive: {:X?}", update);
             }
         }
-        false
+        Err(err) => println!("[!] Could not parse the PJL header: {}", err),
     }
+
+    let text = pjls
+        .iter()
+        .map(|command| format!("{:X?}\n", command))
+        .collect::<String>();
+    options.write_text(&text)
 }
 
-fn main() {
-    let blob = std::fs::read("./init_blob.bin").unwrap();
-    let raw = parse_pjl(&blob);
-    let bm = extract_bitmap(&raw);
-    let srecord = parse_srecords(&bm);
-    let data = print_binary_record(&srecord);
-    let mut firmware = Firmware::new();
+/// `raster`: Extract the decompressed raster bitmap of a job
+fn cmd_raster(options: &Options) -> Result<()> {
+    let bm = load_bitmap(options.input("./init_blob.bin"))?;
+    std::fs::write(options.output("./bitmap"), bm)?;
+    Ok(())
+}
 
-    firmware.parse_header(&data);
-    firmware.parse_data(&data);
-    firmware.parse_segments();
+/// `srec`: Parse the s-records of a bitmap and write out the raw nand image they carry
+fn cmd_srec(options: &Options) -> Result<()> {
+    // Stream the records into the nand image, reporting the type-A payloads as they are parsed
+    let bm = BufReader::new(File::open(options.input("./bitmap"))?);
+    let nand = BufWriter::new(File::create(options.output("./nand"))?);
+    let mut image = FlatImageWriter::new(nand, 0xFF);
+    let mut count = 0;
+    for record in SRecordReader::new(bm) {
+        let record = record?;
+        if let Some(payload) = record.type_a() {
+            println!("Type-A record at {:#X}: {:X?}", record.offset(), payload);
+        }
+        image.write(&record)?;
+        count += 1;
+    }
+    println!("Parsed {} records", count);
+    for (start, end) in image.gaps() {
+        println!("[!] Gap in binary records: {:#X?} - {:#X?}", start, end);
+    }
+    for (start, end) in image.overlaps() {
+        println!("[!] Overlapping binary records: {:#X?} - {:#X?}", start, end);
+    }
+    for offset in image.out_of_order() {
+        println!("[!] Binary record at {:#X?} is out of order", offset);
+    }
+    image.finish()?;
+    Ok(())
+}
 
-    let _ = std::fs::remove_dir_all("segments");
-    std::fs::create_dir_all("segments").unwrap();
-    firmware.dump_hardcoded();
+/// `nand`: Check the pages of a raw nand image against their ECC and strip the OOB data
+fn cmd_nand(options: &Options) -> Result<()> {
+    let geometry = Geometry::new(
+        options.number("--page-size", PAGE_SIZE)?,
+        options.number("--oob-size", OOB_SIZE)?,
+        options.number("--pages-per-block", PAGES_PER_BLOCK)?,
+    )?;
+    let mut nand = Nand::new(std::fs::read(options.input("./nand"))?, geometry);
+
+    for block in nand.bad_blocks() {
+        println!("[!] Block {} is marked bad", block);
+    }
 
-    //println!("HEADER: {:#X?}", firmware.header);
-    std::fs::write("./firmware", &firmware.data).unwrap();
+    match nand.detect_ecc() {
+        Some(layout) => {
+            println!("Detected ECC {:?} at offset {:#X} of the spare area", layout.scheme(),
+                     layout.offset());
+            for (page, status) in nand.correct(&layout) {
+                match status {
+                    PageStatus::Corrected(bits) => {
+                        println!("[+] Page {}: Corrected {} bit errors", page, bits)
+                    }
+                    PageStatus::Uncorrectable => println!("[!] Page {}: Uncorrectable", page),
+                    _ => {}
+                }
+            }
+        }
+        None => println!("[!] Could not detect the ECC scheme, pages are not verified"),
+    }
+    std::fs::write(options.output("./flash"), nand.data())?;
+    Ok(())
+}
 
-    let mut bootloader = BootLoader::default();
-    bootloader.parse_header(&firmware);
-    bootloader.initialize_protected(&firmware);
-    bootloader.initialize_tripples(&firmware);
+/// `fw`: Parse the firmware header and segments out of a flash image
+fn cmd_fw(options: &Options) -> Result<()> {
+    let data = std::fs::read(options.input("./flash"))?;
+    let firmware = load_firmware(&data)?;
+    println!("{:#X?}", firmware.header());
+    println!("{:#X?}", firmware.segments());
+    std::fs::write(options.output("./firmware"), firmware.data())?;
+    Ok(())
+}
 
-    //println!("PROTECTED: {:#X?}", bootloader.protected_ranges);
+/// `boot`: Parse the bootloader tables out of a flash image
+fn cmd_boot(options: &Options) -> Result<()> {
+    let data = std::fs::read(options.input("./flash"))?;
+    let firmware = load_firmware(&data)?;
+    let bootloader = load_bootloader(&firmware)?;
+    options.write_text(&format!("{:#X?}\n", bootloader))
+}
 
+/// Unpack the firmware contained in the job at `input` into `output`/firmware and
+/// `output`/segments/, merging adjacent segment dumps if `merge` is set. The first stage
+/// operations guessed by scanning its code are only applied if `scan` is set. The boot routines
+/// in `routines` are labelled at their entry addresses
+fn extract(input: &str, output: &str, merge: bool, scan: bool, routines: &[(&str, usize)])
+        -> Result<()> {
+    let bm = load_bitmap(input)?;
+    let srecord = parse_srecords(&bm)?;
+    let data = strip_oob(&load_nand(&srecord)?);
+    let firmware = load_firmware(&data)?;
+
+    let segments = format!("{}/segments", output);
+    let _ = std::fs::remove_dir_all(&segments);
+    std::fs::create_dir_all(&segments)?;
 
-    // Uncompress all tripples related to sections meant to be uncompressed
-    for tripple in &bootloader.uncompress_tripples {
-        let dst  = tripple.0;
-        let src  = tripple.1;
-        let size = tripple.2;
+    //println!("HEADER: {:#X?}", firmware.header);
+    std::fs::write(format!("{}/firmware", output), firmware.data())?;
 
-        if size == 0 {
-            continue;
-        }
+    let bootloader = load_bootloader(&firmware)?;
 
-        let data = lzss_uncompress(&firmware.data[src
-                                   .checked_sub(firmware.header.load_addr).unwrap()..
-                                   (src - firmware.header.load_addr)
-                                   .checked_add(size).unwrap()]);
+    //println!("PROTECTED: {:#X?}", bootloader.protected_ranges);
 
-        // Verify that this section is not going to be overwriting a protected segment
-        if bootloader.is_protected(dst, dst+data.len()) {
-            println!("[!] Uncompress: Found protected at: {:#X?} : {:#X?}", dst, dst+data.len());
-            continue
+    // Run all bootloader operations in order and dump the data each of them writes
+    let load_addr = firmware.header().load_addr();
+    let mut manifest = Manifest::new(load_addr, bootloader.header().entry_point());
+    let mut image = MemoryImage::new();
+    let operations = if scan {
+        bootloader.operations_with_scanned()
+    } else {
+        if !bootloader.scanned().is_empty() {
+            println!("[!] Guessed first stage operations are not applied, pass --scan to apply them");
         }
+        bootloader.operations()
+    };
+    for op in operations {
+        let dst = op.dst();
+        let data = match image.apply(op, &bootloader, &firmware)? {
+            Some(data) => data,
+            None => {
+                let err = Error::ProtectedOverlap { start: dst, end: dst + op.len() };
+                println!("[!] {}: {}", op.name(), err);
+                continue
+            }
+        };
 
-        let path: String = format!("segments/{:X}.dump", dst).to_string();
-        std::fs::write(path, data).unwrap()
-    }
+        // Verify that recompressing the section yields data the bootloader uncompresses back to
+        // the exact same bytes
+        if matches!(op, BootOp::Uncompress { .. }) && lzss_uncompress(&lzss_compress(&data)) != data {
+            println!("[!] Uncompress: Failed to round-trip lzss at: {:#X?}", dst);
+        }
 
-    // Dump data for memset tripples
-    for tripple in &bootloader.memset_tripples {
-        let dst  = tripple.0;
-        let val  = tripple.1 as u8;
-        let size = tripple.2;
+        let mut region = Region::new(&format!("segments/{:X}.dump", dst), dst, &data, 0,
+                                     Origin::from(&op));
+        region.source_offset = op.src()
+            .and_then(|src| src.checked_sub(load_addr))
+            .filter(|&offset| offset < firmware.data().len());
+        region.heuristic = bootloader.is_scanned(&op);
+        manifest.add(region);
 
-        if size == 0 {
-            continue;
-        }
+        let path: String = format!("{}/{:X}.dump", segments, dst).to_string();
+        std::fs::write(path, data)?
+    }
 
-        // Verify that this section is not going to be overwriting a protected segment
-        if bootloader.is_protected(dst, dst+size) {
-            println!("[!] Memset: Found protected at: {:#X?} : {:#X?}", dst, dst+data.len());
-            continue
+    // Merged dumps are now part of the dump they were merged into
+    if merge {
+        for (start, into) in merge_dumps(&segments)? {
+            for region in manifest.regions_mut() {
+                if region.vaddr == start && region.file_offset == 0 {
+                    region.file = Some(format!("segments/{:X}.dump", into));
+                    region.file_offset = start - into;
+                }
+            }
         }
+    }
 
-        let data = vec![val; size];
-        let path: String = format!("segments/{:X}.dump", dst).to_string();
-        std::fs::write(path, data).unwrap()
+    for overlap in image.overlaps() {
+        println!("[!] {} at {:#X?} overwrites {} at {:#X?}: {:#X?} - {:#X?}",
+                 overlap.second.name(), overlap.second.dst(), overlap.first.name(),
+                 overlap.first.dst(), overlap.start, overlap.end);
     }
 
-    // Dump data for memcpy tripples
-    for tripple in &bootloader.memcpy_tripples {
-        let dst  = tripple.0;
-        let src  = tripple.1;
-        let size = tripple.2;
+    // Dump the final state of memory once the bootloader is done
+    let memory = format!("{}/memory", output);
+    let _ = std::fs::remove_dir_all(&memory);
+    std::fs::create_dir_all(&memory)?;
+    for (start, region) in image.regions() {
+        std::fs::write(format!("{}/{:X}.bin", memory, start), region)?;
+        manifest.add(Region::new(&format!("memory/{:X}.bin", start), start, region, 0,
+                                 Origin::Memory));
+    }
 
-        if size == 0 {
-            continue;
+    // Locate every named segment in memory. Segments loaded by a bootloader operation span the
+    // memory that operation wrote, all others are taken at their destination as is
+    let sections: Vec<(&str, usize, usize, u32)> = firmware.segments().iter()
+        .filter(|segment| segment.size() != 0)
+        .map(|segment| {
+            let written = image.writes().iter()
+                .find(|(_, _, op)| segment.start() != 0 && op.src() == Some(segment.start()));
+            let (start, end) = match written {
+                Some(&(start, end, _)) => (start, end),
+                None if segment.dst() != 0 => (segment.dst(), segment.dst() + segment.size()),
+                None => (segment.start(), segment.start() + segment.size()),
+            };
+            (segment.name(), start, end - start, segment.permissions())
+        })
+        .collect();
+
+    // Closure to compute the permissions of memory within `start..end`. Memory takes the
+    // permissions of the segments it contains. Without any, memory that is only ever memset is
+    // treated as data and everything else may contain code
+    let permissions = |start: usize, end: usize| {
+        let perms = sections.iter()
+            .filter(|&&(_, addr, size, _)| addr >= start && addr + size <= end)
+            .fold(0, |perms, &(_, _, _, section_perms)| perms | section_perms);
+        let memset_only = image.writes_in(start, end)
+            .all(|op| matches!(op, BootOp::Memset { .. }));
+        match perms {
+            0 if memset_only => PF_R | PF_W,
+            0 => PF_R | PF_W | PF_X,
+            perms => perms,
         }
+    };
+
+    // Describe every emitted region in the manifest, including the named segments
+    for region in manifest.regions_mut() {
+        region.perms = permissions(region.vaddr, region.vaddr + region.size);
+    }
+    let mut firmware_region = Region::new("firmware", load_addr, firmware.data(), PF_R | PF_X,
+                                          Origin::Firmware);
+    firmware_region.source_offset = Some(0);
+    manifest.add(firmware_region);
+    let named = firmware.segments().iter().filter(|segment| segment.size() != 0);
+    for (segment, &(name, addr, size, perms)) in named.zip(&sections) {
+        let mut region = match image.regions().find(|&(start, data)| {
+            addr >= start && addr + size <= start + data.len()
+        }) {
+            Some((start, data)) => {
+                let mut region = Region::new(&format!("memory/{:X}.bin", start), addr,
+                                             &data[addr - start..addr - start + size], perms,
+                                             Origin::SegmentTable);
+                region.file_offset = addr - start;
+                region
+            }
+            None => Region {
+                name: None,
+                file: None,
+                file_offset: 0,
+                vaddr: addr,
+                size,
+                perms,
+                origin: Origin::SegmentTable,
+                source_offset: None,
+                sha256: None,
+                flags: None,
+                heuristic: false,
+            },
+        };
+        region.name = Some(name.to_string());
+        region.flags = Some(segment.flags());
+        region.source_offset = segment.start()
+            .checked_sub(load_addr)
+            .filter(|&offset| offset < firmware.data().len());
+        manifest.add(region);
+    }
 
-        let data = &firmware.data[src.checked_sub(firmware.header.load_addr).unwrap()..
-            (src - firmware.header.load_addr).checked_add(size).unwrap()];
+    // Symbols for the boot tables the bootloader works through
+    let app_header = bootloader.header();
+    manifest.add_symbol("entry", app_header.entry_point(), 0, SymbolKind::Func);
+    manifest.add_symbol("app_hdr", load_addr + find_app_header(firmware.data())?, 0,
+                        SymbolKind::Object);
+    manifest.add_symbol("protected_ranges", app_header.protected_addr(),
+                        app_header.protected_count() * 8, SymbolKind::Object);
+    manifest.add_symbol("segment_table", load_addr + firmware.find_segment_table()?.0,
+                        firmware.segments().len() * SEGMENT_ENTRY_SIZE, SymbolKind::Object);
+    for (name, (start, end)) in [("boot_memset_list", app_header.memset_list()),
+                                 ("boot_memcpy_list", app_header.copy_list()),
+                                 ("boot_uncompress_list", app_header.uncompress_list())] {
+        manifest.add_symbol(name, start, end.saturating_sub(start), SymbolKind::Object);
+    }
 
-        // Verify that this section is not going to be overwriting a protected segment
-        if bootloader.is_protected(dst, dst+size) {
-            println!("[!] Memcpy: Found protected at: {:#X?} : {:#X?}", dst, dst+data.len());
-            continue
+    // No table references the boot routines themselves, their entry points have to be given
+    for &(name, addr) in routines {
+        manifest.add_symbol(&format!("boot_{}", name), addr, 0, SymbolKind::Func);
+    }
+    std::fs::write(format!("{}/manifest.json", output), manifest.to_json())?;
+    std::fs::write(format!("{}/ghidra_loader.py", output), loader_script(&manifest))?;
+
+    // Export the memory map as an ELF file, memory that is only ever memset to zero takes up no
+    // space in the file
+    let mut elf = Elf::new(bootloader.header().entry_point());
+    for (start, region) in image.regions() {
+        let end = start + region.len();
+        let perms = permissions(start, end);
+
+        // Regions without a named segment still get a section so section based tools see them
+        if !sections.iter().any(|&(_, addr, size, _)| addr >= start && addr + size <= end) {
+            elf.add_section(&format!(".mem_{:x}", start), start, region.len(), perms);
         }
-        // Verify that this section is not going to be overwriting a protected segment
-        if bootloader.is_protected(dst, dst+size) {
-            println!("[!] Memcpy: Found protected at: {:#X?} : {:#X?}", dst, dst+data.len());
-            continue
+        let memset_only = image.writes_in(start, end)
+            .all(|op| matches!(op, BootOp::Memset { .. }));
+        if memset_only && region.iter().all(|&b| b == 0) {
+            elf.add_zero_segment(start, region.len(), perms);
+        } else {
+            elf.add_segment(start, region, perms);
         }
+    }
 
-        let path: String = format!("segments/{:X}.dump", dst).to_string();
-        std::fs::write(path, data).unwrap()
+    // The firmware itself stays mapped at its load address, it holds the boot tables
+    let load_end = load_addr + firmware.data().len();
+    for (start, region) in image.regions() {
+        if start < load_end && load_addr < start + region.len() {
+            println!("[!] Firmware overlaps memory at {:#X}-{:#X}", std::cmp::max(start, load_addr),
+                     std::cmp::min(start + region.len(), load_end));
+        }
+    }
+    elf.add_segment(load_addr, firmware.data(), PF_R | PF_X);
+    elf.add_section(".firmware", load_addr, firmware.data().len(), PF_R | PF_X);
+    for &(name, addr, size, perms) in &sections {
+        elf.add_section(&format!(".{}", name.trim_start_matches('.')), addr, size, perms);
     }
+    for symbol in manifest.symbols() {
+        elf.add_symbol(&symbol.name, symbol.addr, symbol.size, symbol.kind);
+    }
+    std::fs::write(format!("{}/firmware.elf", output), elf.to_bytes())?;
 
     println!("{:#X?}", bootloader);
 
@@ -468,7 +748,208 @@ fn main() {
         std::fs::write(path, data).unwrap()
     }
     */
-    println!("Firmware Load address: {:#X?}", firmware.header.load_addr);
-    println!("Entrypoint: {:#X?}", bootloader.header.entry_point);
+    println!("Firmware Load address: {:#X?}", firmware.header().load_addr());
+    println!("Entrypoint: {:#X?}", bootloader.header().entry_point());
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = args.first().map(String::as_str).unwrap_or("extract");
+    let options = Options(args.get(1..).unwrap_or(&[]));
+
+    let result = match command {
+        "pjl" => cmd_pjl(&options),
+        "raster" => cmd_raster(&options),
+        "srec" => cmd_srec(&options),
+        "nand" => cmd_nand(&options),
+        "fw" => cmd_fw(&options),
+        "boot" => cmd_boot(&options),
+        "extract" => options.routines().and_then(|routines| extract(
+            options.input("./init_blob.bin"),
+            options.output("."),
+            options.has("--merge"),
+            options.has("--scan"),
+            &routines,
+        )),
+        "merge" => merge_dumps(options.input("./segments")).map(|_| ()),
+        "repack" => repack(
+            options.input("./init_blob.bin"),
+            options.output("./init_blob_repacked.bin"),
+            options.get(&["--firmware"]),
+            options.get(&["--segments"]),
+        ),
+        _ => {
+            print!("{}", USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("[!] {}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unpacker::{
+        pjl::{encode_job, RASTER_WIDTH},
+        srecord::{write_ascii, write_binary, SRecordType},
+    };
+
+    /// Address the test firmware is loaded to
+    const LOAD_ADDR: usize = 0x26710000;
+
+    /// Size of the test firmware, followed by three erased pages in flash
+    const LOAD_SIZE: usize = 0x3800;
+
+    /// Directory for the files of a test, removed before use
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("unpacker-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().into_owned()
+    }
 
+    /// Firmware holding an app header with empty bootloader lists
+    fn firmware_data() -> Vec<u8> {
+        let mut data: Vec<u8> = (0..LOAD_SIZE as u32).map(|i| (i * 7) as u8).collect();
+        let mut put = |offset: usize, value: usize| {
+            data[offset..offset + 4].copy_from_slice(&(value as u32).to_be_bytes());
+        };
+        put(0xffc, 0);
+        put(0x1000, 0x3ca55a3c);
+        put(0x1004, 104);
+        put(0x1000 + 52, LOAD_ADDR);
+        for field in (72..104).step_by(4) {
+            put(0x1000 + field, LOAD_ADDR + 0x2000);
+        }
+        data
+    }
+
+    /// Job carrying the firmware in binary records, with records that are not data in between
+    fn job() -> Vec<u8> {
+        let mut flash = vec![0xFF; PAGE_SIZE * 11];
+        for (offset, value) in [(0x0, 0xBAD2BFED), (0x8, 0x40), (0x10, PAGE_SIZE), (0x1c, 0),
+                                (0x30, LOAD_ADDR), (0x34, LOAD_SIZE), (0x3c, LOAD_ADDR)] {
+            flash[offset..offset + 4].copy_from_slice(&(value as u32).to_be_bytes());
+        }
+        flash[PAGE_SIZE..PAGE_SIZE + LOAD_SIZE].copy_from_slice(&firmware_data());
+
+        let mut nand = vec![];
+        for (index, page) in flash.chunks(PAGE_SIZE).enumerate() {
+            nand.extend(page);
+            nand.extend([0xFF, 0xFF]);
+            nand.extend((0..OOB_SIZE - 2).map(|i| (index + i) as u8));
+        }
+
+        let mut records = vec![SRecord::new(SRecordType::Zero, 0, b"flash").unwrap()];
+        for (index, chunk) in nand.chunks(0xF0).enumerate() {
+            if index == 10 {
+                records.push(SRecord::new(SRecordType::A, 0, b"HPVER1.0").unwrap());
+            }
+            records.push(SRecord::new(SRecordType::Three, index * 0xF0, chunk).unwrap());
+        }
+        records.push(SRecord::new(SRecordType::Seven, 0, &[]).unwrap());
+
+        let mut bitmap = write_ascii(&[SRecord::new(SRecordType::A, 0, b"HPVER1.0").unwrap(),
+                                       SRecord::new(SRecordType::Zero, 0, b"reflash").unwrap()]);
+        bitmap.extend(write_binary(&records));
+        bitmap.resize(bitmap.len().next_multiple_of(RASTER_WIDTH), 0);
+        encode_job(&bitmap)
+    }
+
+    /// Firmware carried by the job at `path`
+    fn job_firmware(path: &str) -> Firmware {
+        let bm = extract_bitmap(&parse_pjl(&std::fs::read(path).unwrap()).unwrap()).unwrap();
+        let data = strip_oob(&load_nand(&parse_srecords(&bm).unwrap()).unwrap());
+        let mut firmware = Firmware::new();
+        firmware.parse_header(&data).unwrap();
+        firmware.parse_data(&data).unwrap();
+        firmware
+    }
+
+    #[test]
+    fn repack_unchanged_is_identity() {
+        let dir = test_dir("repack");
+        let (input, output) = (format!("{}/job.bin", dir), format!("{}/out.bin", dir));
+        std::fs::write(&input, job()).unwrap();
+
+        repack(&input, &output, None, None).unwrap();
+        assert!(std::fs::read(&output).unwrap() == job());
+
+        // The unchanged firmware given explicitly gives the same job as well
+        let firmware = format!("{}/firmware", dir);
+        std::fs::write(&firmware, firmware_data()).unwrap();
+        repack(&input, &output, Some(&firmware), None).unwrap();
+        assert!(std::fs::read(&output).unwrap() == job());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn repack_grown_firmware_updates_header() {
+        let dir = test_dir("repack-grow");
+        let (input, output) = (format!("{}/job.bin", dir), format!("{}/out.bin", dir));
+        std::fs::write(&input, job()).unwrap();
+
+        // The firmware grows into the first erased page following it
+        let mut image = firmware_data();
+        image.extend([0x5A; 0x100]);
+        let firmware = format!("{}/firmware", dir);
+        std::fs::write(&firmware, &image).unwrap();
+        repack(&input, &output, Some(&firmware), None).unwrap();
+
+        let repacked = job_firmware(&output);
+        assert_eq!(repacked.header().load_size(), image.len());
+        assert_eq!(&repacked.data()[..image.len()], image);
+
+        // Growing past the erased pages would overwrite the end of the flash
+        image.resize(PAGE_SIZE * 11, 0x5A);
+        std::fs::write(&firmware, &image).unwrap();
+        assert!(matches!(repack(&input, &output, Some(&firmware), None),
+                         Err(Error::TooLarge { .. })));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn plan_merge_reports_issues() {
+        let dumps = [
+            (0x1500, 0x1600), (0x1000, 0x1100), (0x1100, 0x1200), (0x1180, 0x1190),
+            (0x1200, 0x1300), (0x1280, 0x1400), (0x1600, 0x1700), (0x90000000, 0x90000100),
+        ];
+        let (groups, issues) = plan_merge(&dumps);
+        assert_eq!(groups, [vec![0x1000, 0x1100, 0x1200], vec![0x1500, 0x1600]]);
+
+        // The dump far past the others is no gap
+        assert_eq!(issues, [
+            MergeIssue::Overlap {
+                start: 0x1180, end: 0x1190, other_start: 0x1000, other_end: 0x1200,
+            },
+            MergeIssue::Overlap {
+                start: 0x1280, end: 0x1400, other_start: 0x1000, other_end: 0x1300,
+            },
+            MergeIssue::Gap { start: 0x1400, end: 0x1500 },
+        ]);
+    }
+
+    #[test]
+    fn merge_dumps_in_dir() {
+        let dir = test_dir("merge");
+        for (start, data) in [(0x1000, &[1u8, 2][..]), (0x1002, &[3]), (0x1003, &[4, 5]),
+                              (0x2000, &[6])] {
+            std::fs::write(format!("{}/{:X}.dump", dir, start), data).unwrap();
+        }
+
+        assert_eq!(merge_dumps(&dir).unwrap(), [(0x1002, 0x1000), (0x1003, 0x1000)]);
+        let mut files: Vec<String> = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["1000.dump", "2000.dump"]);
+        assert_eq!(std::fs::read(format!("{}/1000.dump", dir)).unwrap(), [1, 2, 3, 4, 5]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
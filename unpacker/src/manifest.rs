@@ -0,0 +1,293 @@
+use crate::{bootloader::BootOp, elf::SymbolKind, sha256::sha256};
+
+/// Where the data of a region came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// The firmware image itself, mapped at its load address
+    Firmware,
+
+    /// Data copied by a bootloader memcpy
+    Memcpy,
+
+    /// Data uncompressed by the bootloader
+    Uncompress,
+
+    /// Memory filled by a bootloader memset
+    Memset,
+
+    /// Named segment of the segment table
+    SegmentTable,
+
+    /// Final state of memory once all bootloader operations are applied
+    Memory,
+}
+
+impl Origin {
+    /// Name of the origin as written to the manifest
+    pub fn name(&self) -> &'static str {
+        match self {
+            Origin::Firmware => "firmware",
+            Origin::Memcpy => "memcpy",
+            Origin::Uncompress => "uncompress",
+            Origin::Memset => "memset",
+            Origin::SegmentTable => "segment_table",
+            Origin::Memory => "memory",
+        }
+    }
+}
+
+impl From<&BootOp> for Origin {
+    fn from(op: &BootOp) -> Self {
+        match op {
+            BootOp::Memset { .. } => Origin::Memset,
+            BootOp::Memcpy { .. } => Origin::Memcpy,
+            BootOp::Uncompress { .. } => Origin::Uncompress,
+        }
+    }
+}
+
+/// Single region of memory emitted by the unpacker
+#[derive(Debug, Clone)]
+pub struct Region {
+    /// Name of the region, set for named segments
+    pub name: Option<String>,
+
+    /// Output file holding the data of the region, relative to the output directory. `None` if
+    /// the data of the region is not known
+    pub file: Option<String>,
+
+    /// Offset of the region within `file`
+    pub file_offset: usize,
+
+    /// Virtual address of the region
+    pub vaddr: usize,
+
+    /// Size of the region
+    pub size: usize,
+
+    /// Permissions of the region, combination of `PF_R`, `PF_W` and `PF_X`
+    pub perms: u32,
+
+    /// Where the data of the region came from
+    pub origin: Origin,
+
+    /// Offset of the data the region was built from within the firmware, if any
+    pub source_offset: Option<usize>,
+
+    /// SHA-256 of the data of the region, if it is known
+    pub sha256: Option<[u8; 32]>,
+
+    /// Raw flags of the segment table entry the region was built from, if any
+    pub flags: Option<usize>,
+
+    /// Set if the region comes from a guessed operation rather than a parsed table
+    pub heuristic: bool,
+}
+
+impl Region {
+    /// Create a region holding `data` that is stored at the start of `file`
+    pub fn new(file: &str, vaddr: usize, data: &[u8], perms: u32, origin: Origin) -> Self {
+        Self {
+            name: None,
+            file: Some(file.to_string()),
+            file_offset: 0,
+            vaddr,
+            size: data.len(),
+            perms,
+            origin,
+            source_offset: None,
+            sha256: Some(sha256(data)),
+            flags: None,
+            heuristic: false,
+        }
+    }
+}
+
+/// Named address of interest, such as the boot tables
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    /// Name of the symbol
+    pub name: String,
+
+    /// Address of the symbol
+    pub addr: usize,
+
+    /// Size of the object or function
+    pub size: usize,
+
+    /// Kind of the symbol
+    pub kind: SymbolKind,
+}
+
+/// Machine readable description of every region emitted by the unpacker
+#[derive(Debug, Default)]
+pub struct Manifest {
+    /// Address the firmware is loaded to
+    load_addr: usize,
+
+    /// Address execution starts at
+    entry: usize,
+
+    /// All emitted regions, in the order they were added
+    regions: Vec<Region>,
+
+    /// Symbols for known addresses
+    symbols: Vec<Symbol>,
+}
+
+impl Manifest {
+    /// Create new empty manifest
+    pub fn new(load_addr: usize, entry: usize) -> Self {
+        Self {
+            load_addr,
+            entry,
+            regions: Vec::new(),
+            symbols: Vec::new(),
+        }
+    }
+
+    /// Address the firmware is loaded to
+    pub fn load_addr(&self) -> usize {
+        self.load_addr
+    }
+
+    /// Address execution starts at
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// All regions of the manifest
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Mutable access to the regions, used to fix up file names once dumps are merged
+    pub fn regions_mut(&mut self) -> &mut [Region] {
+        &mut self.regions
+    }
+
+    /// Add a region to the manifest
+    pub fn add(&mut self, region: Region) {
+        self.regions.push(region);
+    }
+
+    /// Symbols for known addresses
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// Add a symbol to the manifest
+    pub fn add_symbol(&mut self, name: &str, addr: usize, size: usize, kind: SymbolKind) {
+        self.symbols.push(Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+            kind,
+        });
+    }
+
+    /// Serialize the manifest to JSON
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\n");
+        out += &format!("  \"load_addr\": {},\n", self.load_addr);
+        out += &format!("  \"entry\": {},\n", self.entry);
+        out += "  \"regions\": [";
+        for (i, region) in self.regions.iter().enumerate() {
+            let source_offset = region.source_offset.map_or("null".to_string(), |o| o.to_string());
+            let flags = region.flags.map_or("null".to_string(), |flags| flags.to_string());
+            let sha256 = region.sha256.map_or("null".to_string(), |digest| {
+                format!("\"{}\"", digest.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+            });
+
+            out += if i == 0 { "\n" } else { ",\n" };
+            out += "    {";
+            out += &format!("\"name\": {}, ", json_string(region.name.as_deref()));
+            out += &format!("\"file\": {}, ", json_string(region.file.as_deref()));
+            out += &format!("\"file_offset\": {}, ", region.file_offset);
+            out += &format!("\"vaddr\": {}, ", region.vaddr);
+            out += &format!("\"size\": {}, ", region.size);
+            out += &format!("\"perms\": {}, ", region.perms);
+            out += &format!("\"origin\": \"{}\", ", region.origin.name());
+            out += &format!("\"source_offset\": {}, ", source_offset);
+            out += &format!("\"sha256\": {}, ", sha256);
+            out += &format!("\"flags\": {}, ", flags);
+            out += &format!("\"heuristic\": {}", region.heuristic);
+            out += "}";
+        }
+        out += "\n  ],\n";
+        out += "  \"symbols\": [";
+        for (i, symbol) in self.symbols.iter().enumerate() {
+            let kind = match symbol.kind {
+                SymbolKind::Object => "object",
+                SymbolKind::Func => "func",
+            };
+
+            out += if i == 0 { "\n" } else { ",\n" };
+            out += "    {";
+            out += &format!("\"name\": {}, ", json_string(Some(&symbol.name)));
+            out += &format!("\"addr\": {}, ", symbol.addr);
+            out += &format!("\"size\": {}, ", symbol.size);
+            out += &format!("\"kind\": \"{}\"", kind);
+            out += "}";
+        }
+        out += "\n  ]\n}\n";
+        out
+    }
+}
+
+/// Encode an optional string as a JSON string or `null`
+fn json_string(value: Option<&str>) -> String {
+    let value = match value {
+        Some(value) => value,
+        None => return "null".to_string(),
+    };
+
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_escapes_strings() {
+        let mut manifest = Manifest::new(0x1000, 0x1040);
+        let mut region = Region::new("dir\\file \"1\"", 0x2000, b"abc", 6, Origin::SegmentTable);
+        region.name = Some("tab\tnew\nline\r\x01\x1f".to_string());
+        region.flags = Some(6);
+        manifest.add(region);
+        manifest.add_symbol("entry", 0x1040, 0, SymbolKind::Func);
+
+        assert_eq!(manifest.to_json(), concat!(
+            "{\n",
+            "  \"load_addr\": 4096,\n",
+            "  \"entry\": 4160,\n",
+            "  \"regions\": [\n",
+            "    {\"name\": \"tab\\tnew\\nline\\r\\u0001\\u001f\", ",
+            "\"file\": \"dir\\\\file \\\"1\\\"\", ",
+            "\"file_offset\": 0, \"vaddr\": 8192, \"size\": 3, ",
+            "\"perms\": 6, \"origin\": \"segment_table\", \"source_offset\": null, ",
+            "\"sha256\": \"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\", ",
+            "\"flags\": 6, \"heuristic\": false}\n",
+            "  ],\n",
+            "  \"symbols\": [\n",
+            "    {\"name\": \"entry\", \"addr\": 4160, \"size\": 0, \"kind\": \"func\"}\n",
+            "  ]\n",
+            "}\n",
+        ));
+        assert_eq!(json_string(None), "null");
+    }
+}
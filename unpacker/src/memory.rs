@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    bootloader::{BootLoader, BootOp},
+    error::Result,
+    firmware::Firmware,
+};
+
+/// Two bootloader operations writing to the same memory
+#[derive(Debug, Clone, Copy)]
+pub struct Overlap {
+    /// Start of the memory written by both operations
+    pub start: usize,
+
+    /// End (exclusive) of the memory written by both operations
+    pub end: usize,
+
+    /// Operation that wrote the memory first
+    pub first: BootOp,
+
+    /// Operation that overwrote the memory afterwards
+    pub second: BootOp,
+}
+
+/// Sparse image of the device memory, built up by applying the bootloader operations in the order
+/// the bootloader executes them
+#[derive(Debug, Default)]
+pub struct MemoryImage {
+    /// Contiguous regions of initialized memory, keyed by their start address. Regions never
+    /// overlap or touch, adjacent writes are merged into a single region
+    regions: BTreeMap<usize, Vec<u8>>,
+
+    /// Start, end and operation of every write applied so far
+    writes: Vec<(usize, usize, BootOp)>,
+
+    /// Writes that overlapped previously written memory
+    overlaps: Vec<Overlap>,
+
+    /// Operations that were skipped since they would overwrite a protected section
+    skipped: Vec<BootOp>,
+}
+
+impl MemoryImage {
+    /// Create new empty memory image
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply a single bootloader operation and return the data it wrote. Operations that would
+    /// overwrite a protected section are skipped and recorded, returning `None`
+    pub fn apply(&mut self, op: BootOp, bootloader: &BootLoader, firmware: &Firmware)
+            -> Result<Option<Vec<u8>>> {
+        let data = op.data(firmware)?;
+        let start = op.dst();
+        let end = start + data.len();
+
+        // Verify that this section is not going to be overwriting a protected segment
+        if bootloader.is_protected(start, end) {
+            self.skipped.push(op);
+            return Ok(None);
+        }
+
+        for &(prev_start, prev_end, prev_op) in &self.writes {
+            if prev_start < end && start < prev_end {
+                self.overlaps.push(Overlap {
+                    start: std::cmp::max(prev_start, start),
+                    end: std::cmp::min(prev_end, end),
+                    first: prev_op,
+                    second: op,
+                });
+            }
+        }
+        self.writes.push((start, end, op));
+        self.write(start, &data);
+        Ok(Some(data))
+    }
+
+    /// Write `data` to `addr`, merging it with all regions it overlaps or touches
+    pub fn write(&mut self, addr: usize, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = addr + data.len();
+
+        // Collect all regions that overlap or are directly adjacent to the new data
+        let touching: Vec<usize> = self.regions
+            .range(..=end)
+            .filter(|(&start, region)| start + region.len() >= addr)
+            .map(|(&start, _)| start)
+            .collect();
+
+        let merged_start = touching.first().map_or(addr, |&start| std::cmp::min(start, addr));
+        let mut merged_end = end;
+        let mut merged = Vec::new();
+        for start in &touching {
+            let region = self.regions.remove(start).unwrap_or_default();
+            merged_end = std::cmp::max(merged_end, start + region.len());
+            merged.resize(merged_end - merged_start, 0);
+            merged[start - merged_start..start - merged_start + region.len()]
+                .copy_from_slice(&region);
+        }
+        merged.resize(merged_end - merged_start, 0);
+        merged[addr - merged_start..end - merged_start].copy_from_slice(data);
+        self.regions.insert(merged_start, merged);
+    }
+
+    /// Read `len` bytes at `addr`, if all of them are initialized
+    pub fn read(&self, addr: usize, len: usize) -> Option<&[u8]> {
+        let (&start, region) = self.regions.range(..=addr).next_back()?;
+        region.get(addr - start..addr - start + len)
+    }
+
+    /// Coalesced regions of initialized memory as start address and contents, sorted by address
+    pub fn regions(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        self.regions.iter().map(|(&start, region)| (start, region.as_slice()))
+    }
+
+    /// Operations that wrote to memory within `start..end`, in the order they were applied
+    pub fn writes_in(&self, start: usize, end: usize) -> impl Iterator<Item = &BootOp> {
+        self.writes.iter()
+            .filter(move |&&(write_start, write_end, _)| write_start < end && start < write_end)
+            .map(|(_, _, op)| op)
+    }
+
+    /// Start, end and operation of every write applied so far, in the order they were applied
+    pub fn writes(&self) -> &[(usize, usize, BootOp)] {
+        &self.writes
+    }
+
+    /// Writes that overlapped memory written by an earlier operation
+    pub fn overlaps(&self) -> &[Overlap] {
+        &self.overlaps
+    }
+
+    /// Operations that were skipped since they would overwrite a protected section
+    pub fn skipped(&self) -> &[BootOp] {
+        &self.skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::Error, lzss::lzss_compress};
+
+    const LOAD_ADDR: usize = 0x26710000;
+
+    /// Data compressed into the firmware at offset 0x800
+    fn uncompressed() -> Vec<u8> {
+        (0..0x300u32).map(|i| (i / 7) as u8).collect()
+    }
+
+    /// Firmware holding compressed data and an app header with a protected range at 0x30000000
+    fn firmware() -> (Firmware, usize) {
+        let page_size = 0x800;
+        let mut flash = vec![0u8; page_size + 0x1000];
+        let mut put = |offset: usize, value: usize| {
+            flash[offset..offset + 4].copy_from_slice(&(value as u32).to_be_bytes());
+        };
+        put(0x0, 0xBAD2BFED);
+        put(0x10, page_size);
+        put(0x30, LOAD_ADDR);
+        put(0x34, 0x1000);
+        put(page_size + 0xfc, 1);
+        put(page_size + 0x100, 0x3ca55a3c);
+        put(page_size + 0x100 + 60, LOAD_ADDR + 0x200);
+        put(page_size + 0x200, 0x30000000);
+        put(page_size + 0x204, 0x30001000);
+        let compressed = lzss_compress(&uncompressed());
+        flash[page_size + 0x800..][..compressed.len()].copy_from_slice(&compressed);
+
+        let mut firmware = Firmware::new();
+        firmware.parse_header(&flash).unwrap();
+        firmware.parse_data(&flash).unwrap();
+        (firmware, compressed.len())
+    }
+
+    fn bootloader(firmware: &Firmware) -> BootLoader {
+        let mut bootloader = BootLoader::default();
+        bootloader.parse_header(firmware).unwrap();
+        bootloader.initialize_protected(firmware).unwrap();
+        bootloader
+    }
+
+    #[test]
+    fn apply_operations() {
+        let (firmware, compressed_len) = firmware();
+        let bootloader = bootloader(&firmware);
+        let mut image = MemoryImage::new();
+
+        let memset = BootOp::Memset { dst: 0x1000, val: 0xAA, len: 0x10 };
+        assert_eq!(image.apply(memset, &bootloader, &firmware).unwrap(), Some(vec![0xAA; 0x10]));
+        let memcpy = BootOp::Memcpy { dst: 0x1010, src: LOAD_ADDR + 0x100, len: 8 };
+        let copied = image.apply(memcpy, &bootloader, &firmware).unwrap().unwrap();
+        assert_eq!(copied, firmware.read(LOAD_ADDR + 0x100, 8).unwrap());
+        let uncompress = BootOp::Uncompress { dst: 0x2000, src: LOAD_ADDR + 0x800,
+                                              len: compressed_len };
+        assert_eq!(image.apply(uncompress, &bootloader, &firmware).unwrap(), Some(uncompressed()));
+
+        // The memset and the memcpy following it form a single region
+        let regions: Vec<_> = image.regions().map(|(start, data)| (start, data.len())).collect();
+        assert_eq!(regions, [(0x1000, 0x18), (0x2000, 0x300)]);
+        assert_eq!(image.read(0x100c, 8).unwrap()[..4], [0xAA; 4]);
+        assert_eq!(image.read(0x2000, 0x300).unwrap(), uncompressed());
+        assert_eq!(image.writes().len(), 3);
+        assert_eq!(image.writes_in(0x1008, 0x1012).collect::<Vec<_>>(), [&memset, &memcpy]);
+        assert!(image.overlaps().is_empty());
+    }
+
+    #[test]
+    fn overlapping_writes() {
+        let (firmware, _) = firmware();
+        let bootloader = bootloader(&firmware);
+        let mut image = MemoryImage::new();
+
+        let first = BootOp::Memset { dst: 0x1000, val: 1, len: 0x20 };
+        let second = BootOp::Memset { dst: 0x1010, val: 2, len: 0x20 };
+        let bridge = BootOp::Memset { dst: 0x1040, val: 3, len: 0x10 };
+        for op in [first, second, bridge] {
+            image.apply(op, &bootloader, &firmware).unwrap();
+        }
+        assert_eq!(image.overlaps().len(), 1);
+        let overlap = image.overlaps()[0];
+        assert_eq!((overlap.start, overlap.end, overlap.first, overlap.second),
+                   (0x1010, 0x1020, first, second));
+
+        // A write between two regions merges them, later writes win
+        image.write(0x1030, &[4; 0x10]);
+        let (start, data) = image.regions().next().unwrap();
+        assert_eq!((start, data.len(), image.regions().count()), (0x1000, 0x50, 1));
+        assert_eq!([data[0xf], data[0x10], data[0x30], data[0x40]], [1, 2, 4, 3]);
+    }
+
+    #[test]
+    fn out_of_range_addresses() {
+        let (firmware, _) = firmware();
+        let bootloader = bootloader(&firmware);
+        let mut image = MemoryImage::new();
+
+        // Sources outside of the firmware are an error
+        let memcpy = BootOp::Memcpy { dst: 0x1000, src: LOAD_ADDR + 0xff8, len: 0x10 };
+        assert!(matches!(image.apply(memcpy, &bootloader, &firmware),
+                         Err(Error::OutOfRange { .. })));
+        let uncompress = BootOp::Uncompress { dst: 0x1000, src: LOAD_ADDR - 4, len: 8 };
+        assert!(matches!(image.apply(uncompress, &bootloader, &firmware),
+                         Err(Error::OutOfRange { .. })));
+
+        // Writes to protected memory are skipped
+        let memset = BootOp::Memset { dst: 0x30000800, val: 0, len: 0x10 };
+        assert_eq!(image.apply(memset, &bootloader, &firmware).unwrap(), None);
+        assert_eq!(image.skipped(), [memset]);
+        assert_eq!(image.regions().count(), 0);
+
+        // Reads have to lie within initialized memory
+        image.write(0x1000, &[0; 0x10]);
+        assert!(image.read(0x1000, 0x10).is_some());
+        assert!(image.read(0x1008, 0x10).is_none());
+        assert!(image.read(0xff0, 4).is_none());
+    }
+}
@@ -0,0 +1,475 @@
+use crate::{
+    ecc::{Bch, EccScheme},
+    error::{Error, Result},
+};
+
+/// Size of a single NAND page stored in the binary records
+pub const PAGE_SIZE: usize = 0x800;
+
+/// Size of the out-of-band (spare) area following every NAND page
+pub const OOB_SIZE: usize = 0x40;
+
+/// Number of pages in an erase block
+pub const PAGES_PER_BLOCK: usize = 64;
+
+/// Bytes at the start of the spare area holding the bad block marker
+const BAD_BLOCK_MARKER_SIZE: usize = 2;
+
+/// Step of the smallest known ECC, a Hamming code over 512 bytes
+const MIN_ECC_STEP: usize = 512;
+
+/// ECC bytes per step of the smallest known ECC
+const MIN_ECC_BYTES: usize = 3;
+
+/// Number of programmed pages sampled when detecting the ECC layout
+const DETECT_SAMPLES: usize = 16;
+
+/// Layout of the NAND flash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// Size of the data area of a page
+    page_size: usize,
+
+    /// Size of the spare area following every page
+    oob_size: usize,
+
+    /// Number of pages in an erase block
+    pages_per_block: usize,
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Self {
+            page_size: PAGE_SIZE,
+            oob_size: OOB_SIZE,
+            pages_per_block: PAGES_PER_BLOCK,
+        }
+    }
+}
+
+impl Geometry {
+    /// Create a new geometry. The spare area has to hold the bad block marker and the smallest
+    /// ECC of a page
+    pub fn new(page_size: usize, oob_size: usize, pages_per_block: usize) -> Result<Self> {
+        if page_size == 0 || pages_per_block == 0 {
+            return Err(Error::InvalidConfig("page size and pages per block must not be 0"));
+        }
+        if oob_size < BAD_BLOCK_MARKER_SIZE + page_size.div_ceil(MIN_ECC_STEP) * MIN_ECC_BYTES {
+            return Err(Error::InvalidConfig("spare area is too small to hold the ECC of a page"));
+        }
+        Ok(Self { page_size, oob_size, pages_per_block })
+    }
+
+    /// Size of the data area of a page
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Size of the spare area following every page
+    pub fn oob_size(&self) -> usize {
+        self.oob_size
+    }
+
+    /// Number of pages in an erase block
+    pub fn pages_per_block(&self) -> usize {
+        self.pages_per_block
+    }
+
+    /// Size of a page including its spare area, as stored in a raw image
+    pub fn raw_page_size(&self) -> usize {
+        self.page_size + self.oob_size
+    }
+}
+
+/// Where the ECC is stored within the spare area and how it is computed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EccLayout {
+    /// Code protecting every step of a page
+    scheme: EccScheme,
+
+    /// Offset into the spare area at which the ECC of all steps is stored back to back
+    offset: usize,
+}
+
+impl EccLayout {
+    /// Create a new layout
+    pub fn new(scheme: EccScheme, offset: usize) -> Self {
+        Self { scheme, offset }
+    }
+
+    /// Code protecting every step of a page
+    pub fn scheme(&self) -> &EccScheme {
+        &self.scheme
+    }
+
+    /// Offset into the spare area at which the ECC is stored
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Range of the ECC within the spare area of a page of `page_size` bytes
+    fn range(&self, page_size: usize) -> std::ops::Range<usize> {
+        let len = page_size / self.scheme.step() * self.scheme.ecc_bytes();
+        self.offset..self.offset + len
+    }
+
+    /// Calculate the ECC of all steps of a page
+    pub fn calculate(&self, page: &[u8]) -> Vec<u8> {
+        page.chunks(self.scheme.step())
+            .flat_map(|step| self.scheme.calculate(step))
+            .collect()
+    }
+}
+
+/// Result of checking a page against its ECC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageStatus {
+    /// Page and spare area are erased, there is no ECC to check
+    Erased,
+
+    /// Page matches its ECC
+    Ok,
+
+    /// Page contained the given number of bit errors, which were corrected
+    Corrected(usize),
+
+    /// Page contains more errors than the ECC can correct
+    Uncorrectable,
+}
+
+/// Single page of a raw NAND image
+#[derive(Debug, Clone, Copy)]
+pub struct Page<'a> {
+    /// Index of the page within the image
+    index: usize,
+
+    /// Data area of the page
+    data: &'a [u8],
+
+    /// Spare area of the page
+    oob: &'a [u8],
+}
+
+impl<'a> Page<'a> {
+    /// Index of the page within the image
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Data area of the page
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Spare area of the page
+    pub fn oob(&self) -> &'a [u8] {
+        self.oob
+    }
+
+    /// Whether the bad block marker, the first byte of the spare area, is set
+    pub fn bad_block_marker(&self) -> bool {
+        self.oob.first().is_some_and(|&marker| marker != 0xFF)
+    }
+
+    /// Whether the page and its spare area are erased
+    pub fn is_erased(&self) -> bool {
+        self.data.iter().chain(self.oob).all(|&b| b == 0xFF)
+    }
+
+    /// ECC bytes stored in the spare area for the given layout
+    pub fn ecc(&self, layout: &EccLayout) -> Option<&'a [u8]> {
+        self.oob.get(layout.range(self.data.len()))
+    }
+}
+
+/// Raw NAND image made up of pages each followed by their spare area
+#[derive(Debug, Default)]
+pub struct Nand {
+    /// Layout of the flash
+    geometry: Geometry,
+
+    /// Pages interleaved with their spare areas
+    raw: Vec<u8>,
+}
+
+impl Nand {
+    /// Create a NAND image from raw pages and spare areas
+    pub fn new(raw: Vec<u8>, geometry: Geometry) -> Self {
+        Self { geometry, raw }
+    }
+
+    /// Layout of the flash
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    /// Raw pages interleaved with their spare areas
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Take the raw pages interleaved with their spare areas
+    pub fn into_raw(self) -> Vec<u8> {
+        self.raw
+    }
+
+    /// Number of pages, including a trailing partial page
+    pub fn page_count(&self) -> usize {
+        self.raw.len().div_ceil(self.geometry.raw_page_size())
+    }
+
+    /// Page at `index`
+    pub fn page(&self, index: usize) -> Option<Page<'_>> {
+        let raw_page_size = self.geometry.raw_page_size();
+        let start = index.checked_mul(raw_page_size).filter(|&start| start < self.raw.len())?;
+        let raw = &self.raw[start..std::cmp::min(start + raw_page_size, self.raw.len())];
+        let split = std::cmp::min(self.geometry.page_size, raw.len());
+        Some(Page { index, data: &raw[..split], oob: &raw[split..] })
+    }
+
+    /// All pages of the image
+    pub fn pages(&self) -> impl Iterator<Item = Page<'_>> {
+        (0..self.page_count()).filter_map(|index| self.page(index))
+    }
+
+    /// Data areas of all pages, without the spare areas
+    pub fn data(&self) -> Vec<u8> {
+        self.pages().flat_map(|page| page.data().to_vec()).collect()
+    }
+
+    /// Indices of the blocks marked bad in the spare area of their first or second page
+    pub fn bad_blocks(&self) -> Vec<usize> {
+        let pages_per_block = self.geometry.pages_per_block;
+        (0..self.page_count().div_ceil(pages_per_block))
+            .filter(|block| {
+                (0..2).filter_map(|i| self.page(block * pages_per_block + i))
+                    .any(|page| page.bad_block_marker())
+            })
+            .collect()
+    }
+
+    /// Detect the ECC scheme and its location within the spare area by trying the known schemes
+    /// on a sample of programmed pages. Returns `None` if no scheme matches most of the sample
+    pub fn detect_ecc(&self) -> Option<EccLayout> {
+        let samples: Vec<Page> = self.pages()
+            .filter(|page| page.oob.len() == self.geometry.oob_size && !page.is_erased())
+            .take(DETECT_SAMPLES)
+            .collect();
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut candidates = Vec::new();
+        for step in [256, 512] {
+            for swapped in [true, false] {
+                candidates.push(EccScheme::Hamming { step, swapped });
+            }
+        }
+        for t in [4, 8] {
+            for masked in [true, false] {
+                candidates.extend(Bch::new(512, t, masked).map(EccScheme::Bch));
+            }
+        }
+
+        let page_size = self.geometry.page_size;
+        for scheme in candidates {
+            if !page_size.is_multiple_of(scheme.step()) {
+                continue;
+            }
+            let len = page_size / scheme.step() * scheme.ecc_bytes();
+            if len > self.geometry.oob_size {
+                continue;
+            }
+
+            // Count for every offset how many sampled pages store the ECC there
+            let mut matches = vec![0; self.geometry.oob_size - len + 1];
+            let layout = EccLayout::new(scheme, 0);
+            for page in &samples {
+                let ecc = layout.calculate(page.data);
+                for (offset, count) in matches.iter_mut().enumerate() {
+                    if page.oob[offset..offset + len] == ecc[..] {
+                        *count += 1;
+                    }
+                }
+            }
+            let best = matches.iter().enumerate().max_by_key(|&(offset, &count)| (count, !offset));
+            if let Some((offset, &count)) = best {
+                if count * 2 > samples.len() {
+                    return Some(EccLayout::new(layout.scheme, offset));
+                }
+            }
+        }
+        None
+    }
+
+    /// Check every page against its ECC, correcting the pages and their ECC in place
+    pub fn correct(&mut self, layout: &EccLayout) -> Vec<(usize, PageStatus)> {
+        let page_size = self.geometry.page_size;
+        let step = layout.scheme.step();
+        let ecc_bytes = layout.scheme.ecc_bytes();
+        let range = layout.range(page_size);
+
+        let mut report = Vec::new();
+        for (index, raw) in self.raw.chunks_mut(self.geometry.raw_page_size()).enumerate() {
+            if raw.len() < page_size + range.end {
+                continue;
+            }
+            if raw.iter().all(|&b| b == 0xFF) {
+                report.push((index, PageStatus::Erased));
+                continue;
+            }
+
+            let (data, oob) = raw.split_at_mut(page_size);
+            let ecc = &mut oob[range.clone()];
+            let mut status = PageStatus::Ok;
+            for (data, ecc) in data.chunks_mut(step).zip(ecc.chunks_mut(ecc_bytes)) {
+                status = match (status, layout.scheme.correct(data, ecc)) {
+                    (_, None) | (PageStatus::Uncorrectable, _) => PageStatus::Uncorrectable,
+                    (PageStatus::Corrected(prev), Some(bits)) => PageStatus::Corrected(prev + bits),
+                    (_, Some(0)) => PageStatus::Ok,
+                    (_, Some(bits)) => PageStatus::Corrected(bits),
+                };
+            }
+            report.push((index, status));
+        }
+        report
+    }
+
+    /// Build a raw image holding `data`, using this image as the original. Unchanged pages keep
+    /// their original spare area byte for byte. Modified pages keep the bytes of the original
+    /// spare area outside the ECC, such as the bad block marker, and get a freshly calculated ECC.
+    /// Without an ECC layout modified pages get an erased (0xFF) spare area instead
+    pub fn encode(&self, data: &[u8], layout: Option<&EccLayout>) -> Nand {
+        let page_size = self.geometry.page_size;
+        let mut raw = Vec::new();
+        for (index, chunk) in data.chunks(page_size).enumerate() {
+            let mut page = chunk.to_vec();
+            page.resize(page_size, 0xFF);
+
+            let original = self.page(index)
+                .filter(|original| original.oob.len() == self.geometry.oob_size);
+            let unchanged = original.is_some_and(|original| original.data == &page[..]);
+            let mut oob = match original {
+                Some(original) if unchanged || layout.is_some() => original.oob.to_vec(),
+                _ => vec![0xFF; self.geometry.oob_size],
+            };
+
+            // Erased pages keep an erased ECC, so they are still recognized as erased
+            if let Some(layout) = layout.filter(|_| !unchanged) {
+                let range = layout.range(page_size);
+                if let Some(ecc) = oob.get_mut(range) {
+                    if page.iter().all(|&b| b == 0xFF) {
+                        ecc.fill(0xFF);
+                    } else {
+                        ecc.copy_from_slice(&layout.calculate(&page));
+                    }
+                }
+            }
+            raw.extend(page);
+            raw.extend(oob);
+        }
+        Nand::new(raw, self.geometry)
+    }
+
+    /// Check every page against its ECC without modifying the image
+    pub fn verify(&self, layout: &EccLayout) -> Vec<(usize, PageStatus)> {
+        Nand::new(self.raw.clone(), self.geometry).correct(layout)
+    }
+}
+
+/// Remove the OOB data following every page of a raw flash image
+pub fn strip_oob(raw: &[u8]) -> Vec<u8> {
+    Nand::new(raw.to_vec(), Geometry::default()).data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flash data of `pages` pages, the last page is left erased
+    fn flash_data(pages: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491u32;
+        let mut data: Vec<u8> = (0..(pages - 1) * PAGE_SIZE).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+        data.resize(pages * PAGE_SIZE, 0xFF);
+        data
+    }
+
+    /// Layouts of every known scheme, placed after the bad block marker
+    fn layouts() -> Vec<EccLayout> {
+        vec![
+            EccLayout::new(EccScheme::Hamming { step: 256, swapped: true }, 40),
+            EccLayout::new(EccScheme::Hamming { step: 512, swapped: false }, 2),
+            EccLayout::new(EccScheme::Bch(Bch::new(512, 4, true).unwrap()), 36),
+            EccLayout::new(EccScheme::Bch(Bch::new(512, 8, false).unwrap()), 12),
+        ]
+    }
+
+    #[test]
+    fn detect_and_verify_encoded_image() {
+        let data = flash_data(8);
+        for layout in layouts() {
+            let nand = Nand::default().encode(&data, Some(&layout));
+            assert_eq!(nand.data(), data);
+            assert_eq!(nand.detect_ecc(), Some(layout.clone()));
+
+            let report = nand.verify(&layout);
+            assert_eq!(report.len(), 8);
+            assert!(report[..7].iter().all(|&(_, status)| status == PageStatus::Ok));
+            assert_eq!(report[7], (7, PageStatus::Erased));
+        }
+    }
+
+    #[test]
+    fn correct_encoded_image() {
+        let data = flash_data(4);
+        let layout = &layouts()[3];
+        let mut raw = Nand::default().encode(&data, Some(layout)).into_raw();
+        let raw_page_size = PAGE_SIZE + OOB_SIZE;
+        raw[5] ^= 0x01;
+        raw[raw_page_size + 100] ^= 0x80;
+        raw[raw_page_size + 101] ^= 0x08;
+
+        let mut nand = Nand::new(raw, Geometry::default());
+        let report = nand.correct(layout);
+        assert_eq!(report[0], (0, PageStatus::Corrected(1)));
+        assert_eq!(report[1], (1, PageStatus::Corrected(2)));
+        assert_eq!(report[2], (2, PageStatus::Ok));
+        assert_eq!(nand.data(), data);
+    }
+
+    #[test]
+    fn unchanged_pages_keep_their_spare_area() {
+        let data = flash_data(4);
+        let layout = &layouts()[0];
+        let original = Nand::default().encode(&data, Some(layout));
+        let raw_page_size = PAGE_SIZE + OOB_SIZE;
+        let mut raw = original.raw().to_vec();
+        raw[PAGE_SIZE + 20] = 0x5A;
+        raw[raw_page_size + PAGE_SIZE + 20] = 0x5A;
+        let original = Nand::new(raw, Geometry::default());
+
+        let mut modified = data.clone();
+        modified[PAGE_SIZE + 3] ^= 0xFF;
+        let nand = original.encode(&modified, Some(layout));
+        assert_eq!(nand.raw()[..raw_page_size], original.raw()[..raw_page_size]);
+        // Spare area bytes outside the ECC of modified pages are carried over as they are
+        assert_eq!(nand.raw()[raw_page_size + PAGE_SIZE + 20], 0x5A);
+        assert_eq!(nand.verify(layout)[1], (1, PageStatus::Ok));
+        assert_eq!(nand.data(), modified);
+    }
+
+    #[test]
+    fn geometry_needs_room_for_ecc() {
+        assert!(Geometry::new(PAGE_SIZE, OOB_SIZE, PAGES_PER_BLOCK).is_ok());
+        assert!(Geometry::new(PAGE_SIZE, 14, PAGES_PER_BLOCK).is_ok());
+        assert!(Geometry::new(PAGE_SIZE, 13, PAGES_PER_BLOCK).is_err());
+        assert!(Geometry::new(PAGE_SIZE, 0, PAGES_PER_BLOCK).is_err());
+        assert!(Geometry::new(512, 16, 32).is_ok());
+        assert!(Geometry::new(0, OOB_SIZE, PAGES_PER_BLOCK).is_err());
+    }
+}
@@ -1,4 +1,17 @@
-use crate::hex_to_ascii;
+use crate::{
+    bytes_to_int_be,
+    ccitt::{decode_row, Coding},
+    error::{Error, Result},
+};
+
+/// Width of a single raster row in bytes
+pub const RASTER_WIDTH: usize = 16384;
+
+/// Universal exit language command that starts every job and precedes the PJL statements
+const UEL: &[u8] = b"%-12345X";
+
+/// Prefix of every PJL statement
+const PJL_PREFIX: &[u8] = b"@PJL";
 
 /// Various parameter types that can be passed to pjl commands
 #[derive(Clone, Debug)]
@@ -8,6 +21,9 @@ pub enum Param {
     Param1(usize),
     Unknown(Vec<u8>),
     Msg(String),
+
+    /// Value field of a generic parameterized command and its parameter character in upper case
+    Value { parameter: u8, value: String },
 }
 
 /// Different types of valid PJL Commands
@@ -24,203 +40,982 @@ pub enum Command {
 
     /// Initialize the dictionary for the sliding window
     AsteriskR(u8),
+
+    /// Known two character escape sequence other than the reset, such as `ESC 9`
+    Escape(u8),
+
+    /// Known parameterized escape sequence, such as `ESC &l1O`, identified by its parameterized,
+    /// group and terminating characters
+    Parameterized { parameterized: u8, group: Option<u8>, terminator: u8 },
+
+    /// Unknown escape sequence or text between escape sequences, kept as `Param::Unknown`
+    Raw,
 }
 
 /// A given printer job language command
-#[derive(Debug)]
 pub struct PJLCommand {
     command: Command,
     params: Vec<Param>,
-    _offset: usize,
+
+    /// Offset of the command within the job
+    offset: usize,
+
+    /// Exact bytes of the command as it was parsed, including any data or text following it
+    raw: Vec<u8>,
+
+    /// Whether the command was created or changed after parsing, so it has to be encoded again
+    edited: bool,
+}
+
+impl std::fmt::Debug for PJLCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PJLCommand")
+            .field("command", &self.command)
+            .field("params", &self.params)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+impl PJLCommand {
+    /// Create a new command, which is encoded from its parameters when written out
+    pub fn new(command: Command, params: Vec<Param>) -> Self {
+        Self { command, params, offset: 0, raw: Vec::new(), edited: true }
+    }
+
+    /// Kind of the command
+    pub fn command(&self) -> &Command {
+        &self.command
+    }
+
+    /// Parameters of the command, including any data or text following it
+    pub fn params(&self) -> &[Param] {
+        &self.params
+    }
+
+    /// Mutable access to the parameters. The command is encoded from its parameters from then on
+    /// instead of being written out as it was parsed
+    pub fn params_mut(&mut self) -> &mut Vec<Param> {
+        self.edited = true;
+        &mut self.params
+    }
+
+    /// Offset of the command within the job
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Range of the job the command was parsed from
+    pub fn span(&self) -> std::ops::Range<usize> {
+        self.offset..self.offset + self.raw.len()
+    }
+
+    /// Exact bytes the command was parsed from, empty for commands created with `new`
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Serialize the command. Commands that were not changed are written out exactly as they were
+    /// parsed, all others are encoded from their parameters
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if !self.edited {
+            return self.raw.clone();
+        }
+
+        // Commands followed by text, which includes the UEL itself
+        let prefix: &[u8] = match self.command {
+            Command::UEL => b"\x1b",
+            Command::E => b"\x1bE",
+            _ => b"",
+        };
+        let mut out = prefix.to_vec();
+        let (parameterized, group, terminator) = match self.command {
+            Command::UEL | Command::E | Command::Raw => {
+                for param in &self.params {
+                    match param {
+                        Param::Msg(msg) => out.extend(msg.as_bytes()),
+                        Param::Unknown(bytes) | Param::Data(bytes) => out.extend(bytes),
+                        _ => {}
+                    }
+                }
+                return out;
+            }
+            Command::Escape(c) => return vec![0x1B, c],
+            Command::AsteriskB(terminator) => (b'*', Some(b'b'), terminator),
+            Command::AsteriskR(terminator) => (b'*', Some(b'r'), terminator),
+            Command::Parameterized { parameterized, group, terminator } => {
+                (parameterized, group, terminator)
+            }
+        };
+
+        out.extend([0x1B, parameterized]);
+        out.extend(group);
+
+        // Every parameter but the last one is combined with the next, using a lower case character
+        let last = self.params.iter()
+            .rposition(|param| !matches!(param, Param::Data(_) | Param::Unknown(_) | Param::Msg(_)));
+        for (i, param) in self.params.iter().enumerate() {
+            let case = |c: u8| if Some(i) == last { c } else { c.to_ascii_lowercase() };
+            match param {
+                Param::Compression(mode) => {
+                    out.extend(mode.to_string().as_bytes());
+                    out.push(case(b'M'));
+                }
+                Param::Param1(value) => {
+                    out.extend(value.to_string().as_bytes());
+                    out.push(terminator);
+                }
+                Param::Value { parameter, value } => {
+                    out.extend(value.as_bytes());
+                    out.push(case(*parameter));
+                }
+                Param::Data(data) => out.extend(data),
+                Param::Unknown(_) | Param::Msg(_) => {}
+            }
+        }
+        if last.is_none() {
+            out.push(terminator);
+        }
+        out
+    }
+}
+
+/// Serialize all commands of a job. A parsed job is reproduced exactly, apart from the commands
+/// that were changed
+pub fn write_pjl(pjls: &[PJLCommand]) -> Vec<u8> {
+    pjls.iter().flat_map(PJLCommand::to_bytes).collect()
+}
+
+/// Known two character escape sequences, as `(character, name)`
+const PCL_ESCAPES: &[(u8, &str)] = &[
+    (b'E', "Reset"), (b'9', "Clear horizontal margins"), (b'=', "Half line feed"),
+    (b'Y', "Display functions on"), (b'Z', "Display functions off"), (b'z', "Self test"),
+];
+
+/// Known parameterized escape sequences, as `(parameterized character, group character, parameter
+/// character, name, whether `value` bytes of data follow)`
+const PCL_COMMANDS: &[(u8, Option<u8>, u8, &str, bool)] = &[
+    (b'%', None, b'X', "Universal exit language", false),
+    (b'%', None, b'A', "Enter PCL mode", false),
+    (b'%', None, b'B', "Enter HP-GL/2 mode", false),
+    (b'&', Some(b'l'), b'A', "Page size", false),
+    (b'&', Some(b'l'), b'C', "Vertical motion index", false),
+    (b'&', Some(b'l'), b'D', "Line spacing", false),
+    (b'&', Some(b'l'), b'E', "Top margin", false),
+    (b'&', Some(b'l'), b'F', "Text length", false),
+    (b'&', Some(b'l'), b'G', "Output bin", false),
+    (b'&', Some(b'l'), b'H', "Paper source", false),
+    (b'&', Some(b'l'), b'L', "Perforation skip", false),
+    (b'&', Some(b'l'), b'M', "Media type", false),
+    (b'&', Some(b'l'), b'O', "Orientation", false),
+    (b'&', Some(b'l'), b'P', "Page length", false),
+    (b'&', Some(b'l'), b'S', "Simplex/duplex print", false),
+    (b'&', Some(b'l'), b'U', "Left offset registration", false),
+    (b'&', Some(b'l'), b'X', "Number of copies", false),
+    (b'&', Some(b'l'), b'Z', "Top offset registration", false),
+    (b'&', Some(b'a'), b'C', "Horizontal cursor position (columns)", false),
+    (b'&', Some(b'a'), b'G', "Duplex page side selection", false),
+    (b'&', Some(b'a'), b'H', "Horizontal cursor position (decipoints)", false),
+    (b'&', Some(b'a'), b'L', "Left margin", false),
+    (b'&', Some(b'a'), b'M', "Right margin", false),
+    (b'&', Some(b'a'), b'P', "Print direction", false),
+    (b'&', Some(b'a'), b'R', "Vertical cursor position (rows)", false),
+    (b'&', Some(b'a'), b'V', "Vertical cursor position (decipoints)", false),
+    (b'&', Some(b'b'), b'W', "Configuration (I/O)", true),
+    (b'&', Some(b'f'), b'S', "Push/pop cursor position", false),
+    (b'&', Some(b'f'), b'X', "Macro control", false),
+    (b'&', Some(b'f'), b'Y', "Macro ID", false),
+    (b'&', Some(b'k'), b'G', "Line termination", false),
+    (b'&', Some(b'k'), b'H', "Horizontal motion index", false),
+    (b'&', Some(b'p'), b'X', "Transparent print data", true),
+    (b'&', Some(b's'), b'C', "End-of-line wrap", false),
+    (b'&', Some(b'u'), b'D', "Unit of measure", false),
+    (b'*', Some(b'b'), b'M', "Compression method", false),
+    (b'*', Some(b'b'), b'V', "Transfer raster data by plane", true),
+    (b'*', Some(b'b'), b'W', "Transfer raster data by row", true),
+    (b'*', Some(b'b'), b'Y', "Raster Y offset", false),
+    (b'*', Some(b'c'), b'A', "Horizontal rectangle size (dots)", false),
+    (b'*', Some(b'c'), b'B', "Vertical rectangle size (dots)", false),
+    (b'*', Some(b'c'), b'D', "Font ID", false),
+    (b'*', Some(b'c'), b'E', "Character code", false),
+    (b'*', Some(b'c'), b'F', "Font control", false),
+    (b'*', Some(b'c'), b'G', "Pattern ID", false),
+    (b'*', Some(b'c'), b'H', "Horizontal rectangle size (decipoints)", false),
+    (b'*', Some(b'c'), b'P', "Fill rectangular area", false),
+    (b'*', Some(b'c'), b'Q', "Pattern control", false),
+    (b'*', Some(b'c'), b'V', "Vertical rectangle size (decipoints)", false),
+    (b'*', Some(b'c'), b'W', "User defined pattern", true),
+    (b'*', Some(b'g'), b'W', "Configure raster data", true),
+    (b'*', Some(b'i'), b'W', "Viewing illuminant", true),
+    (b'*', Some(b'l'), b'O', "Logical operation", false),
+    (b'*', Some(b'l'), b'R', "Pixel placement", false),
+    (b'*', Some(b'm'), b'W', "Download dither matrix", true),
+    (b'*', Some(b'o'), b'M', "Print quality", false),
+    (b'*', Some(b'o'), b'W', "Driver configuration", true),
+    (b'*', Some(b'p'), b'P', "Push/pop palette", false),
+    (b'*', Some(b'p'), b'R', "Pattern reference point", false),
+    (b'*', Some(b'p'), b'X', "Horizontal cursor position (dots)", false),
+    (b'*', Some(b'p'), b'Y', "Vertical cursor position (dots)", false),
+    (b'*', Some(b'r'), b'A', "Start raster graphics", false),
+    (b'*', Some(b'r'), b'B', "End raster graphics (old)", false),
+    (b'*', Some(b'r'), b'C', "End raster graphics", false),
+    (b'*', Some(b'r'), b'F', "Raster presentation mode", false),
+    (b'*', Some(b'r'), b'S', "Source raster width", false),
+    (b'*', Some(b'r'), b'T', "Source raster height", false),
+    (b'*', Some(b'r'), b'U', "Simple color", false),
+    (b'*', Some(b't'), b'H', "Destination raster width", false),
+    (b'*', Some(b't'), b'I', "Gamma correction", false),
+    (b'*', Some(b't'), b'J', "Render algorithm", false),
+    (b'*', Some(b't'), b'R', "Raster resolution", false),
+    (b'*', Some(b't'), b'V', "Destination raster height", false),
+    (b'*', Some(b'v'), b'A', "Color component one", false),
+    (b'*', Some(b'v'), b'B', "Color component two", false),
+    (b'*', Some(b'v'), b'C', "Color component three", false),
+    (b'*', Some(b'v'), b'I', "Assign color index", false),
+    (b'*', Some(b'v'), b'N', "Source transparency mode", false),
+    (b'*', Some(b'v'), b'O', "Pattern transparency mode", false),
+    (b'*', Some(b'v'), b'S', "Foreground color", false),
+    (b'*', Some(b'v'), b'T', "Current pattern", false),
+    (b'*', Some(b'v'), b'W', "Configure image data", true),
+    (b'(', None, b'@', "Primary default font", false),
+    (b'(', None, b'X', "Primary font ID", false),
+    (b'(', Some(b's'), b'B', "Primary stroke weight", false),
+    (b'(', Some(b's'), b'H', "Primary pitch", false),
+    (b'(', Some(b's'), b'P', "Primary spacing", false),
+    (b'(', Some(b's'), b'S', "Primary style", false),
+    (b'(', Some(b's'), b'T', "Primary typeface", false),
+    (b'(', Some(b's'), b'V', "Primary height", false),
+    (b'(', Some(b's'), b'W', "Character descriptor and data", true),
+    (b')', None, b'@', "Secondary default font", false),
+    (b')', None, b'X', "Secondary font ID", false),
+    (b')', Some(b's'), b'B', "Secondary stroke weight", false),
+    (b')', Some(b's'), b'H', "Secondary pitch", false),
+    (b')', Some(b's'), b'P', "Secondary spacing", false),
+    (b')', Some(b's'), b'S', "Secondary style", false),
+    (b')', Some(b's'), b'T', "Secondary typeface", false),
+    (b')', Some(b's'), b'V', "Secondary height", false),
+    (b')', Some(b's'), b'W', "Font header", true),
+];
+
+/// Look up a known parameterized command by its parameterized, group and parameter character,
+/// returning its name and whether data follows it. Parameter characters are matched ignoring case,
+/// as lower case marks a combined command that continues
+fn pcl_command(parameterized: u8, group: Option<u8>, parameter: u8) -> Option<(&'static str, bool)> {
+    let parameter = parameter.to_ascii_uppercase();
+    let known = PCL_COMMANDS.iter()
+        .find(|&&(p, g, c, _, _)| p == parameterized && g == group && c == parameter)
+        .map(|&(_, _, _, name, data)| (name, data));
+
+    // Symbol sets are selected with any upper case character as the terminator
+    match (parameterized, group) {
+        (b'(', None) if known.is_none() => Some(("Primary symbol set", false)),
+        (b')', None) if known.is_none() => Some(("Secondary symbol set", false)),
+        _ => known,
+    }
+}
+
+/// Name of a known PCL command. Two character escape sequences have no group and no parameter
+/// character
+pub fn pcl_command_name(parameterized: u8, group: Option<u8>, parameter: Option<u8>)
+        -> Option<&'static str> {
+    match parameter {
+        Some(parameter) => pcl_command(parameterized, group, parameter).map(|(name, _)| name),
+        None => PCL_ESCAPES.iter().find(|&&(c, _)| c == parameterized).map(|&(_, name)| name),
+    }
+}
+
+/// Single parameter of a parameterized escape sequence
+struct PCLParameter<'a> {
+    /// Value field, with an optional sign and fraction
+    value: &'a [u8],
+
+    /// Parameter character, upper case if it terminates the sequence
+    parameter: u8,
+
+    /// Data following the parameter, if the command takes any
+    data: Option<&'a [u8]>,
+}
+
+/// Parameterized escape sequence, split into its parameters
+struct PCLSequence<'a> {
+    /// Group character, if any
+    group: Option<u8>,
+
+    /// Parameters in the order they appear, the last one terminates the sequence
+    params: Vec<PCLParameter<'a>>,
+
+    /// Offset past the sequence and its data
+    end: usize,
+}
+
+/// Parse a parameterized escape sequence at `offset`, made up of the escape character, the
+/// parameterized character, an optional group character and any number of value fields each
+/// followed by a parameter character. Lower case parameter characters combine several commands,
+/// the first upper case one terminates the sequence. Returns `None` if the sequence does not follow
+/// the grammar or one of its commands is not known
+fn parse_parameterized(blob: &[u8], offset: usize) -> Result<Option<PCLSequence<'_>>> {
+    let parameterized = blob[offset + 1];
+    let mut index = offset + 2;
+    let group = match blob.get(index) {
+        Some(&c @ 0x60..=0x7E) => {
+            index += 1;
+            Some(c)
+        }
+        _ => None,
+    };
+
+    let mut params = vec![];
+    loop {
+        let start = index;
+        if matches!(blob.get(index), Some(b'+' | b'-')) {
+            index += 1;
+        }
+        while matches!(blob.get(index), Some(b'0'..=b'9' | b'.')) {
+            index += 1;
+        }
+        let value = &blob[start..index];
+        let parameter = match blob.get(index) {
+            Some(&c @ 0x40..=0x7E) => c,
+            _ => return Ok(None),
+        };
+        index += 1;
+
+        let Some((_, has_data)) = pcl_command(parameterized, group, parameter) else {
+            return Ok(None);
+        };
+        let data = if has_data {
+            let len = pcl_number(value).ok_or(Error::BadSyntax { offset })?;
+            let data = index.checked_add(len)
+                .and_then(|end| blob.get(index..end))
+                .ok_or(Error::Truncated { offset })?;
+            index += len;
+            Some(data)
+        } else {
+            None
+        };
+        params.push(PCLParameter { value, parameter, data });
+        if parameter.is_ascii_uppercase() || parameter == b'@' {
+            return Ok(Some(PCLSequence { group, params, end: index }));
+        }
+    }
+}
+
+/// Length of the unknown parameterized escape sequence at `offset`, up to and including its
+/// terminating character. Sequences that break the grammar before they are terminated end at the
+/// first offending byte, which is then kept as text
+fn unknown_sequence_len(blob: &[u8], offset: usize) -> usize {
+    let body = blob[offset + 2..]
+        .iter()
+        .take_while(|&&c| matches!(c, b'0'..=b'9' | b'+' | b'-' | b'.' | 0x60..=0x7E))
+        .count();
+    let end = offset + 2 + body;
+    match blob.get(end) {
+        Some(0x40..=0x5E) => end + 1 - offset,
+        _ => end - offset,
+    }
+}
+
+/// Integer part of a PCL value field, negative values are treated as 0. Returns `None` if the
+/// value does not fit into a `usize`
+fn pcl_number(value: &[u8]) -> Option<usize> {
+    value.strip_prefix(b"+").unwrap_or(value)
+        .iter()
+        .take_while(|c| c.is_ascii_digit())
+        .try_fold(0usize, |number, &c| number.checked_mul(10)?.checked_add((c - b'0') as usize))
 }
 
 /// Finds all the sections in the binary, and puts them together, removing the section meta-data, so
-/// we are left with a binary blob that we can then do further work on
-pub fn parse_pjl(blob: &Vec<u8>) -> Vec<PJLCommand> {
+/// we are left with a binary blob that we can then do further work on. Escape sequences that are not
+/// known and text between escape sequences are kept as `Command::Raw`
+pub fn parse_pjl(blob: &[u8]) -> Result<Vec<PJLCommand>> {
     let mut result = vec![];
     let mut index = 0;
 
     // Closure to find next newline in byte-array
     let find_next = |id: &[u8]| id.iter().position(|&c| c == 0x1B).unwrap_or(id.len());
 
-    loop {
+    while index < blob.len() {
         let offset = index;
-        //println!("Go to index {:X}", index);
-        // First element in command is <ESC>
-        if index >= blob.len() {
-            println!("Finished");
-            break;
-        }
+
+        // Closure to keep the next `len` bytes as raw bytes
+        let raw = |len: usize| {
+            PJLCommand::new(Command::Raw, vec![Param::Unknown(blob[offset..offset + len].to_vec())])
+        };
+
+        // Text between commands
         if blob[index] != 0x1B {
-            println!("PJL Command header mismatch");
-            break;
+            let len = find_next(&blob[index..]);
+            let mut parse = raw(len);
+            index += len;
+            parse.offset = offset;
+            parse.raw = blob[offset..index].to_vec();
+            parse.edited = false;
+            result.push(parse);
+            continue;
         }
-        index += 1;
-        let parse: PJLCommand = if blob[index] == b'%' {
-            if &blob[index..index + 8] == b"%-12345X" {
+
+        let lead = blob.get(index + 1).copied();
+        let mut parse: PJLCommand = match lead {
+            // Escape character at the very end
+            None => {
+                index += 1;
+                raw(1)
+            }
+            Some(b'%') if blob.get(index + 1..index + 1 + UEL.len()) == Some(UEL) => {
+                index += 1;
                 let endl = find_next(&blob[index..]);
-                let msg = String::from_utf8(blob[index..index + endl].to_vec())
-                    .expect("Message following command");
+                let msg = String::from_utf8_lossy(&blob[index..index + endl]).to_string();
                 index += endl;
-                PJLCommand {
-                    command: Command::UEL,
-                    params: vec![Param::Msg(msg)],
-                    _offset: offset,
-                }
-            } else {
-                println!("UEL mismatch at index {}", index);
-                break;
+                PJLCommand::new(Command::UEL, vec![Param::Msg(msg)])
             }
-        } else {
-            let cmd_len = 1 + blob[index..]
-                .iter()
-                .position(|&c| matches!(c, b'A'..=b'Z'))
-                .unwrap();
-            let cmdline = &blob[index..cmd_len + index];
-            let extra = &blob[cmd_len + index..];
-            index += cmd_len;
-            match cmdline[0] {
-                b'E' => {
-                    let endl = find_next(&blob[index..]);
-                    let msg = String::from_utf8(blob[index..index + endl].to_vec())
-                        .expect("Message following command");
-                    index += endl;
-                    PJLCommand {
-                        command: Command::E,
-                        params: vec![Param::Msg(msg)],
-                        _offset: offset,
-                    }
-                }
-                b'*' => {
-                    let cmd_name = cmdline[1];
-                    let method = cmdline[cmd_len - 1];
+            Some(b'E') => {
+                index += 2;
+                let endl = find_next(&blob[index..]);
+                let msg = String::from_utf8_lossy(&blob[index..index + endl]).to_string();
+                index += endl;
+                PJLCommand::new(Command::E, vec![Param::Msg(msg)])
+            }
+            // Two character escape sequence
+            Some(lead @ 0x30..=0x7E) if pcl_command_name(lead, None, None).is_some() => {
+                index += 2;
+                PJLCommand::new(Command::Escape(lead), vec![])
+            }
+            // Parameterized escape sequence
+            Some(lead @ 0x21..=0x2F) => match parse_parameterized(blob, offset)? {
+                Some(PCLSequence { group, params: parameters, end }) => {
+                    index = end;
+                    let terminator = parameters.last().map_or(0, |p| p.parameter);
                     let mut params = vec![];
-                    // Parse possible param
-                    for rest in cmdline[2..cmd_len - 1].split_inclusive(|x| x.is_ascii_lowercase())
-                    {
-                        let len = rest.len();
-                        match rest[len - 1] {
-                            b'm' => {
-                                // println!("{:X?}", &rest[..len - 1]);
-                                if len > 0 {
-                                    let compression_level = hex_to_ascii(&rest[..len - 1], 10).0;
-                                    params.push(Param::Compression(
-                                        compression_level.try_into().expect("u8 compression size"),
-                                    ));
-                                }
-                            }
-                            b'0'..=b'9' => {
-                                let length = hex_to_ascii(rest, 10).0;
-                                params.push(Param::Param1(length));
-                            }
-                            _ => {
-                                println!("Skipped parameter {:X?}", rest);
+                    let count = parameters.len();
+                    for (i, parameter) in parameters.into_iter().enumerate() {
+                        let upper = parameter.parameter.to_ascii_uppercase();
+                        let legacy = lead == b'*' && matches!(group, Some(b'b' | b'r'));
+
+                        // Raster commands keep the value of the terminating parameter as `Param1`
+                        if legacy && upper == b'M' {
+                            params.push(Param::Compression(
+                                pcl_number(parameter.value)
+                                    .and_then(|mode| mode.try_into().ok())
+                                    .ok_or(Error::UnknownCommand { offset })?,
+                            ));
+                        } else if legacy && i + 1 == count {
+                            if !parameter.value.is_empty() {
+                                params.push(Param::Param1(pcl_number(parameter.value)
+                                    .ok_or(Error::BadSyntax { offset })?));
                             }
+                        } else {
+                            params.push(Param::Value {
+                                parameter: upper,
+                                value: String::from_utf8_lossy(parameter.value).to_string(),
+                            });
+                        }
+                        if let Some(data) = parameter.data {
+                            params.push(Param::Data(data.to_vec()));
                         }
                     }
 
-                    match cmd_name {
-                        b'r' => {
-                            let command = Command::AsteriskR(method);
-                            PJLCommand {
-                                command,
-                                params,
-                                _offset: offset,
-                            }
+                    let command = match group {
+                        Some(b'b') if lead == b'*' => Command::AsteriskB(terminator),
+                        Some(b'r') if lead == b'*' => Command::AsteriskR(terminator),
+                        _ => Command::Parameterized { parameterized: lead, group, terminator },
+                    };
+                    PJLCommand::new(command, params)
+                }
+                None => {
+                    let len = unknown_sequence_len(blob, offset);
+                    index += len;
+                    raw(len)
+                }
+            },
+            // Unknown two character escape sequence
+            Some(0x30..=0x7E) => {
+                index += 2;
+                raw(2)
+            }
+            // Escape character followed by something that is not a sequence
+            Some(_) => {
+                index += 1;
+                raw(1)
+            }
+        };
+        parse.offset = offset;
+        parse.raw = blob[offset..index].to_vec();
+        parse.edited = false;
+        result.push(parse);
+    }
+
+    Ok(result)
+}
+
+/// Single token of a PJL statement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PJLToken {
+    /// `@PJL` prefix starting a statement
+    Prefix,
+
+    /// Command, modifier, option name or unquoted value
+    Word(String),
+
+    /// Quoted string value, without the quotes
+    Quoted(String),
+
+    /// `=` between an option and its value
+    Equals,
+
+    /// `:` between a command modifier and its value
+    Colon,
+
+    /// Free text following the `COMMENT` and `ECHO` commands
+    Text(String),
+
+    /// End of the statement
+    LineEnd,
+}
+
+/// Split PJL statements into tokens, each with its offset. Tokenizing stops at the first line that
+/// is not a PJL statement, which is where the data of the job starts
+pub fn tokenize_pjl(bytes: &[u8]) -> Result<Vec<(usize, PJLToken)>> {
+    let mut tokens = vec![];
+    let mut index = 0;
+
+    // Closure to check if a byte ends a word
+    let is_separator = |c: u8| matches!(c, b' ' | b'\t' | b'\r' | b'\n' | b'=' | b':' | b'"');
+
+    while index < bytes.len() {
+        let prefix = bytes.get(index..index + PJL_PREFIX.len());
+        if !prefix.is_some_and(|prefix| prefix.eq_ignore_ascii_case(PJL_PREFIX))
+                || bytes.get(index + PJL_PREFIX.len()).is_some_and(|&c| !is_separator(c)) {
+            break;
+        }
+        tokens.push((index, PJLToken::Prefix));
+        index += PJL_PREFIX.len();
+
+        let mut first_word = true;
+        loop {
+            while matches!(bytes.get(index), Some(b' ' | b'\t')) {
+                index += 1;
+            }
+            let offset = index;
+            match bytes.get(index) {
+                None => {
+                    tokens.push((offset, PJLToken::LineEnd));
+                    break;
+                }
+                Some(b'\n') => {
+                    tokens.push((offset, PJLToken::LineEnd));
+                    index += 1;
+                    break;
+                }
+                Some(b'\r') if bytes.get(index + 1) == Some(&b'\n') => {
+                    tokens.push((offset, PJLToken::LineEnd));
+                    index += 2;
+                    break;
+                }
+                Some(b'\r') => index += 1,
+                Some(b'=') => {
+                    tokens.push((offset, PJLToken::Equals));
+                    index += 1;
+                }
+                Some(b':') => {
+                    tokens.push((offset, PJLToken::Colon));
+                    index += 1;
+                }
+                Some(b'"') => {
+                    let len = bytes[index + 1..]
+                        .iter()
+                        .position(|&c| matches!(c, b'"' | b'\n'))
+                        .filter(|&len| bytes[index + 1 + len] == b'"')
+                        .ok_or(Error::BadSyntax { offset })?;
+                    let value = String::from_utf8_lossy(&bytes[index + 1..index + 1 + len]);
+                    tokens.push((offset, PJLToken::Quoted(value.to_string())));
+                    index += len + 2;
+                }
+                Some(_) => {
+                    let len = bytes[index..]
+                        .iter()
+                        .position(|&c| is_separator(c))
+                        .unwrap_or(bytes.len() - index);
+                    let word = String::from_utf8_lossy(&bytes[index..index + len]).to_string();
+                    index += len;
+
+                    // Comments and echoed text run until the end of the line
+                    let free_text = first_word && (word.eq_ignore_ascii_case("COMMENT")
+                        || word.eq_ignore_ascii_case("ECHO"));
+                    tokens.push((offset, PJLToken::Word(word)));
+                    first_word = false;
+                    if free_text {
+                        while matches!(bytes.get(index), Some(b' ' | b'\t')) {
+                            index += 1;
                         }
-                        b'b' => {
-                            let command = Command::AsteriskB(method);
-                            if let Some(&Param::Param1(read_length)) =
-                                params.iter().find(|p| matches!(p, Param::Param1(_)))
-                            {
-                                match method {
-                                    b'V' | b'W' => {
-                                        let stack = &extra[..read_length];
-                                        index += read_length;
-                                        params.push(Param::Data(stack.to_vec()));
-                                        PJLCommand {
-                                            command,
-                                            params,
-                                            _offset: offset,
-                                        }
-                                    }
-                                    _ => {
-                                        let endl = find_next(&blob[index..]);
-                                        println!(
-                                            "Unknown command at index {:X?}-{:X?}",
-                                            index,
-                                            index + endl
-                                        );
-                                        index += endl;
-                                        PJLCommand {
-                                            command,
-                                            params,
-                                            _offset: offset,
-                                        }
-                                    }
-                                }
-                            } else {
-                                println!("Length info not found at index {:X}", index);
-                                break;
-                            }
+                        let mut end = bytes[index..]
+                            .iter()
+                            .position(|&c| c == b'\n')
+                            .map_or(bytes.len(), |len| index + len);
+                        if end > index && bytes[end - 1] == b'\r' {
+                            end -= 1;
                         }
-                        _ => {
-                            println!(
-                                "Cannot parse command *{:X} method {:X} at index {:X}",
-                                cmd_name, method, index
-                            );
-                            break;
+                        if end > index {
+                            let text = String::from_utf8_lossy(&bytes[index..end]).to_string();
+                            tokens.push((index, PJLToken::Text(text)));
+                            index = end;
                         }
                     }
                 }
-                _ => panic!("Unimplemented command"),
             }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Value of a PJL option or command modifier
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PJLValue {
+    /// Integer value
+    Number(i64),
+
+    /// Unquoted alphanumeric value, such as a language or an enumerated setting
+    Word(String),
+
+    /// Quoted string value
+    Quoted(String),
+}
+
+impl PJLValue {
+    /// Value of an unquoted token, a number if it parses as one
+    fn from_word(word: String) -> Self {
+        match word.parse() {
+            Ok(number) => PJLValue::Number(number),
+            Err(_) => PJLValue::Word(word),
+        }
+    }
+
+    /// Value as a string, `None` for numbers
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            PJLValue::Number(_) => None,
+            PJLValue::Word(value) | PJLValue::Quoted(value) => Some(value),
+        }
+    }
+
+    /// Value as a number, `None` for strings
+    pub fn as_number(&self) -> Option<i64> {
+        match self {
+            PJLValue::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for PJLValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PJLValue::Number(number) => write!(f, "{}", number),
+            PJLValue::Word(value) => write!(f, "{}", value),
+            PJLValue::Quoted(value) => write!(f, "\"{}\"", value),
+        }
+    }
+}
+
+/// Single `@PJL` statement, such as `@PJL SET LPARM:PCL SYMSET=ROMAN8`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PJLStatement {
+    /// Command in upper case, empty for a bare `@PJL` line
+    command: String,
+
+    /// Command modifier and its value, such as `LPARM:PCL`
+    modifier: Option<(String, PJLValue)>,
+
+    /// Options in upper case with their values, if any, in the order they appear
+    options: Vec<(String, Option<PJLValue>)>,
+
+    /// Free text of `COMMENT` and `ECHO`
+    text: Option<String>,
+
+    /// Offset of the statement within the job
+    offset: usize,
+}
+
+impl PJLStatement {
+    /// Create a statement for `command` without any options
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_ascii_uppercase(),
+            modifier: None,
+            options: Vec::new(),
+            text: None,
+            offset: 0,
+        }
+    }
+
+    /// Command in upper case, empty for a bare `@PJL` line
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Command modifier and its value
+    pub fn modifier(&self) -> Option<(&str, &PJLValue)> {
+        self.modifier.as_ref().map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// Set the command modifier
+    pub fn set_modifier(&mut self, name: &str, value: PJLValue) {
+        self.modifier = Some((name.to_ascii_uppercase(), value));
+    }
+
+    /// Options with their values, in the order they appear
+    pub fn options(&self) -> &[(String, Option<PJLValue>)] {
+        &self.options
+    }
+
+    /// Value of the option `name`, which is matched ignoring case
+    pub fn option(&self, name: &str) -> Option<&PJLValue> {
+        self.options.iter()
+            .find(|(option, _)| option.eq_ignore_ascii_case(name))
+            .and_then(|(_, value)| value.as_ref())
+    }
+
+    /// Add an option
+    pub fn add_option(&mut self, name: &str, value: Option<PJLValue>) {
+        self.options.push((name.to_ascii_uppercase(), value));
+    }
+
+    /// Free text of `COMMENT` and `ECHO`
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+
+    /// Set the free text of `COMMENT` and `ECHO`
+    pub fn set_text(&mut self, text: &str) {
+        self.text = Some(text.to_string());
+    }
+
+    /// Offset of the statement within the job
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Serialize the statement, terminated by `\r\n`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = String::from("@PJL");
+        if !self.command.is_empty() {
+            out += &format!(" {}", self.command);
+        }
+        if let Some((name, value)) = &self.modifier {
+            out += &format!(" {}:{}", name, value);
+        }
+        for (name, value) in &self.options {
+            match value {
+                Some(value) => out += &format!(" {}={}", name, value),
+                None => out += &format!(" {}", name),
+            }
+        }
+        if let Some(text) = &self.text {
+            out += &format!(" {}", text);
+        }
+        out += "\r\n";
+        out.into_bytes()
+    }
+}
+
+/// Parse the PJL statements at the start of `bytes`, stopping at the first line that is not a PJL
+/// statement
+pub fn parse_pjl_statements(bytes: &[u8]) -> Result<Vec<PJLStatement>> {
+    let mut statements = vec![];
+    let mut tokens = tokenize_pjl(bytes)?.into_iter().peekable();
+
+    // Closure to turn the next token into a value
+    let value = |token: Option<(usize, PJLToken)>, offset: usize| match token {
+        Some((_, PJLToken::Word(word))) => Ok(PJLValue::from_word(word)),
+        Some((_, PJLToken::Quoted(value))) => Ok(PJLValue::Quoted(value)),
+        Some((offset, _)) => Err(Error::BadSyntax { offset }),
+        None => Err(Error::Truncated { offset }),
+    };
+
+    while let Some((offset, token)) = tokens.next() {
+        if token != PJLToken::Prefix {
+            return Err(Error::BadSyntax { offset });
+        }
+        let mut statement = PJLStatement::new("");
+        statement.offset = offset;
+        if let Some((_, PJLToken::Word(command))) = tokens.peek() {
+            statement.command = command.to_ascii_uppercase();
+            tokens.next();
+        }
+
+        loop {
+            match tokens.next() {
+                Some((_, PJLToken::LineEnd)) | None => break,
+                Some((_, PJLToken::Text(text))) => statement.text = Some(text),
+                Some((offset, PJLToken::Word(name))) => match tokens.peek() {
+                    Some((_, PJLToken::Colon)) => {
+                        tokens.next();
+                        let value = value(tokens.next(), offset)?;
+                        statement.set_modifier(&name, value);
+                    }
+                    Some((_, PJLToken::Equals)) => {
+                        tokens.next();
+                        let value = value(tokens.next(), offset)?;
+                        statement.add_option(&name, Some(value));
+                    }
+                    _ => statement.add_option(&name, None),
+                },
+                Some((offset, _)) => return Err(Error::BadSyntax { offset }),
+            }
+        }
+        statements.push(statement);
+    }
+    Ok(statements)
+}
+
+/// Firmware update directive found in the PJL statements of a job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpdateDirective {
+    /// `@PJL UPGRADE`, the firmware image of `size` bytes follows the statement
+    Upgrade { size: Option<usize> },
+
+    /// `@PJL FSDOWNLOAD`, a file of `size` bytes stored as `name` on the printer file system
+    /// follows the statement
+    FsDownload { name: Option<String>, size: Option<usize> },
+}
+
+/// Typed view of the PJL statements wrapped around a job
+#[derive(Debug, Default, Clone)]
+pub struct PJLJob {
+    /// All statements of the job, in the order they appear
+    statements: Vec<PJLStatement>,
+}
+
+impl PJLJob {
+    /// Create a job from its statements
+    pub fn new(statements: Vec<PJLStatement>) -> Self {
+        Self { statements }
+    }
+
+    /// Collect the statements following every UEL of a parsed job
+    pub fn from_commands(pjls: &[PJLCommand]) -> Result<Self> {
+        let mut statements = vec![];
+        for pjl in pjls.iter().filter(|pjl| matches!(pjl.command, Command::UEL)) {
+            for param in &pjl.params {
+                let Param::Msg(msg) = param else { continue };
+                let body = msg.as_bytes().get(UEL.len()..).unwrap_or_default();
+
+                // Offsets are relative to the job, past the ESC and the UEL
+                let start = pjl.offset + 1 + UEL.len();
+                for mut statement in parse_pjl_statements(body)? {
+                    statement.offset += start;
+                    statements.push(statement);
+                }
+            }
+        }
+        Ok(Self { statements })
+    }
+
+    /// All statements of the job, in the order they appear
+    pub fn statements(&self) -> &[PJLStatement] {
+        &self.statements
+    }
+
+    /// Add a statement to the end of the job
+    pub fn add(&mut self, statement: PJLStatement) {
+        self.statements.push(statement);
+    }
+
+    /// Statements with the given command
+    fn find<'a>(&'a self, command: &'a str) -> impl Iterator<Item = &'a PJLStatement> {
+        self.statements.iter().filter(move |statement| statement.command == command)
+    }
+
+    /// Name given by `@PJL JOB NAME=`
+    pub fn name(&self) -> Option<&str> {
+        self.find("JOB").find_map(|statement| statement.option("NAME")?.as_str())
+    }
+
+    /// Printer language switched to by the last `@PJL ENTER LANGUAGE=`
+    pub fn language(&self) -> Option<&str> {
+        self.find("ENTER").filter_map(|statement| statement.option("LANGUAGE")?.as_str()).last()
+    }
+
+    /// Variables changed by `@PJL SET`, in the order they are set
+    pub fn settings(&self) -> impl Iterator<Item = (&str, &PJLValue)> {
+        self.find("SET").flat_map(|statement| {
+            statement.options.iter()
+                .filter_map(|(name, value)| Some((name.as_str(), value.as_ref()?)))
+        })
+    }
+
+    /// Text of all `@PJL COMMENT` statements
+    pub fn comments(&self) -> impl Iterator<Item = &str> {
+        self.find("COMMENT").map(|statement| statement.text().unwrap_or_default())
+    }
+
+    /// Firmware update directives, in the order they appear
+    pub fn updates(&self) -> Vec<UpdateDirective> {
+        // Closure to retrieve a size option as an unsigned number
+        let size = |statement: &PJLStatement| {
+            statement.option("SIZE")?.as_number()?.try_into().ok()
         };
-        result.push(parse);
+
+        self.statements.iter()
+            .filter_map(|statement| match statement.command.as_str() {
+                "UPGRADE" => Some(UpdateDirective::Upgrade { size: size(statement) }),
+                "FSDOWNLOAD" => Some(UpdateDirective::FsDownload {
+                    name: statement.option("NAME").and_then(PJLValue::as_str).map(String::from),
+                    size: size(statement),
+                }),
+                _ => None,
+            })
+            .collect()
     }
 
-    result
+    /// Serialize the job header, the UEL followed by all statements
+    pub fn header(&self) -> Vec<u8> {
+        let mut result = vec![0x1B];
+        result.extend(UEL);
+        for statement in &self.statements {
+            result.extend(statement.to_bytes());
+        }
+        result
+    }
 }
 
 /// Decompress pjl bitmap
-pub fn decompress_bitmap(compress_type: (u8, &Command), blob: &Vec<u8>, seed_row: &Vec<u8>) 
-        -> Vec<u8> {
+pub fn decompress_bitmap(compress_type: (u8, &Command), blob: &[u8], seed_row: &[u8]) 
+        -> Result<Vec<u8>> {
+    // Closure to retrieve a range of bytes, failing if the compressed data is cut short
+    let get = |start: usize, end: usize| {
+        blob.get(start..end).ok_or(Error::Truncated { offset: start })
+    };
+
     match compress_type {
         (0, _) => {
             let mut expand = blob.to_vec();
-            if matches!(compress_type.1, Command::AsteriskB(b'V')) && blob.len() != 16384 {
-                expand.resize(16384, 0);
+            if matches!(compress_type.1, Command::AsteriskB(b'V')) && blob.len() != RASTER_WIDTH {
+                expand.resize(RASTER_WIDTH, 0);
             }
-            expand
+            Ok(expand)
         }
         (2, _) => {
             let mut index = 0;
             let mut expand = vec![];
-            loop {
-                if index >= blob.len() {
-                    assert!(index == blob.len());
-                    break;
-                }
+            while index < blob.len() {
                 let control = blob[index] as i8;
-                // println!("Found control {:X} at offset {:X}", control, index);
                 index += 1;
                 match control {
                     0 => {
-                        let next = blob[index];
+                        let next = get(index, index + 1)?[0];
                         index += 1;
                         expand.push(next);
                     }
                     1..=127 => {
-                        let mut literal = blob[index..index + control as usize + 1].to_vec();
+                        let mut literal = get(index, index + control as usize + 1)?.to_vec();
                         index += literal.len();
                         expand.append(&mut literal);
                     }
-                    -128 => {
-                        println!("Found Do nothing pattern at {}", index);
-                        // Do nothing
-                    }
+                    // No operation
+                    -128 => {}
                     -127..=-1 => {
-                        let mut repeat = blob[index..index + 1].repeat(control.abs() as usize + 1);
+                        let mut repeat = get(index, index + 1)?
+                            .repeat(control.unsigned_abs() as usize + 1);
                         index += 1;
                         expand.append(&mut repeat);
                     }
@@ -229,28 +1024,23 @@ pub fn decompress_bitmap(compress_type: (u8, &Command), blob: &Vec<u8>, seed_row
             // the command Transfer Raster Data by Plane (‘V’) is zero-filled
             // if the amount of bytes after decompression is less than the raster width,
             // while the Transfer Raster Data by Row (‘W’) is not zero-filled
-            if matches!(compress_type.1, Command::AsteriskB(b'V')) && expand.len() != 16384 {
-                expand.resize(16384, 0);
+            if matches!(compress_type.1, Command::AsteriskB(b'V')) && expand.len() != RASTER_WIDTH {
+                expand.resize(RASTER_WIDTH, 0);
             }
-            expand
+            Ok(expand)
         }
         (3, _) => {
             let mut index = 0;
             let mut position = 0;
             let mut seed_row = seed_row.to_vec();
-            loop {
-                if index >= blob.len() {
-                    assert!(index == blob.len());
-                    break;
-                }
+            while index < blob.len() {
                 let control = blob[index];
-                // println!("Found control {:X} at offset {:X}", control, index);
                 index += 1;
                 let replace_count = 1 + ((control >> 5) & 0b111) as usize;
                 let mut replace_offset = (control & 0b11111) as usize;
                 if replace_offset == 0b11111 {
                     loop {
-                        let next_byte = blob[index] as usize;
+                        let next_byte = get(index, index + 1)?[0] as usize;
                         index += 1;
                         replace_offset += next_byte;
                         if next_byte != 0xFF {
@@ -259,53 +1049,691 @@ pub fn decompress_bitmap(compress_type: (u8, &Command), blob: &Vec<u8>, seed_row
                     }
                 }
                 position += replace_offset;
-                let copy_data = &blob[index..index + replace_count];
+                let copy_data = get(index, index + replace_count)?;
+                seed_row
+                    .get_mut(position..position + replace_count)
+                    .ok_or(Error::Truncated { offset: index })?
+                    .copy_from_slice(copy_data);
                 index += replace_count;
-                for i in 0..replace_count {
-                    seed_row[position + i] = copy_data[i];
-                }
                 position += replace_count;
             }
-            seed_row
+            Ok(seed_row)
+        }
+        (1, _) => {
+            // Run-length encoding, pairs of a repeat count and the byte to repeat
+            let mut expand = vec![];
+            for (i, pair) in blob.chunks(2).enumerate() {
+                let &[count, value] = pair else {
+                    return Err(Error::Truncated { offset: i * 2 });
+                };
+                expand.resize(expand.len() + count as usize + 1, value);
+            }
+            if matches!(compress_type.1, Command::AsteriskB(b'V')) && expand.len() != RASTER_WIDTH {
+                expand.resize(RASTER_WIDTH, 0);
+            }
+            Ok(expand)
         }
-        _ => {
-            println!("Warning: Could not decompress. Leaving as-is.");
-            blob.to_vec()
+        (5, _) => Ok(decompress_adaptive(blob, seed_row)?.concat()),
+        (6..=8, _) => {
+            let coding = match compress_type.0 {
+                6 => Coding::Group3OneDim,
+                7 => Coding::Group3TwoDim,
+                _ => Coding::Group4,
+            };
+            decode_row(blob, seed_row, RASTER_WIDTH * 8, coding)
+        }
+        (9, _) => {
+            let mut index = 0;
+            let mut position = 0;
+            let mut seed_row = seed_row.to_vec();
+            while index < blob.len() {
+                let control = blob[index];
+                let start = index;
+                index += 1;
+
+                // Compressed replacements have a shorter offset and a longer count than literal
+                // ones. Both replace one byte more than their count
+                let compressed = control & 0x80 != 0;
+                let (mut offset, offset_max, mut count, count_max) = if compressed {
+                    (((control >> 5) & 0b11) as usize, 0b11, (control & 0b11111) as usize, 0b11111)
+                } else {
+                    (((control >> 3) & 0b1111) as usize, 0b1111, (control & 0b111) as usize, 0b111)
+                };
+                for (value, max) in [(&mut offset, offset_max), (&mut count, count_max)] {
+                    if *value == max {
+                        loop {
+                            let next_byte = get(index, index + 1)?[0] as usize;
+                            index += 1;
+                            *value += next_byte;
+                            if next_byte != 0xFF {
+                                break;
+                            }
+                        }
+                    }
+                }
+                count += 1;
+                position += offset;
+
+                let replace = seed_row
+                    .get_mut(position..position + count)
+                    .ok_or(Error::BadCompressedData { offset: start })?;
+                if compressed {
+                    // The replacement bytes are run length encoded the same way as in mode 1
+                    let mut filled = 0;
+                    while filled < count {
+                        let pair = get(index, index + 2)?;
+                        let run = pair[0] as usize + 1;
+                        replace.get_mut(filled..filled + run)
+                            .ok_or(Error::BadCompressedData { offset: index })?
+                            .fill(pair[1]);
+                        index += 2;
+                        filled += run;
+                    }
+                } else {
+                    replace.copy_from_slice(get(index, index + count)?);
+                    index += count;
+                }
+                position += count;
+            }
+            Ok(seed_row)
         }
+        (10, _) => decompress_near_lossless(blob, seed_row),
+        (4, _) => Err(Error::Unsupported("raster compression mode 4")),
+        (mode, _) => Err(Error::UnknownCompression { mode }),
     }
 }
 
-/// Extract the bitmap from the pjl commands and decompress it
-pub fn extract_bitmap(pjls: &Vec<PJLCommand>) -> Vec<u8> {
-    let mut result = vec![];
-    let mut seed_row = vec![0u8; 16384];
+/// Decompress raster data with adaptive compression (mode 5), which holds a block of rows. Every
+/// row starts with a command byte and a big endian 16-bit count. Commands 0 to 3 carry a row
+/// compressed with the mode of the same number, command 4 adds `count` empty rows and command 5
+/// repeats the previous row `count` times. Returns the decompressed rows
+pub fn decompress_adaptive(blob: &[u8], seed_row: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut rows = vec![];
+    let mut seed_row = seed_row.to_vec();
+    let mut index = 0;
+    while index < blob.len() {
+        let header = blob.get(index..index + 3).ok_or(Error::Truncated { offset: index })?;
+        let (command, count) = (header[0], bytes_to_int_be(&header[1..], 2));
+        let start = index;
+        index += 3;
+        match command {
+            0..=3 => {
+                let row = blob.get(index..index + count)
+                    .ok_or(Error::Truncated { offset: start })?;
+                index += count;
+                seed_row = decompress_bitmap((command, &Command::AsteriskB(b'W')), row, &seed_row)?;
+                rows.push(seed_row.clone());
+            }
+            4 => {
+                seed_row = vec![0; RASTER_WIDTH];
+                rows.extend(std::iter::repeat_n(seed_row.clone(), count));
+            }
+            5 => rows.extend(std::iter::repeat_n(seed_row.clone(), count)),
+            _ => return Err(Error::BadCompressedData { offset: start }),
+        }
+    }
+    Ok(rows)
+}
+
+/// Pixel the near lossless compression assumes left of the first pixel and in an empty cache. The
+/// lowest bit of blue is not stored
+const NEAR_LOSSLESS_WHITE: [u8; 3] = [0xFF, 0xFF, 0xFE];
+
+/// Decompress a row of 24-bit pixels compressed with near lossless replacement delta row
+/// compression (mode 10). Every command skips pixels that are unchanged from the seed row and then
+/// replaces a run of pixels, either repeating one pixel or with literal pixels. New pixels are
+/// stored as a 15-bit delta to the pixel above or as 23 bits with the lowest bit of blue dropped
+fn decompress_near_lossless(blob: &[u8], seed_row: &[u8]) -> Result<Vec<u8>> {
+    // Closure to retrieve a range of bytes, failing if the compressed data is cut short
+    let get = |start: usize, end: usize| {
+        blob.get(start..end).ok_or(Error::Truncated { offset: start })
+    };
+
+    // Closure to read the pixel at `position` of a row, white if it lies outside the row
+    let pixel = |row: &[u8], position: usize| -> [u8; 3] {
+        match row.get(position * 3..position * 3 + 3) {
+            Some(pixel) => [pixel[0], pixel[1], pixel[2]],
+            None => NEAR_LOSSLESS_WHITE,
+        }
+    };
+
+    let mut row = seed_row.to_vec();
+    let pixels = row.len() / 3;
+    let mut cache = NEAR_LOSSLESS_WHITE;
+    let mut index = 0;
+    let mut position = 0;
+
+    // Closure to read an extended count, which continues while the added bytes are 0xFF
+    let extend = |index: &mut usize, count: &mut usize| -> Result<()> {
+        loop {
+            let next_byte = get(*index, *index + 1)?[0] as usize;
+            *index += 1;
+            *count += next_byte;
+            if next_byte != 0xFF {
+                return Ok(());
+            }
+        }
+    };
+
+    // Closure to decode a new pixel relative to the pixel above it
+    let new_pixel = |index: &mut usize, upper: [u8; 3]| -> Result<[u8; 3]> {
+        let first = get(*index, *index + 1)?[0];
+        if first & 0x80 != 0 {
+            // Delta of -16 to 15 for red and green and of -32 to 30 for blue in steps of 2
+            let delta = bytes_to_int_be(get(*index, *index + 2)?, 2);
+            *index += 2;
+            let signed = |value: usize| ((value as u8) << 3) as i8 >> 3;
+            Ok([
+                upper[0].wrapping_add_signed(signed(delta >> 10)),
+                upper[1].wrapping_add_signed(signed(delta >> 5)),
+                upper[2].wrapping_add_signed(signed(delta).wrapping_mul(2)),
+            ])
+        } else {
+            let value = bytes_to_int_be(get(*index, *index + 3)?, 3) << 1;
+            *index += 3;
+            Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+        }
+    };
+
+    while index < blob.len() {
+        let command = blob[index];
+        let start = index;
+        index += 1;
+
+        let mut seed_count = ((command >> 3) & 0b11) as usize;
+        if seed_count == 0b11 {
+            extend(&mut index, &mut seed_count)?;
+        }
+        position += seed_count;
+
+        let first = match (command >> 5) & 0b11 {
+            0 => {
+                let new = new_pixel(&mut index, pixel(seed_row, position))?;
+                cache = new;
+                new
+            }
+            1 => match position.checked_sub(1) {
+                Some(west) => pixel(&row, west),
+                None => NEAR_LOSSLESS_WHITE,
+            },
+            2 => pixel(seed_row, position + 1),
+            _ => cache,
+        };
+
+        // Repeated runs hold at least two pixels, literal runs at least one
+        let repeat = command & 0x80 != 0;
+        let mut count = (command & 0b111) as usize;
+        if count == 0b111 {
+            extend(&mut index, &mut count)?;
+        }
+        count += if repeat { 2 } else { 1 };
+        if position + count > pixels {
+            return Err(Error::BadCompressedData { offset: start });
+        }
+
+        row[position * 3..position * 3 + 3].copy_from_slice(&first);
+        for i in 1..count {
+            let next = if repeat {
+                first
+            } else {
+                let new = new_pixel(&mut index, pixel(seed_row, position + i))?;
+                cache = new;
+                new
+            };
+            row[(position + i) * 3..(position + i) * 3 + 3].copy_from_slice(&next);
+        }
+        position += count;
+    }
+    Ok(row)
+}
+
+/// Locate the raster start and the raster end command following it, returning their indices
+fn raster_bounds(pjls: &[PJLCommand]) -> Result<(usize, usize)> {
     let start = pjls
         .iter()
         .position(|x| matches!(x.command, Command::AsteriskR(b'A')))
-        .expect("Bitmap start");
-    let end = pjls
+        .ok_or(Error::NotFound("bitmap start"))?;
+    let end = pjls[start..]
         .iter()
         .position(|x| matches!(x.command, Command::AsteriskR(b'C')))
-        .expect("Bitmap end");
+        .ok_or(Error::NotFound("bitmap end"))?;
+    Ok((start, start + end))
+}
+
+/// Extract the bitmap from the pjl commands and decompress it
+pub fn extract_bitmap(pjls: &[PJLCommand]) -> Result<Vec<u8>> {
+    let mut result = vec![];
+    let mut seed_row = vec![0u8; RASTER_WIDTH];
+    let (start, end) = raster_bounds(pjls)?;
 
     let mut c_type = 0;
     for part in &pjls[start + 1..end] {
-        //println!("Decompressing at {:X}", part.offset);
         for param in part.params.iter() {
             match param {
-                Param::Compression(level) => {
-                    c_type = *level;
-                    println!("Compression switched to {}", c_type);
+                Param::Compression(level) => c_type = *level,
+                Param::Data(x) if c_type == 5 => {
+                    // Adaptive compression transfers a whole block of rows at once
+                    for row in decompress_adaptive(x, &seed_row)? {
+                        result.extend(&row);
+                        seed_row = row;
+                    }
                 }
                 Param::Data(x) => {
-                    seed_row = decompress_bitmap((c_type, &part.command), x, &seed_row);
+                    seed_row = decompress_bitmap((c_type, &part.command), x, &seed_row)?;
                     result.append(&mut seed_row.to_vec());
                 }
                 _ => {}
             }
         }
     }
+    Ok(result)
+}
+
+/// Compress a row with the raster compression `mode`, the inverse of `decompress_bitmap` for
+/// transfers by row. Mode 3 encodes the differences to `seed_row` and requires both rows to be of
+/// the same length. Returns `None` for modes that cannot be encoded
+pub fn compress_row(mode: u8, row: &[u8], seed_row: &[u8]) -> Option<Vec<u8>> {
+    match mode {
+        0 => Some(row.to_vec()),
+        2 => {
+            // TIFF PackBits, runs of three or more bytes are repeated, everything else is literal
+            let mut out = vec![];
+            let mut literal_start = 0;
+            let mut index = 0;
+            while index < row.len() {
+                let run = row[index..].iter()
+                    .take(128)
+                    .take_while(|&&b| b == row[index])
+                    .count();
+                if run < 3 {
+                    index += run;
+                    continue;
+                }
+                for literal in row[literal_start..index].chunks(128) {
+                    out.push((literal.len() - 1) as u8);
+                    out.extend(literal);
+                }
+                out.push((1 - run as i16) as i8 as u8);
+                out.push(row[index]);
+                index += run;
+                literal_start = index;
+            }
+            for literal in row[literal_start..].chunks(128) {
+                out.push((literal.len() - 1) as u8);
+                out.extend(literal);
+            }
+            Some(out)
+        }
+        3 => {
+            // Delta row, replace up to 8 differing bytes at an offset from the previous change
+            if row.len() != seed_row.len() {
+                return None;
+            }
+            let mut out = vec![];
+            let mut position = 0;
+            let mut index = 0;
+            while index < row.len() {
+                if row[index] == seed_row[index] {
+                    index += 1;
+                    continue;
+                }
+                let count = row[index..].iter()
+                    .zip(&seed_row[index..])
+                    .take(8)
+                    .take_while(|(a, b)| a != b)
+                    .count();
+                let offset = index - position;
+                out.push(((count - 1) << 5) as u8 | std::cmp::min(offset, 0b11111) as u8);
+                if offset >= 0b11111 {
+                    let mut rest = offset - 0b11111;
+                    while rest >= 0xFF {
+                        out.push(0xFF);
+                        rest -= 0xFF;
+                    }
+                    out.push(rest as u8);
+                }
+                out.extend(&row[index..index + count]);
+                index += count;
+                position = index;
+            }
+            Some(out)
+        }
+        _ => None,
+    }
+}
+
+/// Encode the rows of `bitmap` as raster transfers by row, picking the compression mode that
+/// yields the smallest transfer for every row. The compression mode is only switched when it
+/// changes, the first row always sets it
+fn encode_rows(bitmap: &[u8]) -> Vec<u8> {
+    let mut result = vec![];
+    let mut seed_row = vec![0u8; RASTER_WIDTH];
+    let mut current = None;
+
+    for row in bitmap.chunks(RASTER_WIDTH) {
+        let (mode, data) = [0, 2, 3]
+            .into_iter()
+            .filter_map(|mode| Some((mode, compress_row(mode, row, &seed_row)?)))
+            .min_by_key(|(mode, data)| data.len() + if current == Some(*mode) { 0 } else { 2 })
+            .expect("mode 0 encodes every row");
+
+        let switch = if current == Some(mode) { String::new() } else { format!("{}m", mode) };
+        result.extend(format!("\x1b*b{}{}W", switch, data.len()).as_bytes());
+        result.extend(data);
+        current = Some(mode);
+        seed_row = row.to_vec();
+    }
+    result
+}
+
+/// Encode `bitmap` as a complete pjl job that `parse_pjl` and `extract_bitmap` turn back into the
+/// same bitmap
+pub fn encode_job(bitmap: &[u8]) -> Vec<u8> {
+    let mut enter = PJLStatement::new("ENTER");
+    enter.add_option("LANGUAGE", Some(PJLValue::Word("PCL3GUI".to_string())));
+    let mut result = PJLJob::new(vec![enter]).header();
+    result.extend(b"\x1bE\x1b*r1A");
+    result.extend(encode_rows(bitmap));
+    result.extend(b"\x1b*rC\x1bE\x1b%-12345X");
     result
 }
 
+/// Replace the bitmap of the pjl job in `blob` with `bitmap`. Everything up to and including the
+/// raster start command as well as everything from the raster end command onwards is kept as-is.
+/// The new bitmap is padded to full rows, which are compressed the same way as by `encode_job`
+pub fn replace_bitmap(blob: &[u8], bitmap: &[u8]) -> Result<Vec<u8>> {
+    let pjls = parse_pjl(blob)?;
+    let (start, end) = raster_bounds(&pjls)?;
+
+    let mut bitmap = bitmap.to_vec();
+    bitmap.resize(bitmap.len().next_multiple_of(RASTER_WIDTH), 0);
+
+    let mut result = blob[..pjls[start + 1].offset].to_vec();
+    result.extend(encode_rows(&bitmap));
+    result.extend(&blob[pjls[end].offset..]);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic xorshift generator so the random cases are reproducible
+    fn pseudo_random(len: usize, mut state: u32) -> Vec<u8> {
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect()
+    }
+
+    fn assert_round_trip(bitmap: &[u8]) {
+        let pjls = parse_pjl(&encode_job(bitmap)).unwrap();
+        assert!(extract_bitmap(&pjls).unwrap() == bitmap, "len {}", bitmap.len());
+    }
+
+    /// Rows that favour every compression mode in turn: random data, runs, a copy of the previous
+    /// row, sparse changes with short and long offsets and escape characters in the data
+    fn mixed_rows(rows: usize) -> Vec<u8> {
+        let mut bitmap: Vec<u8> = vec![];
+        for i in 0..rows {
+            let previous = bitmap.len().checked_sub(RASTER_WIDTH).map(|start| bitmap[start..].to_vec());
+            let row = match (i % 5, previous) {
+                (0, _) => pseudo_random(RASTER_WIDTH, i as u32 + 1),
+                (1, _) => [0x1Bu8, 0x00, 0xFF, 0x1B].repeat(RASTER_WIDTH / 4),
+                (2, Some(previous)) => previous,
+                (3, Some(mut previous)) => {
+                    for offset in [3, 40, 41, 300, 1000, RASTER_WIDTH - 1] {
+                        previous[offset] ^= 0x1B;
+                    }
+                    previous
+                }
+                _ => {
+                    let mut row = vec![0x1B; RASTER_WIDTH];
+                    row[..RASTER_WIDTH / 2].copy_from_slice(&pseudo_random(RASTER_WIDTH / 2, 7));
+                    row
+                }
+            };
+            bitmap.extend(row);
+        }
+        bitmap
+    }
+
+    #[test]
+    fn unknown_sequences_end_at_terminator() {
+        let blob = b"\x1b&x5Qtext\x1b&x1a2Bmore\x1bQ\x1b\x07\x1b*r1A\x1b&x12";
+        let pjls = parse_pjl(blob).unwrap();
+        let spans: Vec<_> = pjls.iter().map(|pjl| pjl.span()).collect();
+        assert_eq!(spans, [0..5, 5..9, 9..16, 16..20, 20..22, 22..23, 23..24, 24..29, 29..34]);
+        assert!(matches!(pjls[7].command(), Command::AsteriskR(b'A')));
+        for i in [0, 2, 4, 5, 8] {
+            assert!(matches!(pjls[i].command(), Command::Raw), "command {}", i);
+            assert_eq!(pjls[i].raw()[0], 0x1B);
+        }
+        assert_eq!(write_pjl(&pjls), blob);
+    }
+
+    /// Job with PJL statements, fractional and negative values, combined commands and raster data
+    /// that holds escape characters
+    const JOB: &[u8] = b"\x1b%-12345X@PJL JOB NAME=\"fw\"\r\n@PJL ENTER LANGUAGE=PCL3GUI\r\n\
+        \x1bE\x1b&l0.5E\x1b*p-50Y\x1b*r1A\x1b*b2m4W\x02\x1b\x00\x1b\x1b*b3W\x1b\x1b\x00\
+        \x1b*rC\x1bE\x1b%-12345X";
+
+    #[test]
+    fn write_round_trip() {
+        let pjls = parse_pjl(JOB).unwrap();
+        assert_eq!(write_pjl(&pjls), JOB);
+
+        // Every command covers its own bytes, without gaps between them
+        let mut end = 0;
+        for pjl in &pjls {
+            assert_eq!(pjl.offset(), end);
+            assert_eq!(pjl.raw(), &JOB[pjl.span()]);
+            end = pjl.span().end;
+        }
+        assert_eq!(end, JOB.len());
+        assert!(pjls.iter().any(|pjl| matches!(pjl.params(),
+            [Param::Value { parameter: b'Y', value }] if value == "-50")));
+        assert!(pjls.iter().any(|pjl| matches!(pjl.params(),
+            [Param::Value { parameter: b'E', value }] if value == "0.5")));
+    }
+
+    #[test]
+    fn edit_changes_only_its_span() {
+        let mut pjls = parse_pjl(JOB).unwrap();
+        let pjl = pjls.iter_mut()
+            .find(|pjl| matches!(pjl.params(), [Param::Compression(2), ..]))
+            .unwrap();
+        let span = pjl.span();
+        pjl.params_mut()[0] = Param::Compression(0);
+
+        let mut expected = JOB[..span.start].to_vec();
+        expected.extend(b"\x1b*b0m4W\x02\x1b\x00\x1b");
+        expected.extend(&JOB[span.end..]);
+        assert_eq!(write_pjl(&pjls), expected);
+    }
+
+    /// PJL header with quoted values, a modifier, spaces around `=` and a comment
+    const HEADER: &[u8] = b"@PJL JOB NAME = \"my job\"\r\n@PJL SET LPARM:PCL SYMSET=ROMAN8\n\
+        @PJL COMMENT free text: here\r\n\x1bE";
+
+    #[test]
+    fn tokenize_header() {
+        let word = |word: &str| PJLToken::Word(word.to_string());
+        assert_eq!(tokenize_pjl(HEADER).unwrap(), [
+            (0, PJLToken::Prefix), (5, word("JOB")), (9, word("NAME")), (14, PJLToken::Equals),
+            (16, PJLToken::Quoted("my job".to_string())), (24, PJLToken::LineEnd),
+            (26, PJLToken::Prefix), (31, word("SET")), (35, word("LPARM")), (40, PJLToken::Colon),
+            (41, word("PCL")), (45, word("SYMSET")), (51, PJLToken::Equals), (52, word("ROMAN8")),
+            (58, PJLToken::LineEnd),
+            (59, PJLToken::Prefix), (64, word("COMMENT")),
+            (72, PJLToken::Text("free text: here".to_string())), (87, PJLToken::LineEnd),
+        ]);
+    }
+
+    #[test]
+    fn parse_header_statements() {
+        let statements = parse_pjl_statements(HEADER).unwrap();
+        assert_eq!(statements.iter().map(PJLStatement::offset).collect::<Vec<_>>(), [0, 26, 59]);
+
+        assert_eq!(statements[0].command(), "JOB");
+        assert_eq!(statements[0].option("name"), Some(&PJLValue::Quoted("my job".to_string())));
+
+        assert_eq!(statements[1].command(), "SET");
+        assert_eq!(statements[1].modifier(), Some(("LPARM", &PJLValue::Word("PCL".to_string()))));
+        assert_eq!(statements[1].options(),
+                   [("SYMSET".to_string(), Some(PJLValue::Word("ROMAN8".to_string())))]);
+
+        assert_eq!(statements[2].command(), "COMMENT");
+        assert_eq!(statements[2].text(), Some("free text: here"));
+        assert_eq!(statements[2].to_bytes(), b"@PJL COMMENT free text: here\r\n");
+
+        // Keywords are not case sensitive, numbers are parsed as such
+        let statements = parse_pjl_statements(b"@pjl set copies = 2\r\n").unwrap();
+        assert_eq!(statements[0].command(), "SET");
+        assert_eq!(statements[0].option("COPIES"), Some(&PJLValue::Number(2)));
+    }
+
+    #[test]
+    fn unterminated_quotes() {
+        for header in [&b"@PJL JOB NAME=\"fw\r\n@PJL\r\n"[..], b"@PJL JOB NAME=\"fw"] {
+            assert!(matches!(tokenize_pjl(header), Err(Error::BadSyntax { offset: 14 })));
+            assert!(matches!(parse_pjl_statements(header), Err(Error::BadSyntax { offset: 14 })));
+        }
+
+        // Options need a value after the `=`
+        assert!(matches!(parse_pjl_statements(b"@PJL SET COPIES=\r\n"),
+                         Err(Error::BadSyntax { offset: 16 })));
+    }
+
+    #[test]
+    fn job_from_commands() {
+        let job = PJLJob::from_commands(&parse_pjl(JOB).unwrap()).unwrap();
+        assert_eq!(job.name(), Some("fw"));
+        assert_eq!(job.language(), Some("PCL3GUI"));
+        for statement in job.statements() {
+            assert!(JOB[statement.offset()..].starts_with(b"@PJL"));
+        }
+
+        let blob = b"\x1b%-12345X@PJL SET LPARM:PCL SYMSET = \"PC-8\"\r\n\
+            @PJL UPGRADE SIZE=1024\r\n\x1bE\x1b%-12345X";
+        let job = PJLJob::from_commands(&parse_pjl(blob).unwrap()).unwrap();
+        assert_eq!(job.statements().len(), 2);
+        assert_eq!(job.settings().collect::<Vec<_>>(),
+                   [("SYMSET", &PJLValue::Quoted("PC-8".to_string()))]);
+        assert_eq!(job.updates(), [UpdateDirective::Upgrade { size: Some(1024) }]);
+    }
+
+    #[test]
+    fn oversized_values() {
+        // A length that does not fit past the data, and one that does not fit into a usize
+        assert!(matches!(parse_pjl(b"\x1b*b18446744073709551615W\x00"),
+                         Err(Error::Truncated { offset: 0 })));
+        assert!(matches!(parse_pjl(b"\x1b*b99999999999999999999999W\x00"),
+                         Err(Error::BadSyntax { offset: 0 })));
+        assert!(matches!(parse_pjl(b"\x1b*r99999999999999999999999A"),
+                         Err(Error::BadSyntax { offset: 0 })));
+        assert_eq!(pcl_number(b"+18446744073709551615.5"), Some(usize::MAX));
+        assert_eq!(pcl_number(b"-5"), Some(0));
+    }
+
+    #[test]
+    fn raster_end_before_start() {
+        let blob = b"\x1b*rC\x1b*r1A\x1b*b0m2W\x01\x02";
+        let pjls = parse_pjl(blob).unwrap();
+        assert!(matches!(extract_bitmap(&pjls), Err(Error::NotFound("bitmap end"))));
+        assert!(matches!(replace_bitmap(blob, &[0]), Err(Error::NotFound("bitmap end"))));
+
+        let blob = b"\x1b*rC\x1b*r1A\x1b*b0m2W\x01\x02\x1b*rC";
+        assert_eq!(extract_bitmap(&parse_pjl(blob).unwrap()).unwrap(), [0x01, 0x02]);
+    }
+
+    #[test]
+    fn replacement_delta_row() {
+        let command = Command::AsteriskB(b'W');
+        let seed_row = vec![0x11; 64];
+        let blob = [
+            0x0A, 0x01, 0x02, 0x03, // 3 literal bytes 1 byte in
+            0xC3, 0x01, 0xAA,       // 4 run length encoded bytes 2 bytes further, 2 of 0xAA
+            0x01, 0xBB,             // and 2 of 0xBB
+            0x78, 0x01, 0x1B,       // 1 literal byte at an extended offset of 16
+            0x9F, 0x02, 0x20, 0x55, // 34 run length encoded bytes with an extended count, 33 of
+            0x00, 0x66,             // 0x55 and 1 of 0x66
+        ];
+
+        let mut expected = seed_row.clone();
+        expected[1..4].copy_from_slice(&[0x01, 0x02, 0x03]);
+        expected[6..10].copy_from_slice(&[0xAA, 0xAA, 0xBB, 0xBB]);
+        expected[26] = 0x1B;
+        expected[27..60].fill(0x55);
+        expected[60] = 0x66;
+        assert_eq!(decompress_bitmap((9, &command), &blob, &seed_row).unwrap(), expected);
+
+        // Replacing past the end of the seed row or runs longer than the replacement are errors
+        assert!(matches!(decompress_bitmap((9, &command), &[0x9F, 0x40, 0x00], &seed_row),
+                         Err(Error::BadCompressedData { offset: 0 })));
+        assert!(matches!(decompress_bitmap((9, &command), &[0x81, 0x02, 0x55], &seed_row),
+                         Err(Error::BadCompressedData { offset: 1 })));
+        assert!(matches!(decompress_bitmap((9, &command), &[0x81, 0x00, 0x55], &seed_row),
+                         Err(Error::Truncated { offset: 3 })));
+    }
+
+    #[test]
+    fn near_lossless_delta_row() {
+        let command = Command::AsteriskB(b'W');
+        let seed_row: Vec<u8> = (0..8).flat_map(|i| [i * 0x10, 0x80, 0x40]).collect();
+        let blob = [
+            0x08, 0x87, 0xE1,       // skip 1, 1 new pixel as a delta of +1, -1, +2 to the seed
+            0xE0,                   // 2 pixels repeating the cached pixel
+            0x22, 0x12, 0x34, 0x56, // 3 pixels, the west pixel, a 23-bit pixel and
+            0x80, 0x00,             // a pixel equal to the seed
+            0x40,                   // 1 pixel copied from north east, beyond the row so white
+        ];
+
+        let cached = [0x11, 0x7F, 0x42];
+        let mut expected = seed_row[..3].to_vec();
+        for pixel in [cached, cached, cached, cached, [0x24, 0x68, 0xAC], [0x60, 0x80, 0x40]] {
+            expected.extend(pixel);
+        }
+        expected.extend(NEAR_LOSSLESS_WHITE);
+        assert_eq!(decompress_bitmap((10, &command), &blob, &seed_row).unwrap(), expected);
+
+        // Runs that do not fit into the seed row are an error
+        assert!(matches!(decompress_bitmap((10, &command), &[0xE7, 0x00], &seed_row),
+                         Err(Error::BadCompressedData { offset: 0 })));
+    }
 
+    #[test]
+    fn unsupported_compression() {
+        let command = Command::AsteriskB(b'W');
+        assert!(matches!(decompress_bitmap((4, &command), &[0x00], &[]),
+                         Err(Error::Unsupported(_))));
+        assert!(matches!(decompress_bitmap((11, &command), &[0x00], &[]),
+                         Err(Error::UnknownCompression { mode: 11 })));
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        for rows in [0, 1, 3, 10] {
+            assert_round_trip(&mixed_rows(rows));
+        }
+    }
+
+    #[test]
+    fn encode_round_trip_partial_row() {
+        let mut bitmap = mixed_rows(3);
+        bitmap.extend(pseudo_random(100, 3));
+        bitmap.extend([0x1B; 50]);
+        assert_round_trip(&bitmap);
+        assert_round_trip(&[0x1B]);
+    }
+
+    #[test]
+    fn encode_picks_all_modes() {
+        let job = encode_job(&mixed_rows(10));
+        for switch in [&b"\x1b*b0m"[..], b"\x1b*b2m", b"\x1b*b3m"] {
+            assert!(job.windows(switch.len()).any(|w| w == switch), "{:?}", switch);
+        }
+    }
+}
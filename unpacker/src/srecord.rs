@@ -1,10 +1,14 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
 use crate::{
     hex_to_ascii, bytes_to_int_be,
+    error::{Error, Result},
+    nand::strip_oob,
 };
 
 /// Different types the S-Record can take. Extracted from the byte following the 'S' while parsing
 /// the data
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SRecordType {
     /// Generally S-Record types appear to be [0-9]. Type-A may be a proprietary addition made by HP
     A,
@@ -14,13 +18,150 @@ pub enum SRecordType {
     /// data-field of `reflash`
     Zero,
 
+    /// Data SRecord (16-bit)
+    One,
+
+    /// Data SRecord (24-bit)
+    Two,
+
     /// Data SRecord (32-bit)
     /// This type instructs the flash programmer to store the record data to a specified section in
     /// memory
     Three,
 
+    /// Count SRecord (16-bit), the address field holds the number of preceding data records
+    Five,
+
+    /// Count SRecord (24-bit), the address field holds the number of preceding data records
+    Six,
+
     /// Last SRecord (32-bit)
     Seven,
+
+    /// Last SRecord (24-bit)
+    Eight,
+
+    /// Last SRecord (16-bit)
+    Nine,
+}
+
+impl SRecordType {
+    /// Get the record type from the raw type nibble, type 4 is reserved and thus not valid
+    pub fn from_raw(raw_type: u8) -> Option<Self> {
+        Some(match raw_type {
+            0x0 => SRecordType::Zero,
+            0x1 => SRecordType::One,
+            0x2 => SRecordType::Two,
+            0x3 => SRecordType::Three,
+            0x5 => SRecordType::Five,
+            0x6 => SRecordType::Six,
+            0x7 => SRecordType::Seven,
+            0x8 => SRecordType::Eight,
+            0x9 => SRecordType::Nine,
+            0xA => SRecordType::A,
+            _ => return None,
+        })
+    }
+
+    /// Raw type nibble of the record type
+    pub fn raw(&self) -> u8 {
+        match self {
+            SRecordType::Zero => 0x0,
+            SRecordType::One => 0x1,
+            SRecordType::Two => 0x2,
+            SRecordType::Three => 0x3,
+            SRecordType::Five => 0x5,
+            SRecordType::Six => 0x6,
+            SRecordType::Seven => 0x7,
+            SRecordType::Eight => 0x8,
+            SRecordType::Nine => 0x9,
+            SRecordType::A => 0xA,
+        }
+    }
+
+    /// Size of the address field in bytes
+    pub fn address_size(&self) -> usize {
+        match self {
+            SRecordType::Zero | SRecordType::One | SRecordType::Five | SRecordType::Nine => 2,
+            SRecordType::Two | SRecordType::Six | SRecordType::Eight => 3,
+            SRecordType::Three | SRecordType::Seven => 4,
+            SRecordType::A => 0,
+        }
+    }
+
+    /// Whether records of this type carry data to be stored in memory
+    pub fn is_data(&self) -> bool {
+        matches!(self, SRecordType::One | SRecordType::Two | SRecordType::Three)
+    }
+
+    /// Whether records of this type terminate a block of data records
+    pub fn is_termination(&self) -> bool {
+        matches!(self, SRecordType::Seven | SRecordType::Eight | SRecordType::Nine)
+    }
+}
+
+/// Decoded payload of a type-A record. The layout of these records is not documented, so the
+/// payload is categorised by its contents
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeAPayload {
+    /// Printable text that contains a dotted version number, eg. `HPVER1.0`
+    Version(String),
+
+    /// Printable text of the form `key=value` or `key: value`
+    Field { key: String, value: String },
+
+    /// Any other printable text
+    Text(String),
+
+    /// Binary data with the size of a common digest or signature (SHA-1, SHA-256, SHA-384,
+    /// SHA-512 or RSA-1024/2048/3072/4096)
+    Signature(Vec<u8>),
+
+    /// Two big endian 32-bit words, taken to describe a region as start address and length
+    Region { start: usize, len: usize },
+
+    /// Binary data that does not fit any of the other categories
+    Binary(Vec<u8>),
+}
+
+impl TypeAPayload {
+    /// Categorise the data field of a type-A record. This is a heuristic: printable text is
+    /// checked for a version number and then for a key and value, binary data is told apart by
+    /// its length only. A payload may fall into a category by chance, such as an 8 byte value
+    /// that is not a region
+    pub fn decode(data: &[u8]) -> Self {
+        // Text is commonly padded with NUL bytes
+        let text = match data.iter().rposition(|&b| b != 0) {
+            Some(end) => &data[..=end],
+            None => &data[..0],
+        };
+        if !text.is_empty() && text.iter().all(|&b| (0x20..0x7f).contains(&b)) {
+            let text = String::from_utf8_lossy(text).into_owned();
+            let bytes = text.as_bytes();
+            let is_version = bytes.windows(3).any(|w| {
+                w[0].is_ascii_digit() && w[1] == b'.' && w[2].is_ascii_digit()
+            });
+            if is_version {
+                return TypeAPayload::Version(text);
+            }
+            if let Some((key, value)) = text.split_once('=').or_else(|| text.split_once(':')) {
+                return TypeAPayload::Field {
+                    key: key.trim().to_string(),
+                    value: value.trim().to_string(),
+                };
+            }
+            return TypeAPayload::Text(text);
+        }
+
+        match data.len() {
+            8 => TypeAPayload::Region {
+                start: bytes_to_int_be(&data[0..4], 4),
+                len: bytes_to_int_be(&data[4..8], 4),
+            },
+            20 | 32 | 48 | 64 | 128 | 256 | 384 | 512 => TypeAPayload::Signature(data.to_vec()),
+            _ => TypeAPayload::Binary(data.to_vec()),
+        }
+    }
 }
 
 /// SRecord struct
@@ -33,174 +174,977 @@ pub struct SRecord {
     t_type: SRecordType,
 
     /// Length of data field
-    _len: usize,
+    len: usize,
 
     /// Address field
-    _address: usize,
+    address: usize,
 
     /// Data field
     data: Vec<u8>,
 
     /// Sum all bytes (% 256) starting at len field and take 1's complement
-    _checksum: u8,
+    checksum: u8,
+
+    /// Offset of the record within the parsed bytes
+    offset: usize,
 }
 
-/// Parse out all S-Records from the passed in bytes and return them to user
-pub fn parse_srecords(bytes: &[u8]) -> Vec<SRecord> {
-    let mut index: usize = 0;
-    let mut records: Vec<SRecord> = Vec::new();
+impl SRecord {
+    /// Create a new record, the length and checksum are computed from the address and data. The
+    /// length field covers the address, data and checksum and has to fit into a byte
+    pub fn new(t_type: SRecordType, address: usize, data: &[u8]) -> Result<Self> {
+        if t_type.address_size() + data.len() + 1 > 0xFF {
+            return Err(Error::InvalidConfig("record data does not fit into a record"));
+        }
+        let mut record = Self {
+            header: b'S',
+            t_type,
+            len: t_type.address_size() + data.len() + 1,
+            address,
+            data: data.to_vec(),
+            checksum: 0,
+            offset: 0,
+        };
+        record.checksum = record.body()
+            .iter()
+            .fold(record.len as u8, |acc, &ele| acc.wrapping_add(ele)) ^ 0xFF;
+        Ok(record)
+    }
+
+    /// Type of the record
+    pub fn t_type(&self) -> SRecordType {
+        self.t_type
+    }
+
+    /// Length of the address, data and checksum of the record
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the record holds neither an address nor any data
+    pub fn is_empty(&self) -> bool {
+        self.len <= 1
+    }
+
+    /// Address field of the record
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// Data field of the record
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Checksum of the record
+    pub fn checksum(&self) -> u8 {
+        self.checksum
+    }
 
-    loop {
-        let record_cat = bytes[index];
+    /// Decoded payload of a type-A record, `None` for all other record types
+    pub fn type_a(&self) -> Option<TypeAPayload> {
+        match self.t_type {
+            SRecordType::A => Some(TypeAPayload::decode(&self.data)),
+            _ => None,
+        }
+    }
 
-        // Closure to find the next newline within sequence of bytes
-        let find_nl = |id: &[u8]| id.iter().position(|&c| c == b'\n').unwrap_or(id.len());
+    /// Offset of the record within the parsed bytes, 0 for records that were not parsed
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Address field followed by the data field, as they are stored in the record
+    fn body(&self) -> Vec<u8> {
+        let address_size = self.t_type.address_size();
+        let mut body = self.address.to_be_bytes()[8 - address_size..].to_vec();
+        body.extend(&self.data);
+        body
+    }
 
-        // Closure to verify the checksums of records
-        let verify = |calc: &[u8], len: u8, checksum: u8| {
-            let calc_add = calc.iter().fold(len as u16, |acc, &ele| acc + ele as u16);
-            let calc_mask_comp = (calc_add & 0xFF) as u8 ^ 0xFF;
-            assert!(checksum == calc_mask_comp);
+    /// Encode the record as an ascii line: `S`, type, length, address, data and checksum as
+    /// hex followed by a newline
+    pub fn to_ascii(&self) -> Vec<u8> {
+        let t_type = match self.t_type {
+            SRecordType::A => 'A',
+            t_type => (b'0' + t_type.raw()) as char,
         };
+        let mut line = format!("S{}{:02X}", t_type, self.len);
+        for byte in self.body() {
+            line += &format!("{:02X}", byte);
+        }
+        line += &format!("{:02X}\n", self.checksum);
+        line.into_bytes()
+    }
 
-        match record_cat {
-            // Check if record starts with `S` and is thus an S-Record
-            0x53 => {
-                // <ASCII Text>
-                // S                                                    Header
-                // 3                                                    Type
-                // 19                                                   Length
-                // AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA     Data
-                // 28                                                   Checksum
-                // `\n`                                                 Newline
-
-
-                // Parse out length field from the Record
-                let (len, _) = hex_to_ascii(&bytes[index + 2..index + 4], 16);
-
-                // Parse out type of this record
-                let t_type = match bytes[index + 1] {
-                    0x41 => SRecordType::A,
-                    0x30 => SRecordType::Zero,
-                    0x33 => SRecordType::Three,
-                    0x37 => SRecordType::Seven,
-                    _ => panic!("found type: {:X}", bytes[index + 1]),
-                };
-                let raw_type = bytes[index + 1] - 0x30;
-
-                // Extract data fields as ascii instead of hex
-                let ascii_byte_start = index + 4;
-                let ascii_bytes = bytes[ascii_byte_start..ascii_byte_start + (len * 2)].chunks(2);
-                let mut data = vec![];
-                for ascii_byte in ascii_bytes {
-                    data.push(hex_to_ascii(ascii_byte, 16).0 as u8);
-                }
+    /// Encode the record in binary form: header and type, length, address, data and checksum
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut record = vec![0x30 | self.t_type.raw(), self.len as u8];
+        record.extend(self.body());
+        record.push(self.checksum);
+        record
+    }
+}
 
-                // Verify checksum for the data
-                assert!(data.len() == len);
-                let checksum = data.pop().unwrap();
-                verify(&data, len as u8, checksum);
-
-                // Parse out addresses from S-Records
-                let address_size = match raw_type {
-                    0 | 1 | 5 | 9 => 2,
-                    2 | 6 | 8 => 3,
-                    3 | 7 => 4,
-                    _ => 0,
-                };
-                let (address_raw, data) = data.split_at(address_size);
-                let address = bytes_to_int_be(address_raw, address_size);
-
-                records.push(SRecord {
-                    header: bytes[index],
-                    t_type,
-                    _len: len,
-                    _address: address,
-                    data: data.to_vec(),
-                    _checksum: checksum,
-                });
-                // Increment index by length*2 + new-line byte (1) + Header bytes (4)
-                index += (len * 2) + 5;
-            }
-            // Binary S-Record instead of Ascii S-Record
-            0x30..=0x3F => {
-                // <Hexdump>
-                // 33                                                   Header+Type
-                // 05                                                   Length
-                // AA AA AA AA                                          Data
-                // 28                                                   Checksum
-                let raw_type = record_cat & 0xF;
-                let t_type = match raw_type {
-                    0x0 => SRecordType::Zero,
-                    0x3 => SRecordType::Three,
-                    0x7 => SRecordType::Seven,
-                    0xA => SRecordType::A,
-                    _ => panic!("found type: {:X}", record_cat),
-                };
-                let len = bytes[index + 1] as usize;
-                let checksum = bytes[index + 1 + len];
-                let data = &bytes[index + 2..index + 1 + len];
-                verify(&data, len as u8, checksum);
-                // Address size in bytes
-                let address_size = match raw_type {
-                    0 | 1 | 5 | 9 => 2,
-                    2 | 6 | 8 => 3,
-                    3 | 7 => 4,
-                    _ => 0,
-                };
-                let (address_raw, data) = data.split_at(address_size);
-                let address = bytes_to_int_be(address_raw, address_size);
-                records.push(SRecord {
-                    header: bytes[index],
-                    t_type,
-                    _len: len,
-                    _address: address,
-                    data: data.to_vec(),
-                    _checksum: checksum,
-                });
-                // Increment index by
-                // length + new-line byte (1) + length byte (1)
-                index += len + 2;
+/// Splits data into S-Records with a fixed amount of data per record and a fixed address width
+#[derive(Debug, Clone, Copy)]
+pub struct SRecordWriter {
+    /// Maximum number of data bytes per record
+    record_len: usize,
+
+    /// Size of the addresses in bytes, 2, 3 or 4
+    address_size: usize,
+}
+
+impl SRecordWriter {
+    /// Create a writer emitting at most `record_len` data bytes per record with addresses that
+    /// are `address_size` bytes wide
+    pub fn new(record_len: usize, address_size: usize) -> Result<Self> {
+        if !(2..=4).contains(&address_size) {
+            return Err(Error::InvalidConfig("address size has to be 2, 3 or 4 bytes"));
+        }
+        // The length field covers the address, data and checksum and has to fit into a byte
+        if record_len == 0 || record_len + address_size + 1 > 0xFF {
+            return Err(Error::InvalidConfig("record length does not fit into a record"));
+        }
+        Ok(Self { record_len, address_size })
+    }
+
+    /// Type of the data records
+    fn data_type(&self) -> SRecordType {
+        match self.address_size {
+            2 => SRecordType::One,
+            3 => SRecordType::Two,
+            _ => SRecordType::Three,
+        }
+    }
+
+    /// Type of the termination record
+    fn termination_type(&self) -> SRecordType {
+        match self.address_size {
+            2 => SRecordType::Nine,
+            3 => SRecordType::Eight,
+            _ => SRecordType::Seven,
+        }
+    }
+
+    /// Split `data` that is stored at `address` into data records
+    pub fn data_records(&self, address: usize, data: &[u8]) -> Result<Vec<SRecord>> {
+        let max = 1usize << (self.address_size * 8);
+        if address + data.len() > max {
+            return Err(Error::TooLarge { addr: address, size: data.len(), max: max - address });
+        }
+        data.chunks(self.record_len)
+            .enumerate()
+            .map(|(i, chunk)| SRecord::new(self.data_type(), address + i * self.record_len, chunk))
+            .collect()
+    }
+
+    /// Build a complete set of records: a header record holding `header`, the data records for
+    /// `data` at `address`, a count record if the count fits and a termination record holding
+    /// the `entry` address
+    pub fn records(&self, header: &[u8], address: usize, data: &[u8], entry: usize)
+            -> Result<Vec<SRecord>> {
+        let mut records = vec![SRecord::new(SRecordType::Zero, 0, header)?];
+        let data_records = self.data_records(address, data)?;
+        let count = data_records.len();
+        records.extend(data_records);
+        if count < 0x10000 {
+            records.push(SRecord::new(SRecordType::Five, count, &[])?);
+        } else if count < 0x1000000 {
+            records.push(SRecord::new(SRecordType::Six, count, &[])?);
+        }
+        records.push(SRecord::new(self.termination_type(), entry, &[])?);
+        Ok(records)
+    }
+}
+
+/// Serialize records to ascii S-Records, one per line
+pub fn write_ascii(records: &[SRecord]) -> Vec<u8> {
+    records.iter().flat_map(SRecord::to_ascii).collect()
+}
+
+/// Serialize records to binary S-Records
+pub fn write_binary(records: &[SRecord]) -> Vec<u8> {
+    records.iter().flat_map(SRecord::to_binary).collect()
+}
+
+/// Number of bytes requested from the underlying reader at once
+const READ_CHUNK: usize = 0x10000;
+
+/// Outcome of decoding the bytes at the current position of a reader
+enum Step {
+    /// Record decoded from the given number of bytes
+    Record(SRecord, usize),
+
+    /// Non-record line of the given length to skip
+    Skip(usize),
+
+    /// The buffered bytes end before the record does
+    NeedMore,
+
+    /// No more records follow
+    End,
+}
+
+/// Decode the record at the start of `bytes`, which sits at `offset` in the stream. `eof` marks
+/// that no bytes follow the buffered ones, so a cut short record is truncated instead of incomplete
+fn decode_record(bytes: &[u8], offset: usize, eof: bool) -> Result<Step> {
+    // Closure to retrieve a range of bytes, failing if the record is cut short
+    let get = |start: usize, end: usize| {
+        bytes.get(start..end).ok_or(Error::Truncated { offset })
+    };
+
+    // Closure to decode the pair of hex digits at `start`, rejecting any other character
+    let hex_byte = |start: usize| {
+        let pair = get(start, start + 2)?;
+        match pair.iter().position(|c| !c.is_ascii_hexdigit()) {
+            Some(i) => Err(Error::BadHexDigit { offset: offset + start + i, found: pair[i] }),
+            None => Ok(hex_to_ascii(pair, 16).0 as u8),
+        }
+    };
+
+    // Closure to verify the checksums of records
+    let verify = |calc: &[u8], len: u8, checksum: u8| {
+        let calc_add = calc.iter().fold(len as u16, |acc, &ele| acc + ele as u16);
+        let calc_mask_comp = (calc_add & 0xFF) as u8 ^ 0xFF;
+        if checksum != calc_mask_comp {
+            return Err(Error::BadChecksum { offset });
+        }
+        Ok(())
+    };
+
+    let record_cat = match bytes.first() {
+        Some(&record_cat) => record_cat,
+        None => return Ok(if eof { Step::End } else { Step::NeedMore }),
+    };
+
+    match record_cat {
+        // Check if record starts with `S` and is thus an S-Record
+        0x53 => {
+            // <ASCII Text>
+            // S                                                    Header
+            // 3                                                    Type
+            // 19                                                   Length
+            // AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA     Data
+            // 28                                                   Checksum
+            // `\n`                                                 Newline
+
+            if !eof && bytes.len() < 4 {
+                return Ok(Step::NeedMore);
+            }
+
+            // Parse out length field from the Record
+            let len = hex_byte(2)? as usize;
+            let end = (len * 2) + 4;
+            if !eof && bytes.len() < end {
+                return Ok(Step::NeedMore);
+            }
+
+            // Parse out type of this record
+            let record_type = get(1, 2)?[0];
+            let t_type = match record_type {
+                b'A' => Some(SRecordType::A),
+                b'0'..=b'9' => SRecordType::from_raw(record_type - b'0'),
+                _ => None,
+            }.ok_or(Error::UnknownRecordType { offset, record_type })?;
+
+            // Extract data fields as ascii instead of hex
+            let mut data = (4..end).step_by(2).map(hex_byte).collect::<Result<Vec<u8>>>()?;
+
+            // Verify checksum for the data
+            let checksum = data.pop().ok_or(Error::Truncated { offset })?;
+            verify(&data, len as u8, checksum)?;
+
+            // Parse out addresses from S-Records
+            let address_size = t_type.address_size();
+            if data.len() < address_size {
+                return Err(Error::Truncated { offset });
+            }
+            let (address_raw, data) = data.split_at(address_size);
+            let address = bytes_to_int_be(address_raw, address_size);
+
+            // Skip the line ending, which may be `\n` or `\r\n`
+            let mut consumed = end;
+            while matches!(bytes.get(consumed), Some(b'\r') | Some(b'\n')) {
+                consumed += 1;
+            }
+
+            // The line ending has to be buffered in full to know where the next record starts
+            if !eof && consumed == bytes.len() {
+                return Ok(Step::NeedMore);
             }
-            // Useless data, just skip past it until next record is found
-            b'F' | b'P' => {
-                // Skip until new-line
-                let endl = find_nl(&bytes[index..]);
-                println!(
-                    "Skipping {} record: `{}`",
-                    record_cat,
-                    String::from_utf8(bytes[index..index + endl].to_vec()).unwrap(),
-                );
-                index += endl + 1;
+
+            Ok(Step::Record(SRecord {
+                header: record_cat,
+                t_type,
+                len,
+                address,
+                data: data.to_vec(),
+                checksum,
+                offset,
+            }, consumed))
+        }
+        // Binary S-Record instead of Ascii S-Record
+        0x30..=0x3F => {
+            // <Hexdump>
+            // 33                                                   Header+Type
+            // 05                                                   Length
+            // AA AA AA AA                                          Data
+            // 28                                                   Checksum
+            if !eof && bytes.len() < 2 {
+                return Ok(Step::NeedMore);
             }
-            // Not a valid record type
-            _ => {
-                println!(
-                    "{:X}: type = {:X}, bytes[index] = 0x{:X}, bytes[index+1] = 0x{:X}",
-                    index,
-                    record_cat,
-                    bytes[index],
-                    bytes[index + 1]
-                );
-                break;
+            let t_type = SRecordType::from_raw(record_cat & 0xF)
+                .ok_or(Error::UnknownRecordType { offset, record_type: record_cat })?;
+            let len = get(1, 2)?[0] as usize;
+            if !eof && bytes.len() < len + 2 {
+                return Ok(Step::NeedMore);
             }
+            let checksum = get(1 + len, 2 + len)?[0];
+            let data = get(2, 1 + len)?;
+            verify(data, len as u8, checksum)?;
+            // Address size in bytes
+            let address_size = t_type.address_size();
+            if data.len() < address_size {
+                return Err(Error::Truncated { offset });
+            }
+            let (address_raw, data) = data.split_at(address_size);
+            let address = bytes_to_int_be(address_raw, address_size);
+
+            // Length + header byte (1) + length byte (1)
+            Ok(Step::Record(SRecord {
+                header: record_cat,
+                t_type,
+                len,
+                address,
+                data: data.to_vec(),
+                checksum,
+                offset,
+            }, len + 2))
+        }
+        // Useless data, just skip past it until next record is found
+        b'F' | b'P' => {
+            // Skip until new-line
+            let endl = match bytes.iter().position(|&c| c == b'\n') {
+                Some(endl) => endl,
+                None if eof => bytes.len(),
+                None => return Ok(Step::NeedMore),
+            };
+            println!(
+                "Skipping {} record: `{}`",
+                record_cat,
+                String::from_utf8_lossy(&bytes[..endl]),
+            );
+            Ok(Step::Skip(std::cmp::min(endl + 1, bytes.len())))
+        }
+        // Not a valid record type, marks the end of the records
+        _ => {
+            println!("{:X}: type = {:X}, bytes[index] = 0x{:X}", offset, record_cat, record_cat);
+            Ok(Step::End)
         }
     }
-    records
 }
 
-/// Return only the binary sections of the srecords
-pub fn print_binary_record(record: &Vec<SRecord>) -> Vec<u8> {
+/// Streaming S-Record parser, yielding records one at a time as they are read. Only the record
+/// being decoded is buffered, so arbitrarily large dumps are parsed with bounded memory. Every
+/// record carries its byte offset within the stream
+pub struct SRecordReader<R: Read> {
+    /// Source of the records
+    reader: R,
+
+    /// Bytes read from the reader, decoding continues at `pos`
+    buf: Vec<u8>,
+
+    /// Position within `buf` of the next byte to decode
+    pos: usize,
+
+    /// Offset within the stream of the next byte to decode
+    offset: usize,
+
+    /// Whether the reader is exhausted
+    eof: bool,
+
+    /// Whether the end of the records or an error was reached
+    done: bool,
+}
+
+impl<R: Read> SRecordReader<R> {
+    /// Create a reader parsing the records read from `reader`
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            pos: 0,
+            offset: 0,
+            eof: false,
+            done: false,
+        }
+    }
+
+    /// Offset within the stream of the next byte to decode
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Drop the decoded bytes and read the next chunk of bytes into the buffer
+    fn fill(&mut self) -> Result<()> {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let len = self.buf.len();
+        self.buf.resize(len + READ_CHUNK, 0);
+        let read = loop {
+            match self.reader.read(&mut self.buf[len..]) {
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        };
+        self.buf.truncate(len + read.as_ref().map_or(0, |&read| read));
+        self.eof = read? == 0;
+        Ok(())
+    }
+
+    /// Mark `len` buffered bytes as decoded
+    fn consume(&mut self, len: usize) {
+        self.pos += len;
+        self.offset += len;
+    }
+}
+
+impl<R: Read> Iterator for SRecordReader<R> {
+    type Item = Result<SRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let step = decode_record(&self.buf[self.pos..], self.offset, self.eof)
+                .and_then(|step| match step {
+                    Step::NeedMore => self.fill().map(|_| Step::NeedMore),
+                    step => Ok(step),
+                });
+            match step {
+                Ok(Step::Record(record, len)) => {
+                    self.consume(len);
+                    return Some(Ok(record));
+                }
+                Ok(Step::Skip(len)) => self.consume(len),
+                Ok(Step::NeedMore) => {}
+                Ok(Step::End) => self.done = true,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Parse out all S-Records from the passed in bytes and return them to user
+pub fn parse_srecords(bytes: &[u8]) -> Result<Vec<SRecord>> {
+    SRecordReader::new(bytes).collect()
+}
+
+/// Return the binary data records that make up the raw flash image
+fn binary_data_records(record: &[SRecord]) -> impl Iterator<Item = &SRecord> {
     record
         .iter()
         .skip_while(|rec| rec.header != 0x30)
         .filter(|rec| matches!(rec.t_type, SRecordType::Three))
-        .map(|rec| rec.data.clone())
-        .collect::<Vec<_>>()
-        .concat()
-        // Removing unused OOB data
-        .chunks(0x840)
-        .map(|chunk| &chunk[..0x800])
-        .collect::<Vec<_>>()
-        .concat()
+}
+
+/// Largest range of addresses a flat image may cover. Records spread further apart are rejected
+/// instead of allocating the whole range
+const MAX_FLAT_IMAGE: usize = 0x10000000;
+
+/// Flat image assembled from data records placed at their addresses
+#[derive(Debug, Default)]
+pub struct FlatImage {
+    /// Address of the first byte of `data`
+    base: usize,
+
+    /// Contents of memory from `base` up to the end of the last record, gaps are filled
+    data: Vec<u8>,
+
+    /// Contiguous ranges `start..end` covered by records, sorted by address
+    spans: Vec<(usize, usize)>,
+
+    /// Ranges `start..end` written by more than one record
+    overlaps: Vec<(usize, usize)>,
+
+    /// Offsets of the records whose address lies below the end of the record preceding them
+    out_of_order: Vec<usize>,
+}
+
+impl FlatImage {
+    /// Address of the first byte of the image
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Contents of the image
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Take the contents of the image
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Contiguous ranges `start..end` covered by records, sorted by address
+    pub fn spans(&self) -> &[(usize, usize)] {
+        &self.spans
+    }
+
+    /// Gaps `start..end` between the spans that were filled
+    pub fn gaps(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.spans.windows(2).map(|pair| (pair[0].1, pair[1].0))
+    }
+
+    /// Ranges `start..end` written by more than one record
+    pub fn overlaps(&self) -> &[(usize, usize)] {
+        &self.overlaps
+    }
+
+    /// Offsets of the records whose address lies below the end of the record preceding them
+    pub fn out_of_order(&self) -> &[usize] {
+        &self.out_of_order
+    }
+}
+
+/// Assembles data records into a flat image according to their addresses
+#[derive(Debug, Clone, Copy)]
+pub struct FlatImageBuilder {
+    /// Byte used to fill gaps between records
+    fill: u8,
+}
+
+impl FlatImageBuilder {
+    /// Create a builder that fills gaps with `fill`
+    pub fn new(fill: u8) -> Self {
+        Self { fill }
+    }
+
+    /// Place the data records among `records` at their addresses. Records are applied in order,
+    /// so when records overlap the later one wins. Fails if the records span more than
+    /// `MAX_FLAT_IMAGE` bytes
+    pub fn build<'a>(&self, records: impl IntoIterator<Item = &'a SRecord>) -> Result<FlatImage> {
+        let records: Vec<&SRecord> = records.into_iter()
+            .filter(|rec| rec.t_type.is_data())
+            .collect();
+        let (base, end) = match (records.iter().map(|rec| rec.address).min(),
+                                 records.iter().map(|rec| rec.address + rec.data.len()).max()) {
+            (Some(base), Some(end)) => (base, end),
+            _ => return Ok(FlatImage::default()),
+        };
+        if end - base > MAX_FLAT_IMAGE {
+            return Err(Error::TooLarge { addr: base, size: end - base, max: MAX_FLAT_IMAGE });
+        }
+
+        let mut image = FlatImage {
+            base,
+            data: vec![self.fill; end - base],
+            ..Default::default()
+        };
+        let mut prev_end = None;
+        for rec in &records {
+            if prev_end.is_some_and(|prev_end| rec.address < prev_end) {
+                image.out_of_order.push(rec.offset);
+            }
+            prev_end = Some(rec.address + rec.data.len());
+
+            let start = rec.address - base;
+            image.data[start..start + rec.data.len()].copy_from_slice(&rec.data);
+        }
+
+        // Merge the sorted ranges into spans, recording every range written more than once
+        let mut ranges: Vec<(usize, usize)> = records.iter()
+            .map(|rec| (rec.address, rec.address + rec.data.len()))
+            .filter(|(start, end)| start != end)
+            .collect();
+        ranges.sort_unstable();
+        for (start, end) in ranges {
+            match image.spans.last_mut() {
+                Some(span) if start <= span.1 => {
+                    if start < span.1 {
+                        image.overlaps.push((start, std::cmp::min(end, span.1)));
+                    }
+                    span.1 = std::cmp::max(span.1, end);
+                }
+                _ => image.spans.push((start, end)),
+            }
+        }
+        Ok(image)
+    }
+}
+
+/// Writes the binary data records into a flat image as they are read, so the image never has to
+/// be held in memory. Gaps are filled with `fill`, records starting below the end of the data
+/// written so far are written by seeking back and the later record wins, like with
+/// `FlatImageBuilder`. Unlike the builder, the image starts at the address of the first binary
+/// data record, as the image cannot be moved once written. Records below it are rejected
+#[derive(Debug)]
+pub struct FlatImageWriter<W: Write + Seek> {
+    /// Destination of the image
+    writer: W,
+
+    /// Byte used to fill gaps between records
+    fill: u8,
+
+    /// Whether the binary header record was seen, the data records following it form the image
+    started: bool,
+
+    /// Address of the first byte of the image, taken from the first data record
+    base: Option<usize>,
+
+    /// End of the data written so far, relative to `base`
+    end: usize,
+
+    /// Position of `writer`, relative to `base`
+    pos: usize,
+
+    /// Gaps `start..end` that were filled when a record was written past the end of the image
+    gaps: Vec<(usize, usize)>,
+
+    /// Contiguous ranges `start..end` written so far relative to `base`, sorted by address
+    spans: Vec<(usize, usize)>,
+
+    /// Ranges `start..end` written by more than one record
+    overlaps: Vec<(usize, usize)>,
+
+    /// Offsets of the records whose address lies below the end of the data written before them
+    out_of_order: Vec<usize>,
+}
+
+impl<W: Write + Seek> FlatImageWriter<W> {
+    /// Create a writer placing the image at the start of `writer`, filling gaps with `fill`
+    pub fn new(writer: W, fill: u8) -> Self {
+        Self {
+            writer,
+            fill,
+            started: false,
+            base: None,
+            end: 0,
+            pos: 0,
+            gaps: Vec::new(),
+            spans: Vec::new(),
+            overlaps: Vec::new(),
+            out_of_order: Vec::new(),
+        }
+    }
+
+    /// Add `start..end`, relative to the base of the image, to the spans written so far and record
+    /// the parts of it that were already written
+    fn add_span(&mut self, base: usize, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+
+        // Spans touching or overlapping `start..end` are merged with it
+        let first = self.spans.partition_point(|&(_, span_end)| span_end < start);
+        let last = self.spans.partition_point(|&(span_start, _)| span_start <= end);
+        let mut merged = (start, end);
+        for &(span_start, span_end) in &self.spans[first..last] {
+            let (overlap_start, overlap_end) = (span_start.max(start), span_end.min(end));
+            if overlap_start < overlap_end {
+                self.overlaps.push((base + overlap_start, base + overlap_end));
+            }
+            merged = (merged.0.min(span_start), merged.1.max(span_end));
+        }
+        self.spans.splice(first..last, [merged]);
+    }
+
+    /// Move the writer to `pos`, relative to the base of the image
+    fn seek(&mut self, pos: usize) -> Result<()> {
+        if self.pos != pos {
+            self.writer.seek(SeekFrom::Start(pos as u64))?;
+            self.pos = pos;
+        }
+        Ok(())
+    }
+
+    /// Write a record to the image. Records other than the binary data records, see
+    /// `raw_binary_record`, are ignored. Fails for records below the first binary data record
+    pub fn write(&mut self, rec: &SRecord) -> Result<()> {
+        self.started |= rec.header == 0x30;
+        if !self.started || !matches!(rec.t_type, SRecordType::Three) {
+            return Ok(());
+        }
+
+        let base = *self.base.get_or_insert(rec.address);
+        let start = rec.address.checked_sub(base)
+            .ok_or(Error::InvalidConfig("binary record lies below the first binary record"))?;
+        let end = start + rec.data.len();
+        if end > MAX_FLAT_IMAGE {
+            return Err(Error::TooLarge { addr: base, size: end, max: MAX_FLAT_IMAGE });
+        }
+
+        if start < self.end {
+            self.out_of_order.push(rec.offset);
+        } else if start > self.end {
+            self.gaps.push((base + self.end, rec.address));
+            self.seek(self.end)?;
+            let fill = vec![self.fill; std::cmp::min(start - self.end, READ_CHUNK)];
+            while self.pos < start {
+                let len = std::cmp::min(start - self.pos, fill.len());
+                self.writer.write_all(&fill[..len])?;
+                self.pos += len;
+            }
+        }
+        self.seek(start)?;
+        self.writer.write_all(&rec.data)?;
+        self.pos = end;
+        self.end = std::cmp::max(self.end, end);
+        self.add_span(base, start, end);
+        Ok(())
+    }
+
+    /// Gaps `start..end` that were filled, a later record may have written to them after all
+    pub fn gaps(&self) -> &[(usize, usize)] {
+        &self.gaps
+    }
+
+    /// Ranges `start..end` written by more than one record
+    pub fn overlaps(&self) -> &[(usize, usize)] {
+        &self.overlaps
+    }
+
+    /// Offsets of the records whose address lies below the end of the data written before them
+    pub fn out_of_order(&self) -> &[usize] {
+        &self.out_of_order
+    }
+
+    /// Flush the image and return the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Return the binary sections of the srecords including the OOB data of every page. Records are
+/// placed at their addresses, gaps are filled with erased flash (0xFF)
+pub fn raw_binary_record(record: &[SRecord]) -> Result<FlatImage> {
+    FlatImageBuilder::new(0xFF).build(binary_data_records(record))
+}
+
+/// Return only the binary sections of the srecords
+pub fn print_binary_record(record: &[SRecord]) -> Result<Vec<u8>> {
+    Ok(strip_oob(raw_binary_record(record)?.data()))
+}
+
+/// Replace the raw flash image carried by the binary data records in `bytes` with `raw`, which
+/// starts at the lowest record address like the image built by `raw_binary_record`. Every data
+/// record keeps its address and size and takes the bytes of `raw` at that address, everything else
+/// including the records interleaved with the data records is kept as-is. Bytes beyond the original
+/// end continue contiguously in records the size of the last one, following the last data record.
+/// Bytes within gaps between the original records are not written
+pub fn repack_binary_record(bytes: &[u8], record: &[SRecord], raw: &[u8]) -> Result<Vec<u8>> {
+    let originals: Vec<&SRecord> = binary_data_records(record).collect();
+    let first = originals.first().ok_or(Error::NotFound("binary data records"))?;
+    let last = originals.last().ok_or(Error::NotFound("binary data records"))?;
+    let base = originals.iter().map(|rec| rec.address).min().unwrap_or(first.address);
+    let end = originals.iter().map(|rec| rec.address + rec.data.len()).max()
+        .unwrap_or(first.address);
+
+    let mut result = vec![];
+    let mut copied = 0;
+    for rec in &originals {
+        // Keep everything since the previous data record, such as type-A and count records
+        result.extend(&bytes[copied..rec.offset]);
+        copied = rec.offset + rec.len + 2;
+
+        let start = rec.address - base;
+        if start >= raw.len() && !rec.data.is_empty() {
+            // The new image ends before this record
+            continue;
+        }
+        let chunk = &raw[start.min(raw.len())..(start + rec.data.len()).min(raw.len())];
+        result.extend(SRecord::new(SRecordType::Three, rec.address, chunk)?.to_binary());
+    }
+
+    let mut address = end;
+    let len = std::cmp::max(last.data.len(), 1);
+    for chunk in raw.get(end - base..).unwrap_or_default().chunks(len) {
+        result.extend(SRecord::new(SRecordType::Three, address, chunk)?.to_binary());
+        address += chunk.len();
+    }
+    result.extend(&bytes[copied..]);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writer_round_trip() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 31 + 7) as u8).collect();
+        for address_size in [2, 3, 4] {
+            let writer = SRecordWriter::new(32, address_size).unwrap();
+            let records = writer.records(b"header", 0x1234, &data, 0x1240).unwrap();
+            for bytes in [write_ascii(&records), write_binary(&records)] {
+                let parsed = parse_srecords(&bytes).unwrap();
+                assert_eq!(parsed.len(), records.len());
+                for (parsed, record) in parsed.iter().zip(&records) {
+                    assert_eq!(parsed.t_type(), record.t_type());
+                    assert_eq!(parsed.address(), record.address());
+                    assert_eq!(parsed.data(), record.data());
+                    assert_eq!(parsed.checksum(), record.checksum());
+                }
+                let flat: Vec<u8> = parsed.iter()
+                    .filter(|record| record.t_type().is_data())
+                    .flat_map(|record| record.data().to_vec())
+                    .collect();
+                assert_eq!(flat, data);
+            }
+        }
+    }
+
+    #[test]
+    fn reject_oversized_records() {
+        assert!(SRecord::new(SRecordType::Three, 0, &[0; 250]).is_ok());
+        assert!(SRecord::new(SRecordType::Three, 0, &[0; 251]).is_err());
+        assert!(SRecord::new(SRecordType::One, 0, &[0; 252]).is_ok());
+        assert!(SRecord::new(SRecordType::Three, 0, &[0; 300]).is_err());
+        assert!(SRecordWriter::new(251, 4).is_err());
+    }
+
+    /// Binary records as found in a job: header, data records with a gap, a type-A and a count
+    /// record between them and a termination
+    fn binary_stream() -> Vec<u8> {
+        let data: Vec<u8> = (0..0x80u32).map(|i| (i * 13 + 5) as u8).collect();
+        let records = [
+            SRecord::new(SRecordType::Zero, 0, b"hdr").unwrap(),
+            SRecord::new(SRecordType::Three, 0x1000, &data[..0x20]).unwrap(),
+            SRecord::new(SRecordType::Three, 0x1020, &data[0x20..0x40]).unwrap(),
+            SRecord::new(SRecordType::A, 0, b"HPVER1.0").unwrap(),
+            SRecord::new(SRecordType::Three, 0x1050, &data[0x40..0x60]).unwrap(),
+            SRecord::new(SRecordType::Five, 3, &[]).unwrap(),
+            SRecord::new(SRecordType::Three, 0x1070, &data[0x60..0x70]).unwrap(),
+            SRecord::new(SRecordType::Seven, 0x1000, &[]).unwrap(),
+        ];
+        write_binary(&records)
+    }
+
+    #[test]
+    fn repack_unmodified_is_identity() {
+        let bytes = binary_stream();
+        let records = parse_srecords(&bytes).unwrap();
+        let image = raw_binary_record(&records).unwrap();
+        assert_eq!(image.base(), 0x1000);
+        assert_eq!(image.gaps().collect::<Vec<_>>(), [(0x1040, 0x1050)]);
+        assert_eq!(repack_binary_record(&bytes, &records, image.data()).unwrap(), bytes);
+    }
+
+    #[test]
+    fn repack_grown_image_appends_records() {
+        let bytes = binary_stream();
+        let records = parse_srecords(&bytes).unwrap();
+        let mut raw = raw_binary_record(&records).unwrap().into_data();
+        raw.extend([0xAB; 0x18]);
+
+        let repacked = repack_binary_record(&bytes, &records, &raw).unwrap();
+        let parsed = parse_srecords(&repacked).unwrap();
+        let image = raw_binary_record(&parsed).unwrap();
+        assert_eq!(image.data(), raw);
+        assert_eq!(image.spans(), [(0x1000, 0x1040), (0x1050, 0x1098)]);
+
+        // The records between the data records stay in place
+        let types: Vec<_> = parsed.iter().map(|record| record.t_type()).collect();
+        assert_eq!(types[..8], [SRecordType::Zero, SRecordType::Three, SRecordType::Three,
+                                SRecordType::A, SRecordType::Three, SRecordType::Five,
+                                SRecordType::Three, SRecordType::Three]);
+        assert_eq!(types.last(), Some(&SRecordType::Seven));
+    }
+
+    #[test]
+    fn stream_matches_flat_image() {
+        let mut bytes = binary_stream();
+        // An out of order record overwriting earlier data
+        bytes.extend(SRecord::new(SRecordType::Three, 0x1010, &[0x55; 8]).unwrap().to_binary());
+        let records = parse_srecords(&bytes).unwrap();
+
+        let mut image = FlatImageWriter::new(std::io::Cursor::new(Vec::new()), 0xFF);
+        for record in SRecordReader::new(&bytes[..]) {
+            image.write(&record.unwrap()).unwrap();
+        }
+        let flat = raw_binary_record(&records).unwrap();
+        assert_eq!(image.gaps(), [(0x1040, 0x1050)]);
+        assert_eq!(image.overlaps(), [(0x1010, 0x1018)]);
+        assert_eq!(image.overlaps(), flat.overlaps());
+        assert_eq!(image.out_of_order(), [records.last().unwrap().offset()]);
+        let streamed = image.finish().unwrap().into_inner();
+        assert_eq!(streamed, flat.data());
+    }
+
+    #[test]
+    fn stream_overlaps_and_restrictions() {
+        let mut records = vec![SRecord::new(SRecordType::Zero, 0, b"hdr").unwrap()];
+        for (address, len) in [(0x100, 0x10), (0x120, 0x10), (0x108, 0x20)] {
+            records.push(SRecord::new(SRecordType::Three, address, &vec![0; len]).unwrap());
+        }
+        let bytes = write_binary(&records);
+        let mut image = FlatImageWriter::new(std::io::Cursor::new(Vec::new()), 0xFF);
+        for record in SRecordReader::new(&bytes[..]) {
+            image.write(&record.unwrap()).unwrap();
+        }
+
+        // The record spanning the gap overlaps both records around it
+        assert_eq!(image.gaps(), [(0x110, 0x120)]);
+        assert_eq!(image.overlaps(), [(0x108, 0x110), (0x120, 0x128)]);
+
+        // The builder places records below the first one, the writer cannot move the image
+        let bytes = write_binary(&[SRecord::new(SRecordType::Three, 0xF0, &[0; 0x10]).unwrap()]);
+        let below = SRecordReader::new(&bytes[..]).next().unwrap().unwrap();
+        assert!(matches!(image.write(&below), Err(Error::InvalidConfig(_))));
+        assert_eq!(FlatImageBuilder::new(0xFF).build([&below]).unwrap().base(), 0xF0);
+    }
+
+    #[test]
+    fn reject_sparse_flat_image() {
+        let records = [SRecord::new(SRecordType::Three, 0x0, &[0; 0x20]).unwrap(),
+                       SRecord::new(SRecordType::Three, 0xFFFFFF00, &[0; 0x20]).unwrap()];
+        assert!(matches!(FlatImageBuilder::new(0xFF).build(&records),
+                         Err(Error::TooLarge { addr: 0, .. })));
+    }
+
+    #[test]
+    fn parse_lowercase_hex() {
+        let upper = parse_srecords(b"S1051234ABCD3C\n").unwrap();
+        let lower = parse_srecords(b"S1051234abcd3c\n").unwrap();
+        assert_eq!(lower.len(), 1);
+        assert_eq!(lower[0].address(), 0x1234);
+        assert_eq!(lower[0].data(), &[0xAB, 0xCD]);
+        assert_eq!(lower[0].data(), upper[0].data());
+    }
+
+    #[test]
+    fn reject_non_hex_digits() {
+        assert!(matches!(parse_srecords(b"S1051234ABCG3C\n"),
+                         Err(Error::BadHexDigit { offset: 11, found: b'G' })));
+        assert!(matches!(parse_srecords(b"S10x1234ABCD3C\n"),
+                         Err(Error::BadHexDigit { offset: 3, found: b'x' })));
+    }
+
+    #[test]
+    fn decode_type_a_payloads() {
+        let text = |text: &str| text.to_string();
+        assert_eq!(TypeAPayload::decode(b"HPVER1.0\0\0"), TypeAPayload::Version(text("HPVER1.0")));
+        assert_eq!(TypeAPayload::decode(b"model = OJP6835"),
+                   TypeAPayload::Field { key: text("model"), value: text("OJP6835") });
+        assert_eq!(TypeAPayload::decode(b"date: 2014"),
+                   TypeAPayload::Field { key: text("date"), value: text("2014") });
+        assert_eq!(TypeAPayload::decode(b"firmware"), TypeAPayload::Text(text("firmware")));
+        assert_eq!(TypeAPayload::decode(&[0x26, 0x71, 0, 0, 0, 0, 0x38, 0]),
+                   TypeAPayload::Region { start: 0x26710000, len: 0x3800 });
+        for len in [20, 32, 48, 64, 128, 256, 384, 512] {
+            assert_eq!(TypeAPayload::decode(&vec![0x80; len]),
+                       TypeAPayload::Signature(vec![0x80; len]));
+        }
+
+        // Lengths that fit no category, empty and all NUL payloads stay binary
+        for data in [&[0x80; 21][..], &[0x80; 7], &[], &[0; 4]] {
+            assert_eq!(TypeAPayload::decode(data), TypeAPayload::Binary(data.to_vec()));
+        }
+    }
 }